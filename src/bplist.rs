@@ -0,0 +1,299 @@
+//! Decoder of Apple binary property lists (`bplist00`) into JSON, for the plists stored in BLOB columns
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+
+use crate::timestamp::{Timestamp, TimestampFormat};
+
+const MAGIC: &[u8; 8] = b"bplist00";
+const TRAILER_SIZE: usize = 32;
+/// Nested containers deeper than this are not decoded
+const MAX_DEPTH: usize = 256;
+/// Objects written, counting every reference: containers referenced many times can expand exponentially
+const MAX_OBJECTS: usize = 1 << 20;
+/// Size of the JSON written
+const MAX_JSON_SIZE: usize = 64 << 20;
+
+/// Converts a binary plist into JSON.
+/// Dates become RFC 3339 strings, data becomes base64 strings and UIDs become `{"CF$UID": n}` objects.
+/// Plists with reference cycles, or that expand beyond `MAX_OBJECTS` objects or `MAX_JSON_SIZE` bytes, are rejected.
+pub fn to_json(data: &[u8]) -> ForensicResult<String> {
+    let plist = BinaryPlist::parse(data).ok_or_else(|| ForensicError::Other("Not a valid binary plist".into()))?;
+    let mut json = String::with_capacity(data.len() * 2);
+    plist
+        .write_object(plist.top_object, &mut Progress::default(), &mut json)
+        .ok_or_else(|| ForensicError::Other("Not a valid binary plist".into()))?;
+    Ok(json)
+}
+
+/// Containers being written and the objects written so far
+#[derive(Default)]
+struct Progress {
+    open: Vec<u64>,
+    written: usize,
+}
+
+struct BinaryPlist<'a> {
+    data: &'a [u8],
+    offset_size: usize,
+    ref_size: usize,
+    object_count: u64,
+    top_object: u64,
+    offset_table: usize,
+}
+
+impl<'a> BinaryPlist<'a> {
+    fn parse(data: &'a [u8]) -> Option<BinaryPlist<'a>> {
+        if data.len() < MAGIC.len() + TRAILER_SIZE || &data[0..8] != MAGIC {
+            return None;
+        }
+        let trailer = &data[data.len() - TRAILER_SIZE..];
+        let plist = BinaryPlist {
+            data,
+            offset_size: trailer[6] as usize,
+            ref_size: trailer[7] as usize,
+            object_count: be_uint(&trailer[8..16])?,
+            top_object: be_uint(&trailer[16..24])?,
+            offset_table: be_uint(&trailer[24..32])? as usize,
+        };
+        if !(1..=8).contains(&plist.offset_size) || !(1..=8).contains(&plist.ref_size) || plist.top_object >= plist.object_count {
+            return None;
+        }
+        Some(plist)
+    }
+
+    fn object_offset(&self, object: u64) -> Option<usize> {
+        if object >= self.object_count {
+            return None;
+        }
+        let start = self.offset_table.checked_add((object as usize).checked_mul(self.offset_size)?)?;
+        Some(be_uint(self.data.get(start..start + self.offset_size)?)? as usize)
+    }
+
+    /// Length of a container or string: the low nibble, or an integer object after the marker when it is 0xF
+    fn length(&self, marker: u8, offset: usize) -> Option<(usize, usize)> {
+        let nibble = (marker & 0x0f) as usize;
+        if nibble != 0x0f {
+            return Some((nibble, offset + 1));
+        }
+        let int_marker = *self.data.get(offset + 1)?;
+        if int_marker & 0xf0 != 0x10 {
+            return None;
+        }
+        let size = 1usize << (int_marker & 0x0f);
+        let value = be_uint(self.data.get(offset + 2..offset + 2 + size)?)?;
+        Some((value as usize, offset + 2 + size))
+    }
+
+    fn reference(&self, offset: usize, index: usize) -> Option<u64> {
+        let start = offset.checked_add(index.checked_mul(self.ref_size)?)?;
+        be_uint(self.data.get(start..start + self.ref_size)?)
+    }
+
+    fn write_object(&self, object: u64, progress: &mut Progress, json: &mut String) -> Option<()> {
+        progress.written += 1;
+        // A container inside itself is a cycle
+        if progress.open.len() > MAX_DEPTH || progress.written > MAX_OBJECTS || json.len() > MAX_JSON_SIZE || progress.open.contains(&object) {
+            return None;
+        }
+        progress.open.push(object);
+        let offset = self.object_offset(object)?;
+        let marker = *self.data.get(offset)?;
+        match marker >> 4 {
+            0x0 => json.push_str(match marker {
+                0x08 => "false",
+                0x09 => "true",
+                _ => "null",
+            }),
+            0x1 => {
+                let size = 1usize << (marker & 0x0f);
+                let bytes = self.data.get(offset + 1..offset + 1 + size)?;
+                // 16-byte integers keep their value in the low 8 bytes
+                let bytes = if size > 8 { &bytes[size - 8..] } else { bytes };
+                let value = be_uint(bytes)?;
+                if size >= 8 {
+                    json.push_str(&(value as i64).to_string());
+                } else {
+                    json.push_str(&value.to_string());
+                }
+            }
+            0x2 => {
+                let value = match marker & 0x0f {
+                    2 => f32::from_be_bytes(self.data.get(offset + 1..offset + 5)?.try_into().ok()?) as f64,
+                    3 => f64::from_be_bytes(self.data.get(offset + 1..offset + 9)?.try_into().ok()?),
+                    _ => return None,
+                };
+                write_float(value, json);
+            }
+            0x3 => {
+                let seconds = f64::from_be_bytes(self.data.get(offset + 1..offset + 9)?.try_into().ok()?);
+                match Timestamp::from_value(&forensic_rs::traits::sql::ColumnValue::Float(seconds), TimestampFormat::MacAbsolute) {
+                    Ok(v) => write_string(&v.to_string(), json),
+                    Err(_) => json.push_str("null"),
+                }
+            }
+            0x4 => {
+                let (len, start) = self.length(marker, offset)?;
+                write_string(&crate::functions::base64(self.data.get(start..start.checked_add(len)?)?), json);
+            }
+            0x5 => {
+                let (len, start) = self.length(marker, offset)?;
+                let bytes = self.data.get(start..start.checked_add(len)?)?;
+                write_string(&String::from_utf8_lossy(bytes), json);
+            }
+            0x6 => {
+                let (len, start) = self.length(marker, offset)?;
+                let bytes = self.data.get(start..start.checked_add(len.checked_mul(2)?)?)?;
+                let units: Vec<u16> = bytes.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect();
+                write_string(&String::from_utf16_lossy(&units), json);
+            }
+            0x8 => {
+                let size = (marker & 0x0f) as usize + 1;
+                let value = be_uint(self.data.get(offset + 1..offset + 1 + size)?)?;
+                json.push_str(&format!("{{\"CF$UID\":{}}}", value));
+            }
+            // Arrays and sets
+            0xA | 0xC => {
+                let (len, start) = self.length(marker, offset)?;
+                json.push('[');
+                for i in 0..len {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    self.write_object(self.reference(start, i)?, progress, json)?;
+                }
+                json.push(']');
+            }
+            0xD => {
+                let (len, start) = self.length(marker, offset)?;
+                json.push('{');
+                for i in 0..len {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    // Keys are written as strings whatever their type
+                    let mut key = String::new();
+                    self.write_object(self.reference(start, i)?, progress, &mut key)?;
+                    if key.starts_with('"') {
+                        json.push_str(&key);
+                    } else {
+                        write_string(&key, json);
+                    }
+                    json.push(':');
+                    self.write_object(self.reference(start, len + i)?, progress, json)?;
+                }
+                json.push('}');
+            }
+            _ => return None,
+        }
+        progress.open.pop();
+        Some(())
+    }
+}
+
+fn be_uint(data: &[u8]) -> Option<u64> {
+    if data.len() > 8 {
+        return None;
+    }
+    Some(data.iter().fold(0u64, |acc, v| (acc << 8) | *v as u64))
+}
+
+fn write_float(value: f64, json: &mut String) {
+    if value.is_finite() {
+        json.push_str(&format!("{:?}", value));
+    } else {
+        json.push_str("null");
+    }
+}
+
+fn write_string(value: &str, json: &mut String) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test_bplist {
+    use super::*;
+
+    /// {"name": "Alice", "age": 42, "tags": [true, 1.5]}
+    fn sample() -> Vec<u8> {
+        let mut data = b"bplist00".to_vec();
+        let mut offsets = Vec::new();
+        // 0: dict with 3 entries, keys 1 2 3, values 4 5 6
+        offsets.push(data.len());
+        data.extend_from_slice(&[0xD3, 1, 2, 3, 4, 5, 6]);
+        for key in ["name", "age", "tags"] {
+            offsets.push(data.len());
+            data.push(0x50 | key.len() as u8);
+            data.extend_from_slice(key.as_bytes());
+        }
+        offsets.push(data.len());
+        data.push(0x55);
+        data.extend_from_slice(b"Alice");
+        offsets.push(data.len());
+        data.extend_from_slice(&[0x10, 42]);
+        // 6: array with objects 7 and 8
+        offsets.push(data.len());
+        data.extend_from_slice(&[0xA2, 7, 8]);
+        offsets.push(data.len());
+        data.push(0x09);
+        offsets.push(data.len());
+        data.push(0x23);
+        data.extend_from_slice(&1.5f64.to_be_bytes());
+        let offset_table = data.len();
+        data.extend(offsets.iter().map(|v| *v as u8));
+        let mut trailer = vec![0u8; 6];
+        trailer.extend_from_slice(&[1, 1]);
+        trailer.extend_from_slice(&(offsets.len() as u64).to_be_bytes());
+        trailer.extend_from_slice(&0u64.to_be_bytes());
+        trailer.extend_from_slice(&(offset_table as u64).to_be_bytes());
+        data.extend_from_slice(&trailer);
+        data
+    }
+
+    /// Plist of arrays of two references, the last array referencing `last`
+    fn arrays(count: u8, last: u8) -> Vec<u8> {
+        let mut data = b"bplist00".to_vec();
+        let mut offsets = Vec::new();
+        for i in 0..count {
+            let next = if i + 1 == count { last } else { i + 1 };
+            offsets.push(data.len());
+            data.extend_from_slice(&[0xA2, next, next]);
+        }
+        offsets.push(data.len());
+        data.push(0x09);
+        let offset_table = data.len();
+        data.extend(offsets.iter().map(|v| *v as u8));
+        let mut trailer = vec![0u8; 6];
+        trailer.extend_from_slice(&[1, 1]);
+        trailer.extend_from_slice(&(offsets.len() as u64).to_be_bytes());
+        trailer.extend_from_slice(&0u64.to_be_bytes());
+        trailer.extend_from_slice(&(offset_table as u64).to_be_bytes());
+        data.extend_from_slice(&trailer);
+        data
+    }
+
+    #[test]
+    fn should_convert_bplist_to_json() {
+        assert_eq!(r#"{"name":"Alice","age":42,"tags":[true,1.5]}"#, to_json(&sample()).unwrap());
+        assert!(to_json(b"not a plist").is_err());
+    }
+
+    #[test]
+    fn should_reject_cycles_and_expansions() {
+        assert_eq!("[[true,true],[true,true]]", to_json(&arrays(2, 2)).unwrap());
+        // Object 0 is [0, 0]
+        assert!(to_json(&arrays(1, 0)).is_err());
+        // 2^40 references to `true` without any cycle
+        assert!(to_json(&arrays(40, 40)).is_err());
+    }
+}
@@ -0,0 +1,393 @@
+//! Recovery of deleted records from the free space of a database file: freelist pages, freeblocks and unallocated space inside b-tree pages.
+use std::{
+    collections::BTreeSet,
+    io::{Read, Seek},
+};
+
+use forensic_rs::{
+    prelude::{ForensicError, ForensicResult},
+    traits::sql::{ColumnType, ColumnValue, SqlStatement},
+};
+
+use crate::{
+    matching::recover_partial_record,
+    page::{be_u16, be_u32, local_payload_size, BtreeHeader, DbFile, LEAF_TABLE},
+    record::{decode_record, read_varint, Record},
+    schema::SchemaTable,
+};
+
+/// Where a carved record was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarvedSource {
+    /// Page in the freelist
+    Freelist,
+    /// Freeblock inside a b-tree page
+    Freeblock,
+    /// Space between the cell pointer array and the cell content area
+    Unallocated,
+    /// Page image in a WAL frame
+    Wal,
+    /// Page image saved in a rollback journal
+    Journal,
+}
+
+impl CarvedSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CarvedSource::Freelist => "freelist",
+            CarvedSource::Freeblock => "freeblock",
+            CarvedSource::Unallocated => "unallocated",
+            CarvedSource::Wal => "wal",
+            CarvedSource::Journal => "journal",
+        }
+    }
+}
+
+/// A record recovered from free space
+#[derive(Debug, Clone)]
+pub struct CarvedRecord {
+    /// Page where the record was found
+    pub page: u32,
+    /// Offset of the record in the database file
+    pub offset: u64,
+    pub source: CarvedSource,
+    /// Between 0 and 1
+    pub confidence: f32,
+    /// Available when the cell header survived
+    pub rowid: Option<i64>,
+    pub serial_types: Vec<u64>,
+    pub values: Vec<ColumnValue>,
+    /// The payload continued in overflow pages or the free space ended before the record did
+    pub truncated: bool,
+    /// The start of the header was overwritten and the first serial types were guessed from the schema
+    pub partial_header: bool,
+}
+
+const CELL_CONFIDENCE: f32 = 0.9;
+const RECORD_CONFIDENCE: f32 = 0.6;
+const PARTIAL_HEADER_CONFIDENCE: f32 = 0.5;
+
+/// Carves deleted records from a database file
+pub fn carve<R: Read + Seek + ?Sized>(reader: &mut R) -> ForensicResult<Vec<CarvedRecord>> {
+    carve_with_schema(reader, &[])
+}
+
+/// Carves deleted records from a database file. The tables are used to rebuild records whose header was partly overwritten in freeblocks.
+pub fn carve_with_schema<R: Read + Seek + ?Sized>(reader: &mut R, tables: &[SchemaTable]) -> ForensicResult<Vec<CarvedRecord>> {
+    let mut db = DbFile::open(reader)?;
+    let (trunks, leaves) = freelist_pages(&mut db)?;
+    let mut carved = Vec::new();
+    for number in 1..=db.page_count {
+        let page = db.read_page(number)?;
+        let base = db.page_offset(number);
+        let mut carver = PageCarver {
+            page: &page,
+            number,
+            base,
+            usable_size: db.usable_size,
+            tables,
+            carved: &mut carved,
+        };
+        if trunks.contains(&number) {
+            let count = (be_u32(&page[4..8]) as usize).min(db.usable_size / 4 - 2);
+            carver.scan(8 + 4 * count, db.usable_size, CarvedSource::Freelist);
+        } else if leaves.contains(&number) {
+            // A freed page keeps its old content, cells included
+            match BtreeHeader::parse(&page, 0) {
+                Some(header) if header.page_type == LEAF_TABLE => carver.carve_leaf(&header, CarvedSource::Freelist, true),
+                _ => carver.scan(0, db.usable_size, CarvedSource::Freelist),
+            }
+        } else {
+            let offset = if number == 1 { crate::page::DB_HEADER_SIZE } else { 0 };
+            if let Some(header) = BtreeHeader::parse(&page, offset) {
+                if header.page_type == LEAF_TABLE {
+                    carver.carve_leaf(&header, CarvedSource::Unallocated, false);
+                }
+            }
+        }
+    }
+    Ok(carved)
+}
+
+/// Carves a page image stored outside the database file, like a WAL frame or a journal record.
+/// Every cell of the image is recovered, not only the free space: the image may hold versions of rows that no longer exist.
+/// `offset` is the position of the image in its file.
+pub fn carve_page_image(image: &[u8], page: u32, offset: u64, usable_size: usize, source: CarvedSource, tables: &[SchemaTable]) -> Vec<CarvedRecord> {
+    let mut carved = Vec::new();
+    let header_offset = if page == 1 { crate::page::DB_HEADER_SIZE } else { 0 };
+    if let Some(header) = BtreeHeader::parse(image, header_offset) {
+        if header.page_type == LEAF_TABLE {
+            let mut carver = PageCarver {
+                page: image,
+                number: page,
+                base: offset,
+                usable_size: usable_size.min(image.len()),
+                tables,
+                carved: &mut carved,
+            };
+            carver.carve_leaf(&header, source, true);
+        }
+    }
+    carved
+}
+
+/// Trunk and leaf pages of the freelist
+pub(crate) fn freelist_pages<R: Read + Seek + ?Sized>(db: &mut DbFile<R>) -> ForensicResult<(BTreeSet<u32>, BTreeSet<u32>)> {
+    let mut trunks = BTreeSet::new();
+    let mut leaves = BTreeSet::new();
+    let mut trunk = db.freelist_trunk;
+    // The visited set protects against loops in corrupted chains
+    while trunk != 0 && trunk <= db.page_count && trunks.insert(trunk) {
+        let page = db.read_page(trunk)?;
+        let count = (be_u32(&page[4..8]) as usize).min(db.usable_size / 4 - 2);
+        for i in 0..count {
+            let leaf = be_u32(&page[8 + 4 * i..12 + 4 * i]);
+            if leaf != 0 && leaf <= db.page_count {
+                leaves.insert(leaf);
+            }
+        }
+        trunk = be_u32(&page[0..4]);
+    }
+    Ok((trunks, leaves))
+}
+
+struct PageCarver<'a> {
+    page: &'a [u8],
+    number: u32,
+    base: u64,
+    usable_size: usize,
+    tables: &'a [SchemaTable],
+    carved: &'a mut Vec<CarvedRecord>,
+}
+
+impl<'a> PageCarver<'a> {
+    /// Carves the free space of a table leaf page. With `with_cells` the cells in the pointer array are also recovered: the whole page is free.
+    /// `source` tells where the page is: only the freeblocks of live pages, found as `Unallocated`, are reported as `Freeblock`.
+    fn carve_leaf(&mut self, header: &BtreeHeader, source: CarvedSource, with_cells: bool) {
+        if with_cells {
+            for pointer in header.cell_pointers(self.page) {
+                if pointer < self.usable_size {
+                    self.carve_cell(pointer, self.usable_size, source);
+                }
+            }
+        }
+        let mut visited = BTreeSet::new();
+        let mut freeblock = header.first_freeblock as usize;
+        while freeblock != 0 && freeblock + 4 <= self.usable_size && visited.insert(freeblock) {
+            let next = be_u16(&self.page[freeblock..freeblock + 2]) as usize;
+            let size = be_u16(&self.page[freeblock + 2..freeblock + 4]) as usize;
+            if size < 4 || freeblock + size > self.usable_size {
+                break;
+            }
+            // The first 4 bytes of the old cell were overwritten by the freeblock header
+            let found = self.carved.len();
+            // Freeblocks of freed pages and page images keep the source of the page
+            let freeblock_source = match source {
+                CarvedSource::Unallocated => CarvedSource::Freeblock,
+                _ => source,
+            };
+            self.scan(freeblock + 4, freeblock + size, freeblock_source);
+            if self.carved.len() == found {
+                self.carve_partial_record(freeblock + 4, freeblock + size, freeblock_source);
+            }
+            freeblock = next;
+        }
+        let unallocated_end = header.cell_content_start.min(self.usable_size);
+        self.scan(header.cell_pointers_end(), unallocated_end, source);
+    }
+
+    /// Looks for records at every offset of a region
+    fn scan(&mut self, start: usize, end: usize, source: CarvedSource) {
+        let mut offset = start;
+        while offset < end {
+            let found = match self.carve_cell(offset, end, source) {
+                Some(v) => Some(v),
+                None => self.carve_record(offset, end, source),
+            };
+            offset = match found {
+                Some(record_end) => record_end.max(offset + 1),
+                None => offset + 1,
+            };
+        }
+    }
+
+    /// Tries a table leaf cell: payload size, rowid and record. Returns the end of the cell.
+    fn carve_cell(&mut self, offset: usize, end: usize, source: CarvedSource) -> Option<usize> {
+        let page = self.page;
+        let data = page.get(offset..end)?;
+        let (payload, used_payload) = read_varint(data)?;
+        let (rowid, used_rowid) = read_varint(&data[used_payload..])?;
+        let payload = payload as usize;
+        if payload == 0 || payload > 1 << 30 {
+            return None;
+        }
+        let start = used_payload + used_rowid;
+        let local = local_payload_size(LEAF_TABLE, payload, self.usable_size);
+        let available = (data.len() - start).min(local);
+        let record = decode_record(&data[start..start + available]).ok()?;
+        // Without overflow the record must fill the payload exactly
+        if local == payload && record.size != payload {
+            return None;
+        }
+        if !plausible(&record, 1) {
+            return None;
+        }
+        let truncated = record.truncated || local < payload;
+        self.push(offset, source, CELL_CONFIDENCE, Some(rowid as i64), record, truncated);
+        Some(offset + start + available)
+    }
+
+    /// Tries a record without cell header. Returns the end of the record.
+    fn carve_record(&mut self, offset: usize, end: usize, source: CarvedSource) -> Option<usize> {
+        let page = self.page;
+        let data = page.get(offset..end)?;
+        let record = decode_record(data).ok()?;
+        // Single column headers are too easy to find in random data
+        if record.truncated || !plausible(&record, 2) {
+            return None;
+        }
+        let size = record.size;
+        self.push(offset, source, RECORD_CONFIDENCE, None, record, false);
+        Some(offset + size)
+    }
+
+    /// Rebuilds the record of a freeblock with the table that fits best
+    fn carve_partial_record(&mut self, start: usize, end: usize, source: CarvedSource) {
+        let data = match self.page.get(start..end) {
+            Some(v) => v,
+            None => return,
+        };
+        let best = self
+            .tables
+            .iter()
+            .filter_map(|table| recover_partial_record(data, table))
+            .max_by(|a, b| a.score.partial_cmp(&b.score).unwrap_or(std::cmp::Ordering::Equal));
+        if let Some(record) = best {
+            self.carved.push(CarvedRecord {
+                page: self.number,
+                offset: self.base + start as u64,
+                source,
+                confidence: PARTIAL_HEADER_CONFIDENCE * record.score,
+                rowid: None,
+                serial_types: record.serial_types,
+                values: record.values,
+                truncated: false,
+                partial_header: true,
+            });
+        }
+    }
+
+    fn push(&mut self, offset: usize, source: CarvedSource, base: f32, rowid: Option<i64>, record: Record, truncated: bool) {
+        let confidence = confidence(base, &record, truncated);
+        self.carved.push(CarvedRecord {
+            page: self.number,
+            offset: self.base + offset as u64,
+            source,
+            confidence,
+            rowid,
+            serial_types: record.serial_types,
+            values: record.values,
+            truncated,
+            partial_header: false,
+        });
+    }
+}
+
+/// Rejects headers that say nothing: too few columns or only constants
+fn plausible(record: &Record, min_columns: usize) -> bool {
+    record.serial_types.len() >= min_columns
+        && record.serial_types.len() <= 2000
+        && record.serial_types.iter().any(|v| !matches!(v, 0 | 8 | 9))
+}
+
+fn confidence(base: f32, record: &Record, truncated: bool) -> f32 {
+    let mut confidence = base;
+    if truncated {
+        confidence -= 0.2;
+    }
+    // Lossy decoding of garbage leaves replacement characters
+    let broken_texts = record
+        .values
+        .iter()
+        .filter(|v| matches!(v, ColumnValue::String(s) if s.contains('\u{FFFD}')))
+        .count();
+    confidence -= 0.2 * broken_texts as f32;
+    confidence.clamp(0.0, 1.0)
+}
+
+const PROVENANCE_COLUMNS: [&str; 5] = ["page", "offset", "source", "confidence", "rowid"];
+
+/// Carved records exposed as a SqlStatement. The provenance columns are followed by the values `c0`, `c1`...
+pub struct CarvedStatement {
+    records: Vec<CarvedRecord>,
+    columns: Vec<String>,
+    current: Option<usize>,
+}
+
+impl CarvedStatement {
+    pub fn new(records: Vec<CarvedRecord>) -> Self {
+        let values = records.iter().map(|v| v.values.len()).max().unwrap_or(0);
+        let mut columns: Vec<String> = PROVENANCE_COLUMNS.iter().map(|v| v.to_string()).collect();
+        columns.extend((0..values).map(|i| format!("c{}", i)));
+        Self {
+            records,
+            columns,
+            current: None,
+        }
+    }
+
+    fn record(&self) -> ForensicResult<&CarvedRecord> {
+        match self.current.and_then(|v| self.records.get(v)) {
+            Some(v) => Ok(v),
+            None => Err(ForensicError::NoMoreData),
+        }
+    }
+}
+
+impl SqlStatement for CarvedStatement {
+    fn column_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    fn column_name(&self, i: usize) -> Option<&str> {
+        self.columns.get(i).map(|v| &v[..])
+    }
+
+    fn column_names(&self) -> Vec<&str> {
+        self.columns.iter().map(|v| &v[..]).collect()
+    }
+
+    fn column_type(&self, i: usize) -> ColumnType {
+        match self.read(i) {
+            Ok(ColumnValue::Integer(_)) => ColumnType::Integer,
+            Ok(ColumnValue::Float(_)) => ColumnType::Float,
+            Ok(ColumnValue::String(_)) => ColumnType::String,
+            Ok(ColumnValue::Binary(_)) => ColumnType::Binary,
+            _ => ColumnType::Null,
+        }
+    }
+
+    fn next(&mut self) -> ForensicResult<bool> {
+        let next = self.current.map(|v| v + 1).unwrap_or(0);
+        self.current = Some(next.min(self.records.len()));
+        Ok(next < self.records.len())
+    }
+
+    fn read(&self, i: usize) -> ForensicResult<ColumnValue> {
+        let record = self.record()?;
+        Ok(match i {
+            0 => ColumnValue::Integer(record.page as i64),
+            1 => ColumnValue::Integer(record.offset as i64),
+            2 => ColumnValue::String(record.source.as_str().to_string()),
+            3 => ColumnValue::Float(record.confidence as f64),
+            4 => match record.rowid {
+                Some(v) => ColumnValue::Integer(v),
+                None => ColumnValue::Null,
+            },
+            n => match record.values.get(n - PROVENANCE_COLUMNS.len()) {
+                Some(v) => v.clone(),
+                None => ColumnValue::Null,
+            },
+        })
+    }
+}
@@ -0,0 +1,170 @@
+//! Fallback collations for the collation sequences declared by applications, like `LOCALIZED` or `UNICODE` in Android and iOS databases.
+//! Without them SQLite refuses every query that needs the collation with "no such collation sequence".
+use std::{
+    cmp::Ordering,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    sync::{Arc, Mutex},
+};
+
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_UTF8: c_int = 1;
+/// Collations every SQLite has
+const BUILTIN: [&str; 3] = ["BINARY", "NOCASE", "RTRIM"];
+/// Parts of collation names that mean a locale or case aware comparison
+const UNICODE_HINTS: [&str; 5] = ["LOCALIZED", "UNICODE", "NOCASE", "ICU", "PHONEBOOK"];
+
+/// Comparison used in place of a collation the database declares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollationFallback {
+    /// memcmp, like the BINARY collation
+    Binary,
+    /// Case insensitive comparison of the Unicode text, binary on ties
+    UnicodeNoCase,
+}
+
+impl CollationFallback {
+    /// Fallback for a collation name
+    pub fn for_name(name: &str) -> CollationFallback {
+        let name = name.to_uppercase();
+        if UNICODE_HINTS.iter().any(|v| name.contains(v)) {
+            CollationFallback::UnicodeNoCase
+        } else {
+            CollationFallback::Binary
+        }
+    }
+}
+
+/// A collation of the database replaced by a fallback. Sorting and comparisons on it may differ from the application.
+#[derive(Debug, Clone)]
+pub struct CollationSubstitution {
+    pub name: String,
+    pub fallback: CollationFallback,
+}
+
+pub(crate) type SharedSubstitutions = Arc<Mutex<Vec<CollationSubstitution>>>;
+
+/// Registers fallbacks on demand for the collations SQLite does not know. The substitutions must outlive the connection.
+pub(crate) fn register_fallbacks(connection: &mut Connection) -> SharedSubstitutions {
+    let substitutions: SharedSubstitutions = Arc::new(Mutex::new(Vec::new()));
+    // Without the callback the queries that need a collation fail as they would in SQLite
+    unsafe {
+        ffi::sqlite3_collation_needed(
+            connection.as_raw(),
+            Arc::as_ptr(&substitutions) as *mut c_void,
+            Some(x_collation_needed),
+        );
+    }
+    substitutions
+}
+
+/// Registers the fallback of a collation once
+pub(crate) fn substitute(db: *mut ffi::sqlite3, substitutions: &Mutex<Vec<CollationSubstitution>>, name: &str) {
+    if BUILTIN.iter().any(|v| v.eq_ignore_ascii_case(name)) {
+        return;
+    }
+    let mut substitutions = substitutions.lock().unwrap_or_else(|e| e.into_inner());
+    if substitutions.iter().any(|v| v.name.eq_ignore_ascii_case(name)) {
+        return;
+    }
+    let c_name = match CString::new(name) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let fallback = CollationFallback::for_name(name);
+    let compare: unsafe extern "C" fn(*mut c_void, c_int, *const c_void, c_int, *const c_void) -> c_int = match fallback {
+        CollationFallback::Binary => x_compare_binary,
+        CollationFallback::UnicodeNoCase => x_compare_unicode_nocase,
+    };
+    let result = unsafe { ffi::sqlite3_create_collation_v2(db, c_name.as_ptr(), SQLITE_UTF8, std::ptr::null_mut(), Some(compare), None) };
+    if result == SQLITE_OK {
+        substitutions.push(CollationSubstitution {
+            name: name.to_string(),
+            fallback,
+        });
+    }
+}
+
+unsafe extern "C" fn x_collation_needed(arg: *mut c_void, db: *mut ffi::sqlite3, _text_rep: c_int, name: *const c_char) {
+    if arg.is_null() || name.is_null() {
+        return;
+    }
+    let substitutions = &*(arg as *const Mutex<Vec<CollationSubstitution>>);
+    let name = CStr::from_ptr(name).to_string_lossy();
+    substitute(db, substitutions, &name);
+}
+
+unsafe fn bytes<'a>(len: c_int, data: *const c_void) -> &'a [u8] {
+    if data.is_null() || len <= 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data as *const u8, len as usize)
+    }
+}
+
+fn ordering(value: Ordering) -> c_int {
+    match value {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+unsafe extern "C" fn x_compare_binary(_arg: *mut c_void, len1: c_int, data1: *const c_void, len2: c_int, data2: *const c_void) -> c_int {
+    ordering(bytes(len1, data1).cmp(bytes(len2, data2)))
+}
+
+unsafe extern "C" fn x_compare_unicode_nocase(_arg: *mut c_void, len1: c_int, data1: *const c_void, len2: c_int, data2: *const c_void) -> c_int {
+    let (first, second) = (bytes(len1, data1), bytes(len2, data2));
+    let folded = String::from_utf8_lossy(first)
+        .to_lowercase()
+        .cmp(&String::from_utf8_lossy(second).to_lowercase());
+    ordering(folded.then_with(|| first.cmp(second)))
+}
+
+/// Names after the COLLATE keyword in a CREATE statement
+pub(crate) fn schema_collations(sql: &str) -> Vec<String> {
+    let upper = sql.to_ascii_uppercase();
+    let mut names = Vec::new();
+    let mut start = 0;
+    while let Some(pos) = upper[start..].find("COLLATE") {
+        let keyword = start + pos;
+        start = keyword + "COLLATE".len();
+        let before = sql[..keyword].chars().next_back();
+        let after = sql[start..].chars().next();
+        // Only the whole word, not column names like collate_key
+        if before.map(is_identifier_char).unwrap_or(false) || !after.map(|v| v.is_whitespace()).unwrap_or(false) {
+            continue;
+        }
+        let rest = sql[start..].trim_start();
+        let name = match rest.chars().next() {
+            Some(quote @ ('"' | '\'' | '`')) => rest[1..].split(quote).next().unwrap_or(""),
+            Some('[') => rest[1..].split(']').next().unwrap_or(""),
+            _ => rest.split(|v: char| !is_identifier_char(v)).next().unwrap_or(""),
+        };
+        if !name.is_empty() && !names.iter().any(|v: &String| v.eq_ignore_ascii_case(name)) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+#[cfg(test)]
+mod test_collations {
+    use super::*;
+
+    #[test]
+    fn should_find_schema_collations() {
+        let sql = "CREATE TABLE contacts (name TEXT COLLATE LOCALIZED, sort_key TEXT COLLATE \"icu_root\", collate_key TEXT COLLATE NOCASE)";
+        assert_eq!(vec!["LOCALIZED", "icu_root", "NOCASE"], schema_collations(sql));
+        assert_eq!(CollationFallback::UnicodeNoCase, CollationFallback::for_name("icu_root"));
+        assert_eq!(CollationFallback::Binary, CollationFallback::for_name("custom_sort"));
+    }
+}
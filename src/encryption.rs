@@ -0,0 +1,185 @@
+//! Classification of a file before opening it: plain SQLite, SQLCipher, wrapped in a container or compression format, or not SQLite at all.
+//! Encrypted databases are opened by SQLite without complaint and only fail at the first query with "file is not a database".
+use std::io::{Read, Seek, SeekFrom};
+
+use forensic_rs::prelude::ForensicResult;
+
+use crate::header::{SqliteHeader, HEADER_SIZE};
+
+/// Bytes of the start of the file that are examined
+const SAMPLE_SIZE: usize = 4096;
+/// Shannon entropy in bits per byte above which the data is considered encrypted or compressed
+const HIGH_ENTROPY: f32 = 7.5;
+/// Smallest page size of SQLCipher. v3 defaults to 1024 and v4 to 4096.
+const SQLCIPHER_MIN_PAGE: u64 = 1024;
+/// Reserved bytes of a SQLCipher page: IV and HMAC. 48 for v3 (SHA1), 80 for v4 (SHA512), 16 without HMAC.
+const SQLCIPHER_RESERVED: [u8; 3] = [16, 48, 80];
+
+/// Magic numbers of the formats databases are commonly wrapped in
+const WRAPPERS: [(&[u8], &str); 8] = [
+    (&[0x1f, 0x8b], "gzip"),
+    (b"PK\x03\x04", "zip"),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], "xz"),
+    (&[0x28, 0xb5, 0x2f, 0xfd], "zstd"),
+    (b"BZh", "bzip2"),
+    (&[0x04, 0x22, 0x4d, 0x18], "lz4"),
+    (b"7z\xbc\xaf\x27\x1c", "7z"),
+    (b"bplist00", "bplist"),
+];
+
+/// What the file looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseClass {
+    /// Readable SQLite database, or an empty file
+    Plain,
+    /// Random looking pages with the size and layout of SQLCipher. A key is needed to read it.
+    SqlCipherLikely,
+    /// A compression or container format, or encrypted data that is not page aligned like WhatsApp crypt files
+    Wrapped,
+    /// Neither SQLite nor encrypted looking data
+    NotSqlite,
+}
+
+/// Result of the classification and the evidence behind it
+#[derive(Debug, Clone)]
+pub struct DatabaseClassification {
+    pub class: DatabaseClass,
+    pub size: u64,
+    /// Shannon entropy of the examined bytes in bits per byte, from 0 to 8
+    pub entropy: f32,
+    /// Known format of a wrapped file, like gzip or zip
+    pub wrapper: Option<&'static str>,
+    /// SQLCipher page size that fits the file size. The key derivation settings are still needed to decrypt it.
+    pub page_size: Option<u32>,
+    /// SQLCipher keeps the first 32 bytes of the header in plain text, as iOS apps do to let the system recognize WAL databases
+    pub plaintext_header: bool,
+}
+
+impl DatabaseClassification {
+    /// Whether the file looks encrypted: SQLCipher, or random looking data of no known wrapper format
+    pub fn encrypted(&self) -> bool {
+        match self.class {
+            DatabaseClass::SqlCipherLikely => true,
+            DatabaseClass::Wrapped => self.wrapper.is_none(),
+            _ => false,
+        }
+    }
+}
+
+/// Classifies a file from its first page without opening it with SQLite
+pub fn classify<R: Read + Seek + ?Sized>(reader: &mut R) -> ForensicResult<DatabaseClassification> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut sample = vec![0u8; SAMPLE_SIZE.min(size as usize)];
+    reader.read_exact(&mut sample)?;
+    let mut classification = DatabaseClassification {
+        class: DatabaseClass::NotSqlite,
+        size,
+        entropy: entropy(&sample),
+        wrapper: None,
+        page_size: None,
+        plaintext_header: false,
+    };
+    if size == 0 {
+        classification.class = DatabaseClass::Plain;
+        return Ok(classification);
+    }
+    if let Ok(header) = SqliteHeader::parse(&sample) {
+        if header.magic_valid {
+            let body_entropy = entropy(&sample[HEADER_SIZE..]);
+            if SQLCIPHER_RESERVED.contains(&header.reserved_bytes) && body_entropy > HIGH_ENTROPY {
+                classification.class = DatabaseClass::SqlCipherLikely;
+                classification.plaintext_header = true;
+                classification.page_size = Some(header.page_size).filter(|_| header.page_size_valid());
+                classification.entropy = body_entropy;
+            } else {
+                classification.class = DatabaseClass::Plain;
+            }
+            return Ok(classification);
+        }
+    }
+    if let Some((_, name)) = WRAPPERS.iter().find(|(magic, _)| sample.starts_with(magic)) {
+        classification.class = DatabaseClass::Wrapped;
+        classification.wrapper = Some(*name);
+    } else if classification.entropy > HIGH_ENTROPY {
+        if size % SQLCIPHER_MIN_PAGE == 0 {
+            classification.class = DatabaseClass::SqlCipherLikely;
+            classification.page_size = Some(if size % 4096 == 0 { 4096 } else { SQLCIPHER_MIN_PAGE as u32 });
+        } else {
+            classification.class = DatabaseClass::Wrapped;
+        }
+    }
+    Ok(classification)
+}
+
+/// Shannon entropy in bits per byte
+fn entropy(data: &[u8]) -> f32 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    let len = data.len() as f32;
+    counts
+        .iter()
+        .filter(|v| **v > 0)
+        .map(|v| {
+            let p = *v as f32 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test_encryption {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::page::SQLITE_MAGIC;
+
+    /// Deterministic random looking bytes
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn classify_bytes(data: Vec<u8>) -> DatabaseClassification {
+        classify(&mut Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn should_classify_files() {
+        let mut plain = vec![0u8; 8192];
+        plain[0..16].copy_from_slice(SQLITE_MAGIC);
+        plain[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        assert_eq!(DatabaseClass::Plain, classify_bytes(plain.clone()).class);
+
+        let cipher = classify_bytes(random_bytes(16384));
+        assert_eq!(DatabaseClass::SqlCipherLikely, cipher.class);
+        assert_eq!(Some(4096), cipher.page_size);
+        assert!(cipher.entropy > HIGH_ENTROPY);
+
+        let mut plaintext_header = random_bytes(8192);
+        plaintext_header[0..100].copy_from_slice(&plain[0..100]);
+        plaintext_header[20] = 80;
+        let plaintext_header = classify_bytes(plaintext_header);
+        assert_eq!(DatabaseClass::SqlCipherLikely, plaintext_header.class);
+        assert!(plaintext_header.plaintext_header);
+
+        assert_eq!(DatabaseClass::Wrapped, classify_bytes(random_bytes(10_000)).class);
+        let mut gzip = random_bytes(8192);
+        gzip[0..2].copy_from_slice(&[0x1f, 0x8b]);
+        assert_eq!(Some("gzip"), classify_bytes(gzip).wrapper);
+        assert_eq!(DatabaseClass::NotSqlite, classify_bytes(b"just some text, not a database".to_vec()).class);
+    }
+}
@@ -0,0 +1,188 @@
+//! Typed errors of SQLite, classified by result code. https://www.sqlite.org/rescode.html
+use std::fmt;
+
+use forensic_rs::prelude::ForensicError;
+
+const SQLITE_ERROR: isize = 1;
+const SQLITE_PERM: isize = 3;
+const SQLITE_BUSY: isize = 5;
+const SQLITE_LOCKED: isize = 6;
+const SQLITE_READONLY: isize = 8;
+const SQLITE_IOERR: isize = 10;
+const SQLITE_CORRUPT: isize = 11;
+const SQLITE_CANTOPEN: isize = 14;
+const SQLITE_CONSTRAINT: isize = 19;
+const SQLITE_MISMATCH: isize = 20;
+const SQLITE_AUTH: isize = 23;
+const SQLITE_RANGE: isize = 25;
+const SQLITE_NOTADB: isize = 26;
+
+/// What went wrong, from the primary result code and, for generic errors, the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqliteErrorKind {
+    /// The file is not a database
+    NotADatabase,
+    /// SQLite reported the file is not a database but it looks encrypted, like a SQLCipher database: a key is needed.
+    /// Only told apart from `NotADatabase` for databases opened from a VirtualFile, whose evidence can be classified.
+    Encrypted,
+    /// A page or record of the database is malformed
+    Corrupt,
+    /// Another connection holds a lock on the database
+    Busy,
+    /// A table of the same connection is locked
+    Locked,
+    ReadOnly,
+    PermissionDenied,
+    CantOpen,
+    Io,
+    NoSuchTable,
+    NoSuchColumn,
+    /// Function or collation not registered
+    NoSuchFunction,
+    /// Virtual table module or FTS tokenizer not registered
+    NoSuchModule,
+    Syntax,
+    TypeMismatch,
+    Constraint,
+    /// Parameter or column index out of range
+    Range,
+    Other,
+}
+
+/// An error of SQLite with its result code, its message and the SQL that caused it
+#[derive(Debug, Clone)]
+pub struct SqliteError {
+    pub kind: SqliteErrorKind,
+    /// Extended result code, the same as the primary code when the connection does not report extended codes. None for errors of the wrapper itself.
+    pub extended_code: Option<isize>,
+    pub message: String,
+    /// Statement being prepared or run
+    pub sql: Option<String>,
+}
+
+impl SqliteError {
+    pub fn new(error: sqlite::Error, sql: Option<&str>) -> SqliteError {
+        let message = error.message.unwrap_or_default();
+        SqliteError {
+            kind: classify(error.code, &message),
+            extended_code: error.code,
+            message,
+            sql: sql.map(|v| v.to_string()),
+        }
+    }
+
+    /// Primary result code: the low byte of the extended code
+    pub fn code(&self) -> Option<isize> {
+        self.extended_code.map(|v| v & 0xff)
+    }
+}
+
+impl From<sqlite::Error> for SqliteError {
+    fn from(error: sqlite::Error) -> SqliteError {
+        SqliteError::new(error, None)
+    }
+}
+
+impl fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(code) = self.extended_code {
+            write!(f, " (code {})", code)?;
+        }
+        if let Some(sql) = &self.sql {
+            write!(f, " in: {}", sql)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SqliteError {}
+
+/// Every error keeps its message, its code and its SQL. Errors of the file and the connection become I/O errors of the matching kind,
+/// the rest become `ForensicError::Other`.
+impl From<SqliteError> for ForensicError {
+    fn from(error: SqliteError) -> ForensicError {
+        let io_kind = match error.kind {
+            SqliteErrorKind::Io => std::io::ErrorKind::Other,
+            SqliteErrorKind::CantOpen => std::io::ErrorKind::NotFound,
+            SqliteErrorKind::ReadOnly | SqliteErrorKind::PermissionDenied => std::io::ErrorKind::PermissionDenied,
+            // The operation can be retried once the lock is released
+            SqliteErrorKind::Busy | SqliteErrorKind::Locked => std::io::ErrorKind::WouldBlock,
+            SqliteErrorKind::Encrypted => return ForensicError::Other(format!("The database looks encrypted, a key is needed: {}", error)),
+            _ => return ForensicError::Other(error.to_string()),
+        };
+        ForensicError::Io(std::io::Error::new(io_kind, error.to_string()))
+    }
+}
+
+fn classify(code: Option<isize>, message: &str) -> SqliteErrorKind {
+    let code = match code {
+        Some(v) => v & 0xff,
+        // Errors raised by the sqlite crate before calling SQLite
+        None if message.contains("out of range") => return SqliteErrorKind::Range,
+        None => return SqliteErrorKind::Other,
+    };
+    match code {
+        SQLITE_NOTADB => SqliteErrorKind::NotADatabase,
+        SQLITE_CORRUPT => SqliteErrorKind::Corrupt,
+        SQLITE_BUSY => SqliteErrorKind::Busy,
+        SQLITE_LOCKED => SqliteErrorKind::Locked,
+        SQLITE_READONLY => SqliteErrorKind::ReadOnly,
+        SQLITE_PERM | SQLITE_AUTH => SqliteErrorKind::PermissionDenied,
+        SQLITE_CANTOPEN => SqliteErrorKind::CantOpen,
+        SQLITE_IOERR => SqliteErrorKind::Io,
+        SQLITE_CONSTRAINT => SqliteErrorKind::Constraint,
+        SQLITE_MISMATCH => SqliteErrorKind::TypeMismatch,
+        SQLITE_RANGE => SqliteErrorKind::Range,
+        // Generic errors only tell the cause in the message
+        SQLITE_ERROR => {
+            if message.starts_with("no such table") {
+                SqliteErrorKind::NoSuchTable
+            } else if message.starts_with("no such column") {
+                SqliteErrorKind::NoSuchColumn
+            } else if message.starts_with("no such function") || message.starts_with("no such collation") {
+                SqliteErrorKind::NoSuchFunction
+            } else if message.starts_with("no such module") || message.starts_with("unknown tokenizer") {
+                SqliteErrorKind::NoSuchModule
+            } else if message.contains("syntax error") || message.starts_with("incomplete input") {
+                SqliteErrorKind::Syntax
+            } else if message.contains("datatype mismatch") {
+                SqliteErrorKind::TypeMismatch
+            } else {
+                SqliteErrorKind::Other
+            }
+        }
+        _ => SqliteErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+
+    fn error(code: isize, message: &str) -> SqliteError {
+        SqliteError::new(sqlite::Error { code: Some(code), message: Some(message.into()) }, Some("SELECT 1"))
+    }
+
+    #[test]
+    fn should_classify_result_codes() {
+        let corrupt = error(779, "database disk image is malformed");
+        assert_eq!(SqliteErrorKind::Corrupt, corrupt.kind);
+        assert_eq!(Some(SQLITE_CORRUPT), corrupt.code());
+        assert_eq!("database disk image is malformed (code 779) in: SELECT 1", corrupt.to_string());
+        assert_eq!(SqliteErrorKind::NotADatabase, error(26, "file is not a database").kind);
+        assert_eq!(SqliteErrorKind::NoSuchTable, error(1, "no such table: users").kind);
+        assert_eq!(SqliteErrorKind::Syntax, error(1, "near \"SELEC\": syntax error").kind);
+        assert!(matches!(
+            ForensicError::from(error(1, "no such column: age")),
+            ForensicError::Other(v) if v == "no such column: age (code 1) in: SELECT 1"
+        ));
+        assert!(matches!(
+            ForensicError::from(error(517, "database is locked")),
+            ForensicError::Io(v) if v.kind() == std::io::ErrorKind::WouldBlock && v.to_string().contains("code 517")
+        ));
+        let mut encrypted = error(26, "file is not a database");
+        encrypted.kind = SqliteErrorKind::Encrypted;
+        assert!(matches!(ForensicError::from(encrypted), ForensicError::Other(v) if v.starts_with("The database looks encrypted")));
+    }
+}
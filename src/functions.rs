@@ -0,0 +1,288 @@
+//! Scalar SQL functions registered on every connection, so artifacts can be decoded inside queries:
+//!
+//! - `chrome_time(x)`, `mac_time(x)`, `filetime(x)`, `unix_ms(x)`: timestamp to RFC 3339 UTC text. `mac_time` takes nanoseconds when the value is too large for seconds.
+//! - `hexdump(x)`: hexadecimal dump of a BLOB or text.
+//! - `b64(x)`: base64 of a BLOB or text.
+//! - `regexp(pattern, x)`: backs the `x REGEXP pattern` operator.
+//! - `bplist_json(x)`: binary plist to JSON.
+//!
+//! Every function is deterministic, never touches the database and returns NULL for NULL or unusable input.
+use std::{
+    cell::RefCell,
+    ffi::CString,
+    os::raw::{c_char, c_int, c_void},
+};
+
+use forensic_rs::{
+    prelude::{ForensicError, ForensicResult},
+    traits::sql::ColumnValue,
+};
+use regex::Regex;
+use sqlite::Connection;
+use sqlite3_sys as ffi;
+
+use crate::{
+    bplist,
+    timestamp::{Timestamp, TimestampFormat},
+};
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_UTF8: c_int = 1;
+const SQLITE_DETERMINISTIC: c_int = 0x0000_0800;
+/// The function has no side effects and is safe in views and triggers
+const SQLITE_INNOCUOUS: c_int = 0x0020_0000;
+
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_TEXT: c_int = 3;
+const SQLITE_BLOB: c_int = 4;
+
+/// Mac absolute times larger than this are taken as nanoseconds: as seconds they would be thousands of years away
+const MAC_NANOS_THRESHOLD: f64 = 1e11;
+
+type ScalarFunction = fn(&[ColumnValue]) -> Result<ColumnValue, String>;
+
+const FUNCTIONS: [(&str, c_int, ScalarFunction); 8] = [
+    ("chrome_time", 1, chrome_time),
+    ("mac_time", 1, mac_time),
+    ("filetime", 1, filetime),
+    ("unix_ms", 1, unix_ms),
+    ("hexdump", 1, hexdump),
+    ("b64", 1, b64),
+    ("regexp", 2, regexp),
+    ("bplist_json", 1, bplist_json),
+];
+
+/// Registers the forensic functions on a connection
+pub(crate) fn register(connection: &mut Connection) -> ForensicResult<()> {
+    let db = connection.as_raw();
+    for (name, args, function) in FUNCTIONS {
+        let c_name = CString::new(name).map_err(|e| ForensicError::Other(e.to_string()))?;
+        let result = unsafe {
+            ffi::sqlite3_create_function_v2(
+                db,
+                c_name.as_ptr(),
+                args,
+                SQLITE_UTF8 | SQLITE_DETERMINISTIC | SQLITE_INNOCUOUS,
+                function as usize as *mut c_void,
+                Some(x_func),
+                None,
+                None,
+                None,
+            )
+        };
+        if result != SQLITE_OK {
+            return Err(ForensicError::Other(format!("Cannot register the SQL function {}: error {}", name, result)));
+        }
+    }
+    Ok(())
+}
+
+/// Entry point of every function. The Rust function is the user data of the SQLite function.
+unsafe extern "C" fn x_func(ctx: *mut ffi::sqlite3_context, argc: c_int, argv: *mut *mut ffi::sqlite3_value) {
+    let function: ScalarFunction = std::mem::transmute::<*mut c_void, ScalarFunction>(ffi::sqlite3_user_data(ctx));
+    let args: Vec<ColumnValue> = (0..argc.max(0) as usize).map(|i| read_value(*argv.add(i))).collect();
+    // Panics must not unwind into SQLite
+    let result = match std::panic::catch_unwind(|| function(&args)) {
+        Ok(v) => v,
+        Err(_) => Err("Panic in SQL function".to_string()),
+    };
+    match result {
+        Ok(value) => set_result(ctx, value),
+        Err(msg) => ffi::sqlite3_result_error(ctx, msg.as_ptr() as *const c_char, msg.len() as c_int),
+    }
+}
+
+unsafe fn read_value(value: *mut ffi::sqlite3_value) -> ColumnValue {
+    match ffi::sqlite3_value_type(value) {
+        SQLITE_INTEGER => ColumnValue::Integer(ffi::sqlite3_value_int64(value)),
+        SQLITE_FLOAT => ColumnValue::Float(ffi::sqlite3_value_double(value)),
+        SQLITE_TEXT => {
+            let text = ffi::sqlite3_value_text(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if text.is_null() {
+                ColumnValue::String(String::new())
+            } else {
+                ColumnValue::String(String::from_utf8_lossy(std::slice::from_raw_parts(text, len)).into_owned())
+            }
+        }
+        SQLITE_BLOB => {
+            let blob = ffi::sqlite3_value_blob(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if blob.is_null() {
+                ColumnValue::Binary(Vec::new())
+            } else {
+                ColumnValue::Binary(std::slice::from_raw_parts(blob as *const u8, len).to_vec())
+            }
+        }
+        _ => ColumnValue::Null,
+    }
+}
+
+unsafe fn set_result(ctx: *mut ffi::sqlite3_context, value: ColumnValue) {
+    match value {
+        ColumnValue::Null => ffi::sqlite3_result_null(ctx),
+        ColumnValue::Integer(v) => ffi::sqlite3_result_int64(ctx, v),
+        ColumnValue::Float(v) => ffi::sqlite3_result_double(ctx, v),
+        ColumnValue::String(v) => ffi::sqlite3_result_text(ctx, v.as_ptr() as *const c_char, v.len() as c_int, transient()),
+        ColumnValue::Binary(v) => ffi::sqlite3_result_blob(ctx, v.as_ptr() as *const c_void, v.len() as c_int, transient()),
+    }
+}
+
+/// SQLITE_TRANSIENT: SQLite copies the result before the function returns
+fn transient() -> Option<unsafe extern "C" fn(*mut c_void)> {
+    unsafe { std::mem::transmute::<isize, Option<unsafe extern "C" fn(*mut c_void)>>(-1) }
+}
+
+fn time_text(value: &ColumnValue, format: TimestampFormat) -> ColumnValue {
+    match Timestamp::from_value(value, format) {
+        Ok(v) => ColumnValue::String(v.to_string()),
+        Err(_) => ColumnValue::Null,
+    }
+}
+
+fn chrome_time(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    Ok(time_text(&args[0], TimestampFormat::WebKit))
+}
+
+fn mac_time(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    let large = match &args[0] {
+        ColumnValue::Integer(v) => (*v as f64).abs() > MAC_NANOS_THRESHOLD,
+        ColumnValue::Float(v) => v.abs() > MAC_NANOS_THRESHOLD,
+        _ => false,
+    };
+    let format = if large { TimestampFormat::MacAbsoluteNanos } else { TimestampFormat::MacAbsolute };
+    Ok(time_text(&args[0], format))
+}
+
+fn filetime(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    Ok(time_text(&args[0], TimestampFormat::Filetime))
+}
+
+fn unix_ms(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    Ok(time_text(&args[0], TimestampFormat::UnixMillis))
+}
+
+fn bytes(value: &ColumnValue) -> Option<&[u8]> {
+    match value {
+        ColumnValue::Binary(v) => Some(v),
+        ColumnValue::String(v) => Some(v.as_bytes()),
+        _ => None,
+    }
+}
+
+fn hexdump(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    let data = match bytes(&args[0]) {
+        Some(v) => v,
+        None => return Ok(ColumnValue::Null),
+    };
+    let mut lines = Vec::with_capacity(data.len() / 16 + 1);
+    for (pos, chunk) in data.chunks(16).enumerate() {
+        let mut hex = String::with_capacity(49);
+        for (i, byte) in chunk.iter().enumerate() {
+            if i == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|v| if v.is_ascii_graphic() || *v == b' ' { *v as char } else { '.' })
+            .collect();
+        lines.push(format!("{:08x}  {:<49} |{}|", pos * 16, hex, ascii));
+    }
+    Ok(ColumnValue::String(lines.join("\n")))
+}
+
+fn b64(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    Ok(match bytes(&args[0]) {
+        Some(v) => ColumnValue::String(base64(v)),
+        None => ColumnValue::Null,
+    })
+}
+
+/// Standard base64 with padding
+pub(crate) fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let block = ((chunk[0] as u32) << 16) | ((*chunk.get(1).unwrap_or(&0) as u32) << 8) | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((block >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+thread_local! {
+    /// Last compiled pattern: queries call regexp with the same pattern for every row
+    static LAST_REGEX: RefCell<Option<(String, Regex)>> = const { RefCell::new(None) };
+}
+
+fn regexp(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    let (pattern, value) = match (&args[0], &args[1]) {
+        (ColumnValue::String(pattern), ColumnValue::String(value)) => (pattern, value.clone()),
+        (ColumnValue::String(pattern), ColumnValue::Integer(value)) => (pattern, value.to_string()),
+        (ColumnValue::String(pattern), ColumnValue::Float(value)) => (pattern, value.to_string()),
+        (ColumnValue::String(pattern), ColumnValue::Binary(value)) => (pattern, String::from_utf8_lossy(value).into_owned()),
+        _ => return Ok(ColumnValue::Null),
+    };
+    LAST_REGEX.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.as_ref().map(|v| &v.0 != pattern).unwrap_or(true) {
+            let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+            *cache = Some((pattern.clone(), regex));
+        }
+        let matched = cache.as_ref().map(|v| v.1.is_match(&value)).unwrap_or(false);
+        Ok(ColumnValue::Integer(matched as i64))
+    })
+}
+
+fn bplist_json(args: &[ColumnValue]) -> Result<ColumnValue, String> {
+    Ok(match &args[0] {
+        ColumnValue::Binary(v) => match bplist::to_json(v) {
+            Ok(json) => ColumnValue::String(json),
+            Err(_) => ColumnValue::Null,
+        },
+        _ => ColumnValue::Null,
+    })
+}
+
+#[cfg(test)]
+mod test_functions {
+    use super::*;
+
+    #[test]
+    fn should_encode_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+    }
+
+    #[test]
+    fn should_call_functions_from_sql() {
+        let mut connection = sqlite::open(":memory:").unwrap();
+        register(&mut connection).unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT chrome_time(13344473600000000), mac_time(721692800), mac_time(721692800000000000), filetime(133444736000000000),
+                unix_ms(1700000000000), b64('foo'), 'Alice' REGEXP '^A.*e$', 'Bob' REGEXP '^A', hexdump(x'53514c'), chrome_time(NULL)",
+            )
+            .unwrap();
+        assert_eq!(sqlite::State::Row, statement.next().unwrap());
+        for i in 0..5 {
+            assert_eq!("2023-11-14T22:13:20Z", statement.read::<String, _>(i).unwrap());
+        }
+        assert_eq!("Zm9v", statement.read::<String, _>(5).unwrap());
+        assert_eq!(1, statement.read::<i64, _>(6).unwrap());
+        assert_eq!(0, statement.read::<i64, _>(7).unwrap());
+        assert_eq!(format!("00000000  {:<49} |SQL|", "53 51 4c "), statement.read::<String, _>(8).unwrap());
+        assert_eq!(sqlite::Type::Null, statement.column_type(9).unwrap());
+        assert!(connection.execute("SELECT 'a' REGEXP '(';").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! The 100-byte header at the start of every database file. https://www.sqlite.org/fileformat.html#the_database_header
+use std::io::{Read, Seek, SeekFrom};
+
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+
+use crate::page::{be_u16, be_u32, SQLITE_MAGIC};
+
+pub const HEADER_SIZE: usize = 100;
+
+/// Encoding of the texts stored in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+    /// Value not defined by SQLite, including 0 for databases without schema
+    Unknown(u32),
+}
+
+/// Fields of the database header. The values are read as they are, without validation, so they are available even for files SQLite refuses to open.
+#[derive(Debug, Clone)]
+pub struct SqliteHeader {
+    /// The file starts with "SQLite format 3\0"
+    pub magic_valid: bool,
+    /// Page size in bytes, with the value 1 already translated to 65536
+    pub page_size: u32,
+    /// File format write version: 1 legacy, 2 WAL
+    pub write_version: u8,
+    /// File format read version: 1 legacy, 2 WAL
+    pub read_version: u8,
+    /// Bytes reserved at the end of each page, used by extensions like SQLCipher
+    pub reserved_bytes: u8,
+    pub max_payload_fraction: u8,
+    pub min_payload_fraction: u8,
+    pub leaf_payload_fraction: u8,
+    pub file_change_counter: u32,
+    /// Size of the database in pages. Only reliable when `page_count_valid`.
+    pub page_count: u32,
+    pub freelist_trunk: u32,
+    pub freelist_count: u32,
+    pub schema_cookie: u32,
+    /// 1 to 4
+    pub schema_format: u32,
+    pub default_cache_size: u32,
+    /// Largest root b-tree page in auto-vacuum or incremental-vacuum modes, zero otherwise
+    pub largest_root_page: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: i32,
+    /// Incremental-vacuum mode flag
+    pub incremental_vacuum: bool,
+    pub application_id: u32,
+    pub version_valid_for: u32,
+    /// SQLITE_VERSION_NUMBER of the library that last wrote the file, like 3041002
+    pub sqlite_version: u32,
+}
+
+impl SqliteHeader {
+    /// Parses the header from its 100 bytes
+    pub fn parse(data: &[u8]) -> ForensicResult<SqliteHeader> {
+        if data.len() < HEADER_SIZE {
+            return Err(ForensicError::Other(format!("A SQLite header has {} bytes, only {} available", HEADER_SIZE, data.len())));
+        }
+        Ok(SqliteHeader {
+            magic_valid: &data[0..16] == SQLITE_MAGIC,
+            page_size: match be_u16(&data[16..18]) {
+                1 => 65536,
+                v => v as u32,
+            },
+            write_version: data[18],
+            read_version: data[19],
+            reserved_bytes: data[20],
+            max_payload_fraction: data[21],
+            min_payload_fraction: data[22],
+            leaf_payload_fraction: data[23],
+            file_change_counter: be_u32(&data[24..28]),
+            page_count: be_u32(&data[28..32]),
+            freelist_trunk: be_u32(&data[32..36]),
+            freelist_count: be_u32(&data[36..40]),
+            schema_cookie: be_u32(&data[40..44]),
+            schema_format: be_u32(&data[44..48]),
+            default_cache_size: be_u32(&data[48..52]),
+            largest_root_page: be_u32(&data[52..56]),
+            text_encoding: match be_u32(&data[56..60]) {
+                1 => TextEncoding::Utf8,
+                2 => TextEncoding::Utf16le,
+                3 => TextEncoding::Utf16be,
+                v => TextEncoding::Unknown(v),
+            },
+            user_version: be_u32(&data[60..64]) as i32,
+            incremental_vacuum: be_u32(&data[64..68]) != 0,
+            application_id: be_u32(&data[68..72]),
+            version_valid_for: be_u32(&data[92..96]),
+            sqlite_version: be_u32(&data[96..100]),
+        })
+    }
+
+    /// Reads the header at the start of a file
+    pub fn read<R: Read + Seek + ?Sized>(reader: &mut R) -> ForensicResult<SqliteHeader> {
+        let mut data = [0u8; HEADER_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        let mut readed = 0;
+        while readed < HEADER_SIZE {
+            match reader.read(&mut data[readed..])? {
+                0 => break,
+                n => readed += n,
+            }
+        }
+        Self::parse(&data[0..readed])
+    }
+
+    /// Page size is a power of two between 512 and 65536
+    pub fn page_size_valid(&self) -> bool {
+        (512..=65536).contains(&self.page_size) && self.page_size.is_power_of_two()
+    }
+
+    /// Usable bytes of each page: the page size minus the reserved bytes
+    pub fn usable_size(&self) -> u32 {
+        self.page_size.saturating_sub(self.reserved_bytes as u32)
+    }
+
+    /// The in-header page count is only valid if the file was last written by a version of SQLite that maintains it
+    pub fn page_count_valid(&self) -> bool {
+        self.page_count != 0 && self.file_change_counter == self.version_valid_for
+    }
+
+    pub fn wal_mode(&self) -> bool {
+        self.write_version == 2 || self.read_version == 2
+    }
+
+    /// Auto-vacuum or incremental-vacuum are enabled
+    pub fn auto_vacuum(&self) -> bool {
+        self.largest_root_page != 0
+    }
+
+    /// SQLite version as text, like "3.41.2"
+    pub fn sqlite_version_string(&self) -> String {
+        format!(
+            "{}.{}.{}",
+            self.sqlite_version / 1_000_000,
+            (self.sqlite_version / 1000) % 1000,
+            self.sqlite_version % 1000
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_header {
+    use super::*;
+
+    use forensic_rs::traits::vfs::VirtualFileSystem;
+
+    use crate::workspace::TempWorkspace;
+
+    #[test]
+    fn should_read_header_of_database() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("header.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection
+            .execute("PRAGMA page_size=8192; PRAGMA user_version=7; PRAGMA application_id=1234; CREATE TABLE t (a);")
+            .unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut file = fs.open(&temp_path).unwrap();
+        let header = SqliteHeader::read(file.as_mut()).unwrap();
+        assert!(header.magic_valid);
+        assert_eq!(8192, header.page_size);
+        assert_eq!(7, header.user_version);
+        assert_eq!(1234, header.application_id);
+        assert_eq!(TextEncoding::Utf8, header.text_encoding);
+        assert_eq!(4, header.schema_format);
+        assert!(header.page_count_valid());
+        assert_eq!(2, header.page_count);
+        assert!(header.sqlite_version_string().starts_with("3."));
+    }
+
+    #[test]
+    fn should_read_header_of_invalid_file() {
+        let header = SqliteHeader::parse(&[0xffu8; 100]).unwrap();
+        assert!(!header.magic_valid);
+        assert!(!header.page_size_valid());
+        assert!(SqliteHeader::parse(&[0u8; 50]).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+//! Recovery of the keys of index b-trees. Index pages keep copies of the indexed columns, like URLs, phone numbers or message ids, after the rows of the table are gone:
+//! in the free space of the index pages and in freed pages that used to be index pages.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Seek},
+};
+
+use forensic_rs::{
+    prelude::ForensicResult,
+    traits::sql::{ColumnValue, SqlDb},
+};
+
+use crate::{
+    carving::freelist_pages,
+    matching::{recover_partial_record, table_score, MIN_SCORE},
+    page::{cell_fragment, parse_btree_page, walk, BtreePage, DbFile},
+    record::decode_record,
+    recovered::quote_identifier,
+    schema::{Affinity, Schema, SchemaColumn, SchemaTable},
+    SqliteDB,
+};
+
+/// Where an index key was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKeySource {
+    /// Cell of the index b-tree
+    Live,
+    /// Freeblock inside a page of the index b-tree
+    Freeblock,
+    /// Space between the cell pointer array and the cell content area of a page of the index b-tree
+    Unallocated,
+    /// Page in the freelist that used to be an index page
+    Freelist,
+}
+
+impl IndexKeySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexKeySource::Live => "live",
+            IndexKeySource::Freeblock => "freeblock",
+            IndexKeySource::Unallocated => "unallocated",
+            IndexKeySource::Freelist => "freelist",
+        }
+    }
+}
+
+/// A key of an index b-tree linked to the table and columns it indexes
+#[derive(Debug, Clone)]
+pub struct IndexKey {
+    /// Index of the key. Keys of freed pages go to the index whose columns fit them best, None when no index or more than one fits equally well.
+    pub index: Option<String>,
+    pub table: Option<String>,
+    /// Column of the table of each value: the indexed columns followed by the rowid or the primary key. None for expressions and keys without index.
+    pub columns: Vec<Option<String>>,
+    pub serial_types: Vec<u64>,
+    pub values: Vec<ColumnValue>,
+    /// Rowid of the row the key points to: the last value of the keys of rowid tables
+    pub rowid: Option<i64>,
+    /// Whether the table still has a row with the rowid. Only set by `SqliteDB::carve_index_keys`.
+    pub row_exists: Option<bool>,
+    pub page: u32,
+    /// Offset of the cell or record in the database file
+    pub offset: u64,
+    pub source: IndexKeySource,
+    /// The key continued in overflow pages that could not be read or the free space ended before the record did
+    pub truncated: bool,
+    /// The start of the header was overwritten and the first serial types were guessed from the index columns
+    pub partial_header: bool,
+}
+
+/// Columns stored in the keys of an index
+struct IndexLayout {
+    index: String,
+    table: String,
+    root_page: u32,
+    columns: Vec<Option<String>>,
+    /// The key columns as a table, to score keys like carved rows
+    key_table: SchemaTable,
+    /// The last column is the rowid of the table
+    rowid: bool,
+}
+
+/// Carves the keys of the index b-trees of a database file and of the freelist pages that used to be index pages.
+/// Keys are linked to their index, table and columns according to the schema. Live keys are returned too, with source `Live`.
+pub fn carve_index_keys<R: Read + Seek + ?Sized>(reader: &mut R, schema: &Schema) -> ForensicResult<Vec<IndexKey>> {
+    let mut db = DbFile::open(reader)?;
+    let layouts = index_layouts(schema);
+    let mut carver = KeyCarver {
+        layouts: &layouts,
+        usable_size: db.usable_size,
+        keys: Vec::new(),
+    };
+    let mut used = BTreeSet::new();
+    for (i, layout) in layouts.iter().enumerate() {
+        // Pages that cannot be read are skipped, the rest of the index is still carved
+        for walked in walk(&mut db, layout.root_page, &mut used)?.pages {
+            let number = walked.page.number;
+            let base = db.page_offset(number);
+            for cell in &walked.page.cells {
+                let (payload, truncated) = match cell.overflow_page {
+                    Some(_) => {
+                        let fragment = cell_fragment(&mut db, number, cell, &mut used);
+                        (fragment.payload, !fragment.complete)
+                    }
+                    None => (cell.payload.clone(), false),
+                };
+                if let Ok(record) = decode_record(&payload) {
+                    let mut key = found_key(record.serial_types, record.values, number, base + cell.offset as u64, IndexKeySource::Live);
+                    key.truncated = truncated || record.truncated;
+                    carver.link(Some(i), key);
+                }
+            }
+            let data = db.read_page(number)?;
+            carver.carve_free_space(&walked.page, &data, base, &[i], false);
+        }
+    }
+    let all: Vec<usize> = (0..layouts.len()).collect();
+    let (_, leaves) = freelist_pages(&mut db)?;
+    for number in leaves {
+        if used.contains(&number) {
+            continue;
+        }
+        let data = db.read_page(number)?;
+        let page = match parse_btree_page(number, &data, db.usable_size) {
+            Ok(v) if !v.page_type.is_table() => v,
+            _ => continue,
+        };
+        let base = db.page_offset(number);
+        // A freed page keeps its old content, cells included. Their overflow pages may have been reused.
+        for cell in &page.cells {
+            if let Ok(record) = decode_record(&cell.payload) {
+                let layout = carver.best_layout(&all, &record.serial_types);
+                let mut key = found_key(record.serial_types, record.values, number, base + cell.offset as u64, IndexKeySource::Freelist);
+                key.truncated = record.truncated || cell.overflow_page.is_some();
+                carver.link(layout, key);
+            }
+        }
+        carver.carve_free_space(&page, &data, base, &all, true);
+    }
+    Ok(carver.keys)
+}
+
+/// Layout of the keys of every index with a b-tree of its own. The primary key of a WITHOUT ROWID table is the table b-tree itself.
+fn index_layouts(schema: &Schema) -> Vec<IndexLayout> {
+    let table_roots: BTreeSet<u32> = schema.tables.iter().map(|v| v.root_page).collect();
+    schema
+        .indexes
+        .iter()
+        .filter(|v| v.root_page != 0 && !table_roots.contains(&v.root_page))
+        .filter_map(|index| {
+            let table = schema.table(&index.table)?;
+            let mut columns = index.columns.clone();
+            if table.without_rowid {
+                // Keys end with the primary key columns the index does not hold already
+                let mut primary_key: Vec<&SchemaColumn> = table.columns.iter().filter(|v| v.primary_key > 0).collect();
+                primary_key.sort_by_key(|v| v.primary_key);
+                for column in primary_key {
+                    if !columns.iter().flatten().any(|v| v.eq_ignore_ascii_case(&column.name)) {
+                        columns.push(Some(column.name.clone()));
+                    }
+                }
+            } else {
+                let alias = table.columns.iter().find(|v| v.rowid_alias).map(|v| v.name.clone());
+                columns.push(Some(alias.unwrap_or_else(|| "rowid".to_string())));
+            }
+            let key_columns = columns.iter().map(|name| key_column(table, name.as_deref())).collect();
+            Some(IndexLayout {
+                index: index.name.clone(),
+                table: table.name.clone(),
+                root_page: index.root_page,
+                key_table: SchemaTable {
+                    name: index.name.clone(),
+                    columns: key_columns,
+                    root_page: index.root_page,
+                    without_rowid: false,
+                    sql: String::new(),
+                },
+                columns,
+                rowid: !table.without_rowid,
+            })
+        })
+        .collect()
+}
+
+/// Column of a key. Expressions take any value and the rowid is an integer.
+fn key_column(table: &SchemaTable, name: Option<&str>) -> SchemaColumn {
+    match name.and_then(|name| table.columns.iter().find(|v| v.name.eq_ignore_ascii_case(name))) {
+        // Keys store the value of the INTEGER PRIMARY KEY, not the NULL of the record
+        Some(column) => SchemaColumn {
+            rowid_alias: false,
+            ..column.clone()
+        },
+        None => {
+            let declared_type = if name == Some("rowid") { "INTEGER" } else { "" };
+            SchemaColumn {
+                name: name.unwrap_or_default().to_string(),
+                declared_type: declared_type.to_string(),
+                affinity: Affinity::from_declared_type(declared_type),
+                primary_key: 0,
+                not_null: false,
+                default_value: None,
+                rowid_alias: false,
+            }
+        }
+    }
+}
+
+/// Key found at an offset of the file, not linked to an index yet
+fn found_key(serial_types: Vec<u64>, values: Vec<ColumnValue>, page: u32, offset: u64, source: IndexKeySource) -> IndexKey {
+    IndexKey {
+        index: None,
+        table: None,
+        columns: vec![None; values.len()],
+        serial_types,
+        values,
+        rowid: None,
+        row_exists: None,
+        page,
+        offset,
+        source,
+        truncated: false,
+        partial_header: false,
+    }
+}
+
+/// Index page being carved
+struct KeyPage<'a> {
+    number: u32,
+    data: &'a [u8],
+    /// Offset of the page in the file
+    base: u64,
+    /// Indexes the page may belong to
+    candidates: &'a [usize],
+}
+
+struct KeyCarver<'a> {
+    layouts: &'a [IndexLayout],
+    usable_size: usize,
+    keys: Vec<IndexKey>,
+}
+
+impl<'a> KeyCarver<'a> {
+    /// Carves the freeblocks and the unallocated space of an index page. `candidates` are the indexes the page may belong to.
+    fn carve_free_space(&mut self, page: &BtreePage, data: &[u8], base: u64, candidates: &[usize], freed: bool) {
+        let usable_size = self.usable_size.min(data.len());
+        let key_page = KeyPage {
+            number: page.number,
+            data,
+            base,
+            candidates,
+        };
+        for freeblock in &page.freeblocks {
+            let source = if freed { IndexKeySource::Freelist } else { IndexKeySource::Freeblock };
+            // The first 4 bytes of the old cell were overwritten by the freeblock header
+            let (start, end) = (freeblock.offset + 4, freeblock.offset + freeblock.size);
+            if self.scan(&key_page, start, end, source) == 0 && page.page_type.is_leaf() {
+                self.carve_partial_key(&key_page, start, end, source);
+            }
+        }
+        let source = if freed { IndexKeySource::Freelist } else { IndexKeySource::Unallocated };
+        let end = page.cell_content_start.min(usable_size);
+        self.scan(&key_page, page.cell_pointers_end(), end, source);
+    }
+
+    /// Looks for keys at every offset of a region. Returns the number of keys found.
+    fn scan(&mut self, page: &KeyPage, start: usize, end: usize, source: IndexKeySource) -> usize {
+        let found = self.keys.len();
+        let mut offset = start;
+        while offset < end {
+            let record = match page.data.get(offset..end).map(decode_record) {
+                Some(Ok(v)) if !v.truncated && v.serial_types.iter().any(|serial| !matches!(serial, 0 | 8 | 9)) => v,
+                _ => {
+                    offset += 1;
+                    continue;
+                }
+            };
+            match self.best_layout(page.candidates, &record.serial_types) {
+                Some(layout) => {
+                    let size = record.size;
+                    self.link(Some(layout), found_key(record.serial_types, record.values, page.number, page.base + offset as u64, source));
+                    offset += size.max(1);
+                }
+                None => offset += 1,
+            }
+        }
+        self.keys.len() - found
+    }
+
+    /// Rebuilds the key of a freeblock with the index that fits best
+    fn carve_partial_key(&mut self, page: &KeyPage, start: usize, end: usize, source: IndexKeySource) {
+        let data = match page.data.get(start..end) {
+            Some(v) => v,
+            None => return,
+        };
+        let best = page
+            .candidates
+            .iter()
+            .filter_map(|i| recover_partial_record(data, &self.layouts[*i].key_table).map(|v| (*i, v)))
+            // Keys guessed without a single serial type from the page are noise
+            .filter(|(i, v)| v.guessed < v.serial_types.len() && self.score(*i, &v.serial_types).is_some())
+            .max_by(|a, b| a.1.score.partial_cmp(&b.1.score).unwrap_or(std::cmp::Ordering::Equal));
+        if let Some((layout, record)) = best {
+            let mut key = found_key(record.serial_types, record.values, page.number, page.base + start as u64, source);
+            key.partial_header = true;
+            self.link(Some(layout), key);
+        }
+    }
+
+    /// Index among the candidates whose columns fit a key best. None when no index fits or several fit equally well.
+    fn best_layout(&self, candidates: &[usize], serial_types: &[u64]) -> Option<usize> {
+        let mut best: Option<(usize, f32)> = None;
+        let mut tie = false;
+        for layout in candidates {
+            let score = match self.score(*layout, serial_types) {
+                Some(v) => v,
+                None => continue,
+            };
+            match best {
+                Some((_, best_score)) if score < best_score => {}
+                Some((_, best_score)) if score == best_score => tie = true,
+                _ => {
+                    best = Some((*layout, score));
+                    tie = false;
+                }
+            }
+        }
+        if tie {
+            None
+        } else {
+            best.map(|v| v.0)
+        }
+    }
+
+    /// Score of a serial type signature against the columns of an index. None when it cannot be one of its keys.
+    fn score(&self, layout: usize, serial_types: &[u64]) -> Option<f32> {
+        let layout = &self.layouts[layout];
+        if serial_types.len() != layout.columns.len() {
+            return None;
+        }
+        // Rowids are integers, never NULL
+        if layout.rowid && !matches!(serial_types.last(), Some(1..=6 | 8 | 9)) {
+            return None;
+        }
+        table_score(serial_types, &layout.key_table).filter(|v| *v >= MIN_SCORE)
+    }
+
+    /// Links a key to the index, table and columns of a layout and keeps it
+    fn link(&mut self, layout: Option<usize>, mut key: IndexKey) {
+        if let Some(layout) = layout.map(|i| &self.layouts[i]) {
+            key.index = Some(layout.index.clone());
+            key.table = Some(layout.table.clone());
+            // Live keys written before the schema changed, or truncated ones, may not have every column
+            key.columns = (0..key.values.len()).map(|i| layout.columns.get(i).cloned().flatten()).collect();
+            if layout.rowid && key.values.len() == layout.columns.len() {
+                if let Some(ColumnValue::Integer(rowid)) = key.values.last() {
+                    key.rowid = Some(*rowid);
+                }
+            }
+        }
+        self.keys.push(key);
+    }
+}
+
+impl SqliteDB {
+    /// Keys of the index b-trees of the database file and of the freelist pages that used to be index pages, linked to the table and columns they index.
+    /// For rowid tables `row_exists` tells whether the row is still in the table: the keys of deleted rows keep the values the row had.
+    pub fn carve_index_keys(&self) -> ForensicResult<Vec<IndexKey>> {
+        let schema = self.schema()?;
+        let mut keys = carve_index_keys(&mut self.evidence("")?, &schema)?;
+        let mut rowids: BTreeMap<String, BTreeSet<i64>> = BTreeMap::new();
+        for key in keys.iter_mut() {
+            let (table, rowid) = match (&key.table, key.rowid) {
+                (Some(table), Some(rowid)) => (table, rowid),
+                _ => continue,
+            };
+            if !rowids.contains_key(table) {
+                let definition = match schema.table(table) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                match self.table_rowids(definition)? {
+                    Some(v) => {
+                        rowids.insert(table.clone(), v);
+                    }
+                    // A table with columns named rowid, _rowid_ and oid: whether the row exists is unknown
+                    None => continue,
+                }
+            }
+            key.row_exists = Some(rowids[table].contains(&rowid));
+        }
+        Ok(keys)
+    }
+
+    fn table_rowids(&self, table: &SchemaTable) -> ForensicResult<Option<BTreeSet<i64>>> {
+        let rowid = match table.rowid_name() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let query = format!("SELECT {} FROM {};", rowid, quote_identifier(&table.name));
+        let mut sts = self.prepare(&query)?;
+        let mut rowids = BTreeSet::new();
+        while sts.next()? {
+            if let ColumnValue::Integer(rowid) = sts.read(0)? {
+                rowids.insert(rowid);
+            }
+        }
+        Ok(Some(rowids))
+    }
+}
+
+#[cfg(test)]
+mod test_index_carving {
+    use super::*;
+
+    use forensic_rs::traits::vfs::VirtualFileSystem;
+
+    use crate::workspace::TempWorkspace;
+
+    #[test]
+    fn should_recover_deleted_index_keys() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("index_keys.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection
+            .execute(
+                "PRAGMA page_size=1024; PRAGMA secure_delete=OFF;
+                CREATE TABLE visits (id INTEGER PRIMARY KEY, url TEXT NOT NULL, visit_count INTEGER);
+                CREATE INDEX visits_url ON visits (url);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200) INSERT INTO visits SELECT i, 'https://example.com/visited/' || i, i % 7 FROM n;
+                DELETE FROM visits WHERE id BETWEEN 50 AND 150;",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let db = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let keys = db.carve_index_keys().unwrap();
+        let live: Vec<&IndexKey> = keys.iter().filter(|v| v.source == IndexKeySource::Live).collect();
+        assert_eq!(99, live.len());
+        assert!(live.iter().all(|v| v.row_exists == Some(true)));
+        let deleted = keys
+            .iter()
+            .find(|v| v.source != IndexKeySource::Live && v.row_exists == Some(false) && matches!(v.rowid, Some(50..=150)))
+            .unwrap();
+        assert_eq!(Some("visits_url"), deleted.index.as_deref());
+        assert_eq!(Some("visits"), deleted.table.as_deref());
+        assert_eq!(vec![Some("url".to_string()), Some("id".to_string())], deleted.columns);
+        match &deleted.values[0] {
+            ColumnValue::String(url) => assert_eq!(&format!("https://example.com/visited/{}", deleted.rowid.unwrap()), url),
+            _ => panic!("Invalid value"),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Hashes of the evidence computed when a database is opened and verified again when it is closed
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Hashes of a file in lowercase hexadecimal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvidenceHashes {
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+/// Chain of custody of a file the database was opened from
+#[derive(Debug, Clone)]
+pub struct EvidenceRecord {
+    /// File of the database: "" for the main file, "-wal" or "-journal" for its companions
+    pub file: String,
+    pub size: u64,
+    /// Hashes computed while the file was read at open
+    pub hashes: EvidenceHashes,
+    /// When the file started to be read at open, before it was hashed
+    pub opened: SystemTime,
+    pub closed: Option<SystemTime>,
+    /// Whether the file read again at close has the same hashes. None until closed, or when the file cannot be read again.
+    pub verified: Option<bool>,
+    location: Option<EvidenceLocation>,
+}
+
+/// Where the file is read again at close
+#[derive(Debug, Clone)]
+pub(crate) enum EvidenceLocation {
+    /// File served through the forensic VFS, by suffix
+    Vfs(String),
+    /// Copy in a temp workspace
+    Copy(PathBuf),
+}
+
+impl EvidenceRecord {
+    pub(crate) fn new(file: &str, hasher: EvidenceHasher, location: Option<EvidenceLocation>) -> EvidenceRecord {
+        EvidenceRecord {
+            file: file.to_string(),
+            size: hasher.size,
+            opened: hasher.started,
+            hashes: hasher.finish(),
+            closed: None,
+            verified: None,
+            location,
+        }
+    }
+
+    pub(crate) fn location(&self) -> Option<&EvidenceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Marks the record as closed with the hashes computed at close
+    pub(crate) fn close(&mut self, hasher: Option<EvidenceHasher>) {
+        self.closed = Some(SystemTime::now());
+        if self.location.is_some() {
+            self.verified = Some(match hasher {
+                Some(hasher) => hasher.size == self.size && hasher.finish() == self.hashes,
+                None => false,
+            });
+        }
+    }
+}
+
+/// Computes MD5, SHA-1 and SHA-256 in a single pass
+pub(crate) struct EvidenceHasher {
+    md5: Md5,
+    sha1: Sha1,
+    sha256: Sha256,
+    size: u64,
+    started: SystemTime,
+}
+
+impl EvidenceHasher {
+    pub(crate) fn new() -> EvidenceHasher {
+        EvidenceHasher {
+            md5: Md5::new(),
+            sha1: Sha1::new(),
+            sha256: Sha256::new(),
+            size: 0,
+            started: SystemTime::now(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.md5.update(data);
+        self.sha1.update(data);
+        self.sha256.update(data);
+        self.size += data.len() as u64;
+    }
+
+    pub(crate) fn finish(self) -> EvidenceHashes {
+        EvidenceHashes {
+            md5: to_hex(&self.md5.finalize()),
+            sha1: to_hex(&self.sha1.finalize()),
+            sha256: to_hex(&self.sha256.finalize()),
+        }
+    }
+}
+
+/// Reader that hashes everything read through it, so evidence is hashed while it is copied
+pub(crate) struct HashingReader<'a, R: Read + ?Sized> {
+    inner: &'a mut R,
+    hasher: EvidenceHasher,
+}
+
+impl<'a, R: Read + ?Sized> HashingReader<'a, R> {
+    pub(crate) fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            hasher: EvidenceHasher::new(),
+        }
+    }
+
+    pub(crate) fn into_hasher(self) -> EvidenceHasher {
+        self.hasher
+    }
+}
+
+impl<'a, R: Read + ?Sized> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let readed = self.inner.read(buf)?;
+        self.hasher.update(&buf[0..readed]);
+        Ok(readed)
+    }
+}
+
+/// Hashes a whole file
+pub(crate) fn hash_reader<R: Read + Seek + ?Sized>(reader: &mut R) -> std::io::Result<EvidenceHasher> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut hashing = HashingReader::new(reader);
+    std::io::copy(&mut hashing, &mut std::io::sink())?;
+    Ok(hashing.into_hasher())
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+#[cfg(test)]
+mod test_integrity {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn should_hash_evidence() {
+        let hasher = hash_reader(&mut Cursor::new(b"abc")).unwrap();
+        assert_eq!(3, hasher.size);
+        let hashes = hasher.finish();
+        assert_eq!("900150983cd24fb0d6963f7d28e17f72", hashes.md5);
+        assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", hashes.sha1);
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", hashes.sha256);
+    }
+
+    #[test]
+    fn should_verify_at_close() {
+        let hasher = hash_reader(&mut Cursor::new(b"abc")).unwrap();
+        let hashed = SystemTime::now();
+        let mut record = EvidenceRecord::new("", hasher, Some(EvidenceLocation::Vfs(String::new())));
+        // Opened when the hashing started, not when it finished
+        assert!(record.opened <= hashed);
+        record.close(Some(hash_reader(&mut Cursor::new(b"abc")).unwrap()));
+        assert_eq!(Some(true), record.verified);
+
+        let mut record = EvidenceRecord::new("", hash_reader(&mut Cursor::new(b"abc")).unwrap(), Some(EvidenceLocation::Vfs(String::new())));
+        record.close(Some(hash_reader(&mut Cursor::new(b"abd")).unwrap()));
+        assert_eq!(Some(false), record.verified);
+    }
+}
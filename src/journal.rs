@@ -0,0 +1,222 @@
+//! Parser of SQLite rollback journals. https://www.sqlite.org/fileformat.html#the_rollback_journal
+use std::io::{Read, Seek, SeekFrom};
+
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+
+use crate::page::be_u32;
+
+pub const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+pub const JOURNAL_HEADER_SIZE: u64 = 28;
+
+/// Header of a journal segment. The header is padded to the sector size and followed by the page records.
+#[derive(Debug, Clone)]
+pub struct JournalHeader {
+    /// Offset of the header in the journal
+    pub offset: u64,
+    /// Number of page records. 0xffffffff when it must be computed from the size of the journal.
+    pub record_count: u32,
+    /// Random value that seeds the checksum of every record of the segment
+    pub nonce: u32,
+    /// Size of the database in pages before the transaction started
+    pub initial_page_count: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+/// A page saved in the journal before being modified
+#[derive(Debug, Clone)]
+pub struct JournalRecord {
+    /// Segment of the journal the record belongs to
+    pub segment: usize,
+    pub page_number: u32,
+    /// Offset of the page image in the journal
+    pub offset: u64,
+    pub checksum: u32,
+    pub checksum_valid: bool,
+}
+
+/// A header and the page records that follow it
+#[derive(Debug, Clone)]
+pub struct JournalSegment {
+    pub header: JournalHeader,
+    pub records: Vec<JournalRecord>,
+}
+
+/// A parsed rollback journal
+#[derive(Debug, Clone)]
+pub struct JournalFile {
+    pub segments: Vec<JournalSegment>,
+}
+
+impl JournalFile {
+    /// Parses every segment of the journal. `db_page_size` is used when the header does not store the page size.
+    pub fn parse<R: Read + Seek + ?Sized>(reader: &mut R, db_page_size: u32) -> ForensicResult<JournalFile> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while let Some(header) = read_header(reader, offset, db_page_size)? {
+            let sector_size = (header.sector_size as u64).max(JOURNAL_HEADER_SIZE);
+            let record_size = header.page_size as u64 + 8;
+            let start = offset + sector_size;
+            let available = size.saturating_sub(start) / record_size;
+            // A zero count is left by journals that were never synced, the records are there anyway
+            let count = if header.record_count == u32::MAX || header.record_count == 0 {
+                available
+            } else {
+                (header.record_count as u64).min(available)
+            };
+            let mut records = Vec::with_capacity(count as usize);
+            let mut page = vec![0u8; header.page_size as usize];
+            let mut number = [0u8; 4];
+            for i in 0..count {
+                let record_offset = start + i * record_size;
+                reader.seek(SeekFrom::Start(record_offset))?;
+                reader.read_exact(&mut number)?;
+                reader.read_exact(&mut page)?;
+                let mut checksum = [0u8; 4];
+                reader.read_exact(&mut checksum)?;
+                let checksum = be_u32(&checksum);
+                records.push(JournalRecord {
+                    segment: segments.len(),
+                    page_number: be_u32(&number),
+                    offset: record_offset + 4,
+                    checksum,
+                    checksum_valid: journal_checksum(header.nonce, &page) == checksum,
+                });
+            }
+            // The next segment starts at the first sector boundary after the records
+            let end = start + count * record_size;
+            let next = end.div_ceil(sector_size) * sector_size;
+            segments.push(JournalSegment { header, records });
+            if next <= offset || next >= size {
+                break;
+            }
+            offset = next;
+        }
+        if segments.is_empty() {
+            return Err(ForensicError::Other("Not a SQLite rollback journal".into()));
+        }
+        Ok(JournalFile { segments })
+    }
+
+    /// Page records of every segment in journal order
+    pub fn records(&self) -> impl Iterator<Item = &JournalRecord> {
+        self.segments.iter().flat_map(|v| v.records.iter())
+    }
+
+    /// Page size of the journaled pages
+    pub fn page_size(&self) -> u32 {
+        self.segments[0].header.page_size
+    }
+
+    /// Size of the database in pages after a rollback
+    pub fn initial_page_count(&self) -> u32 {
+        self.segments[0].header.initial_page_count
+    }
+
+    /// Records SQLite would play back on rollback: every record until the first one with a bad checksum, without the pages beyond the initial size of the database.
+    pub fn rollback_records(&self) -> Vec<&JournalRecord> {
+        let initial = self.initial_page_count();
+        self.records()
+            .take_while(|v| v.checksum_valid)
+            .filter(|v| v.page_number != 0 && v.page_number <= initial)
+            .collect()
+    }
+
+    /// Reads the page image stored in a record
+    pub fn read_page<R: Read + Seek + ?Sized>(&self, reader: &mut R, record: &JournalRecord) -> ForensicResult<Vec<u8>> {
+        let mut page = vec![0u8; self.segments[record.segment].header.page_size as usize];
+        reader.seek(SeekFrom::Start(record.offset))?;
+        reader.read_exact(&mut page)?;
+        Ok(page)
+    }
+}
+
+fn read_header<R: Read + Seek + ?Sized>(reader: &mut R, offset: u64, db_page_size: u32) -> ForensicResult<Option<JournalHeader>> {
+    let mut buffer = [0u8; JOURNAL_HEADER_SIZE as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    if reader.read_exact(&mut buffer).is_err() || buffer[0..8] != JOURNAL_MAGIC {
+        return Ok(None);
+    }
+    let page_size = match be_u32(&buffer[24..28]) {
+        0 => db_page_size,
+        v => v,
+    };
+    if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+        return Err(ForensicError::Other(format!("Invalid journal page size {}", page_size)));
+    }
+    Ok(Some(JournalHeader {
+        offset,
+        record_count: be_u32(&buffer[8..12]),
+        nonce: be_u32(&buffer[12..16]),
+        initial_page_count: be_u32(&buffer[16..20]),
+        sector_size: be_u32(&buffer[20..24]),
+        page_size,
+    }))
+}
+
+/// Checksum of a page record: the nonce plus every 200th byte of the page, from the end
+pub fn journal_checksum(nonce: u32, page: &[u8]) -> u32 {
+    let mut checksum = nonce;
+    let mut i = page.len() as isize - 200;
+    while i > 0 {
+        checksum = checksum.wrapping_add(page[i as usize] as u32);
+        i -= 200;
+    }
+    checksum
+}
+
+#[cfg(test)]
+mod test_journal {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn build_journal(nonce: u32, pages: &[(u32, u8)], corrupt: usize) -> Vec<u8> {
+        let mut journal = Vec::new();
+        journal.extend_from_slice(&JOURNAL_MAGIC);
+        journal.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+        journal.extend_from_slice(&nonce.to_be_bytes());
+        journal.extend_from_slice(&2u32.to_be_bytes());
+        journal.extend_from_slice(&512u32.to_be_bytes());
+        journal.extend_from_slice(&512u32.to_be_bytes());
+        journal.resize(512, 0);
+        for (pos, (number, fill)) in pages.iter().enumerate() {
+            let page = vec![*fill; 512];
+            let mut checksum = journal_checksum(nonce, &page);
+            if pos == corrupt {
+                checksum += 1;
+            }
+            journal.extend_from_slice(&number.to_be_bytes());
+            journal.extend_from_slice(&page);
+            journal.extend_from_slice(&checksum.to_be_bytes());
+        }
+        journal
+    }
+
+    #[test]
+    fn should_parse_journal_records() {
+        let journal = build_journal(7, &[(1, 1), (3, 3), (2, 2)], usize::MAX);
+        let parsed = JournalFile::parse(&mut Cursor::new(&journal), 4096).unwrap();
+        assert_eq!(1, parsed.segments.len());
+        let header = &parsed.segments[0].header;
+        assert_eq!(7, header.nonce);
+        assert_eq!(2, parsed.initial_page_count());
+        assert_eq!(512, parsed.page_size());
+        assert_eq!(vec![1, 3, 2], parsed.records().map(|v| v.page_number).collect::<Vec<u32>>());
+        assert!(parsed.records().all(|v| v.checksum_valid));
+        // Page 3 did not exist before the transaction
+        assert_eq!(vec![1, 2], parsed.rollback_records().iter().map(|v| v.page_number).collect::<Vec<u32>>());
+        let record = parsed.records().nth(2).unwrap();
+        assert_eq!(vec![2u8; 512], parsed.read_page(&mut Cursor::new(&journal), record).unwrap());
+    }
+
+    #[test]
+    fn should_stop_rollback_at_bad_checksum() {
+        let journal = build_journal(7, &[(1, 1), (2, 2)], 1);
+        let parsed = JournalFile::parse(&mut Cursor::new(&journal), 4096).unwrap();
+        assert!(!parsed.records().nth(1).unwrap().checksum_valid);
+        assert_eq!(1, parsed.rollback_records().len());
+        assert!(JournalFile::parse(&mut Cursor::new(vec![0u8; 1024]), 4096).is_err());
+    }
+}
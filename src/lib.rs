@@ -1,55 +1,474 @@
-use std::{io::Write, time::{UNIX_EPOCH, SystemTime, Duration}};
+use std::{io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}};
 
 use forensic_rs::{
     prelude::{ForensicError, ForensicResult},
-    traits::{sql::{ColumnType, ColumnValue, SqlDb, SqlStatement}, vfs::VirtualFile},
+    traits::{sql::{ColumnType, ColumnValue, SqlDb, SqlStatement}, vfs::{VirtualFile, VirtualFileSystem}},
 };
 use sqlite::{Connection, Statement, OpenFlags};
 
+mod collations;
+mod functions;
+mod vfs;
+pub mod bplist;
+pub mod carving;
+pub mod encryption;
+pub mod error;
+pub mod header;
+pub mod index_carving;
+pub mod integrity;
+pub mod journal;
+pub mod matching;
+pub mod page;
+pub mod record;
+pub mod recovered;
+pub mod recovery;
+pub mod schema;
+pub mod sqlcipher;
+pub mod timestamp;
+pub mod vtab;
+pub mod wal;
+pub mod workspace;
+
+use carving::CarvedRecord;
+use collations::SharedSubstitutions;
+use error::{SqliteError, SqliteErrorKind};
+pub use collations::{CollationFallback, CollationSubstitution};
+use header::SqliteHeader;
+use integrity::{hash_reader, EvidenceLocation, EvidenceRecord, HashingReader};
+use journal::JournalFile;
+use matching::MatchedRecord;
+use sqlcipher::{PageCipher, SqlCipherKey, SqlCipherParams};
+use vfs::{EvidenceReader, VfsDatabase};
+use wal::WalFile;
+use workspace::TempWorkspace;
+
 /// SQLite DB that implements the forensic SqlDb trait
+/// Databases opened from VirtualFiles read them through the forensic VFS, only from the thread that opened them: VirtualFiles are not Send, and neither is SqliteDB.
 pub struct SqliteDB {
     conn: Connection,
+    // Must be dropped after the connection, SQLite keeps a pointer to them
+    collations: SharedSubstitutions,
+    // Must be dropped after the connection
+    files: Option<VfsDatabase>,
+    // Copies on disk, deleted after the connection is closed
+    workspace: Option<TempWorkspace>,
+    custody: Vec<EvidenceRecord>,
 }
 
 impl SqliteDB {
+    /// Wraps a connection and registers the forensic SQL functions on it: `chrome_time`, `mac_time`, `filetime`, `unix_ms`, `hexdump`, `b64`, `regexp` and `bplist_json`.
+    /// Collations declared by the schema that SQLite does not know, like `LOCALIZED` or ICU collations, are replaced by fallbacks. See `collation_substitutions`.
+    /// Panics if the functions cannot be registered, see `try_new`.
     pub fn new(conn: Connection) -> SqliteDB {
-        SqliteDB { conn }
+        Self::try_new(conn).expect("Cannot register the forensic SQL functions")
+    }
+    /// Same as `new` but returning the error when the forensic SQL functions cannot be registered
+    pub fn try_new(mut conn: Connection) -> ForensicResult<SqliteDB> {
+        // Errors keep the extended result code, like SQLITE_IOERR_SHORT_READ instead of SQLITE_IOERR
+        unsafe { sqlite3_sys::sqlite3_extended_result_codes(conn.as_raw(), 1) };
+        functions::register(&mut conn)?;
+        let collations = collations::register_fallbacks(&mut conn);
+        let mut db = SqliteDB { conn, collations, files: None, workspace: None, custody: Vec::new() };
+        db.substitute_schema_collations();
+        Ok(db)
     }
     /// Create an empty in-memmory DB
     pub fn empty() -> SqliteDB {
-        SqliteDB { conn: sqlite::open(":memory:").unwrap() }
-    }
-    /// Create a SQLite DB from a virtual file in ReadOnly and Serialized mode. The implementation copies the entire SQLite into a temp folder and opens it.
-    /// The alternative is create a custom VFS for SQLite. https://www.sqlite.org/vfs.html
-    pub fn virtual_file(mut file: Box<dyn VirtualFile>) -> ForensicResult<SqliteDB> {
-        // We need to copy the full file from the virtual filesystem into a temp file in the machine
-        let mut buffer = vec![0; 4096];
-        let millis = match SystemTime::now()
-            .duration_since(UNIX_EPOCH) {
-                Ok(v) => v,
-                Err(_) => Duration::from_secs(1)
-            }.subsec_nanos();
-        let file_name =format!("forensic_sqlite.{}.db", millis);
-        let temp_path = std::env::temp_dir().join(file_name);
-        let mut tmp_file = std::fs::File::create(&temp_path)?;
-        loop {
-            let readed = file.read(&mut buffer)?;
-            if readed == 0 {
-                break;
+        SqliteDB::new(sqlite::open(":memory:").unwrap())
+    }
+    /// Create a SQLite DB from a virtual file in ReadOnly and Serialized mode. The file is served to SQLite in place through the forensic VFS, nothing is copied to disk.
+    pub fn virtual_file(file: Box<dyn VirtualFile>) -> ForensicResult<SqliteDB> {
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", file)?;
+        Self::open_vfs(files, true)
+    }
+    /// Create a SQLite DB from a database of a virtual filesystem together with its -wal and -journal companions, so uncheckpointed WAL frames and hot journals are not lost.
+    /// SQLite replays them over a copy-on-write overlay kept in memory: the evidence is never modified and the connection is query only.
+    /// The -shm is not needed, the wal-index is rebuilt from the -wal.
+    pub fn virtual_fs(fs: &mut dyn VirtualFileSystem, path: &Path) -> ForensicResult<SqliteDB> {
+        let mut files = VfsDatabase::new(true)?;
+        files.register("", fs.open(path)?)?;
+        for suffix in COMPANION_SUFFIXES {
+            // A companion that cannot be opened is treated as absent
+            if let Ok(file) = fs.open(&companion_path(path, suffix)) {
+                files.register(suffix, file)?;
             }
-            tmp_file.write_all(&buffer[0..readed])?;
         }
-        let connection = match sqlite::Connection::open_with_flags(&temp_path.to_string_lossy()[..], OpenFlags::new().set_read_only().set_full_mutex()) {
-            Ok(v) => v,
-            Err(e) => return Err(ForensicError::Other(e.to_string()))
+        Self::open_vfs(files, false)
+    }
+    /// Create a SQLite DB from a virtual file with the `immutable=1` URI semantics: SQLite takes no locks, creates no -shm and never looks for a -wal or -journal.
+    /// The file is read exactly as it is. A WAL-mode database shows its last checkpointed state and hot journals are not rolled back.
+    /// Returns `ForensicError::Missing` when the file cannot be consistent without its -wal or -journal: it is shorter than the page count of its header or ends in a partial page.
+    pub fn immutable_file(file: Box<dyn VirtualFile>) -> ForensicResult<SqliteDB> {
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", file)?;
+        if let Some(mut reader) = files.evidence("") {
+            if needs_companion(&mut reader)? {
+                return Err(ForensicError::Missing);
+            }
+        }
+        let uri = files.immutable_uri();
+        Self::open_vfs_uri(files, &uri, true)
+    }
+    /// Create a SQLite DB from a SQLCipher database with its key, a passphrase or a raw key, and the cipher settings it was created with.
+    /// The pages are decrypted in memory as SQLite reads them, nothing decrypted is written to disk. Hashes and carving see the file as it is on disk and decrypted respectively.
+    /// The database is opened immutable: the -wal and -journal are encrypted with the same key and are not read.
+    pub fn sqlcipher_file(file: Box<dyn VirtualFile>, key: &SqlCipherKey, params: &SqlCipherParams) -> ForensicResult<SqliteDB> {
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", file)?;
+        let mut first_page = vec![0u8; params.page_size as usize];
+        if let Some(mut reader) = files.evidence("") {
+            reader.read_exact(&mut first_page)?;
+        }
+        let cipher = PageCipher::new(key, params, &first_page)?;
+        // A wrong key or wrong settings are reported here instead of at the first query
+        cipher.decrypt_page(1, &mut first_page)?;
+        match SqliteHeader::parse(&first_page) {
+            Ok(header) if header.page_size == params.page_size && header.max_payload_fraction == 64 && header.min_payload_fraction == 32 => {}
+            _ => return Err(ForensicError::Other("Cannot decrypt the database: wrong key or cipher settings".into())),
+        }
+        files.set_cipher("", cipher)?;
+        let uri = files.immutable_uri();
+        Self::open_vfs_uri(files, &uri, true)
+    }
+    /// Create a SQLite DB with the state of the database as of a commit frame of its WAL, older salt generations included.
+    /// The frames of that generation up to the commit are applied in memory over the main file. Pages not present in those frames come from the main file, which may already hold newer checkpointed data.
+    pub fn wal_snapshot(file: Box<dyn VirtualFile>, mut wal: Box<dyn VirtualFile>, commit_frame: usize) -> ForensicResult<SqliteDB> {
+        let wal_hasher = hash_reader(wal.as_mut())?;
+        let parsed = WalFile::parse(wal.as_mut())?;
+        let frames = parsed.frames_until(commit_frame)?;
+        let page_size = parsed.header.page_size as u64;
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", file)?;
+        for frame in &frames {
+            if frame.page_number == 0 {
+                continue;
+            }
+            let page = parsed.read_page(wal.as_mut(), frame)?;
+            files.patch("", (frame.page_number as u64 - 1) * page_size, &page)?;
+        }
+        if let Some(commit) = frames.last() {
+            files.truncate("", commit.commit_size as u64 * page_size)?;
+        }
+        let mut db = Self::open_vfs(files, true)?;
+        // The WAL is not kept open, it cannot be verified at close
+        db.custody.push(EvidenceRecord::new("-wal", wal_hasher, None));
+        Ok(db)
+    }
+    /// Create a SQLite DB with the state of the database as it would be after rolling back its journal.
+    /// The page images of the journal are applied in memory over the main file as SQLite would do it: until the first record with a bad checksum, and truncating the database to its size before the transaction.
+    pub fn journal_rollback(file: Box<dyn VirtualFile>, mut journal: Box<dyn VirtualFile>) -> ForensicResult<SqliteDB> {
+        let journal_hasher = hash_reader(journal.as_mut())?;
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", file)?;
+        let db_page_size = match files.evidence("").map(|mut v| SqliteHeader::read(&mut v)) {
+            Some(Ok(header)) if header.page_size_valid() => header.page_size,
+            _ => 4096,
         };
-        Ok(SqliteDB::new(connection))
+        let parsed = JournalFile::parse(journal.as_mut(), db_page_size)?;
+        let page_size = parsed.page_size() as u64;
+        for record in parsed.rollback_records() {
+            let page = parsed.read_page(journal.as_mut(), record)?;
+            files.patch("", (record.page_number as u64 - 1) * page_size, &page)?;
+        }
+        files.truncate("", parsed.initial_page_count() as u64 * page_size)?;
+        let mut db = Self::open_vfs(files, true)?;
+        // The journal is not kept open, it cannot be verified at close
+        db.custody.push(EvidenceRecord::new("-journal", journal_hasher, None));
+        Ok(db)
+    }
+    /// Opens a database of a virtual filesystem twice to compare the two states of an interrupted transaction: the database as it is on disk and as it would be after rolling back its -journal.
+    pub fn journal_states(fs: &mut dyn VirtualFileSystem, path: &Path) -> ForensicResult<(SqliteDB, SqliteDB)> {
+        let on_disk = Self::virtual_file(fs.open(path)?)?;
+        let rolled_back = Self::journal_rollback(fs.open(path)?, fs.open(&companion_path(path, "-journal"))?)?;
+        Ok((on_disk, rolled_back))
+    }
+    /// Prepares a statement that accepts bound parameters
+    pub fn statement<'a>(&'a self, statement: &str) -> ForensicResult<SqliteStatement<'a>> {
+        Ok(self.try_statement(statement)?)
+    }
+    /// Same as `statement` but returning the typed SQLite error
+    pub fn try_statement<'a>(&'a self, statement: &str) -> Result<SqliteStatement<'a>, SqliteError> {
+        SqliteStatement::try_new(&self.conn, statement).map_err(|e| self.classify_error(e))
+    }
+    /// Header of the database file, read from the evidence without SQL
+    pub fn header(&self) -> ForensicResult<SqliteHeader> {
+        SqliteHeader::read(&mut self.evidence("")?)
+    }
+    /// Carves deleted records from the free space of the database file: freelist pages, freeblocks and unallocated space inside b-tree pages.
+    /// Use `carving::CarvedStatement` to read them as a SqlStatement.
+    pub fn carve_deleted(&self) -> ForensicResult<Vec<CarvedRecord>> {
+        let mut reader = self.evidence("")?;
+        // Carving does not need the schema, it only helps with overwritten headers
+        let tables = self.schema_tables().unwrap_or_default();
+        carving::carve_with_schema(&mut reader, &tables)
+    }
+    /// Carves deleted records and matches each one against the tables of the schema by its serial type signature.
+    pub fn carve_deleted_tables(&self) -> ForensicResult<Vec<MatchedRecord>> {
+        let mut reader = self.evidence("")?;
+        let tables = self.schema_tables()?;
+        Ok(carving::carve_with_schema(&mut reader, &tables)?
+            .into_iter()
+            .map(|record| MatchedRecord {
+                table: matching::match_record(&record.serial_types, &tables),
+                record,
+            })
+            .collect())
+    }
+    /// SQLite reports encrypted files as not being a database: the evidence tells them apart
+    fn classify_error(&self, mut error: SqliteError) -> SqliteError {
+        if error.kind == SqliteErrorKind::NotADatabase {
+            let encrypted = self
+                .evidence("")
+                .and_then(|mut v| encryption::classify(&mut v))
+                .map(|v| v.encrypted())
+                .unwrap_or(false);
+            if encrypted {
+                error.kind = SqliteErrorKind::Encrypted;
+            }
+        }
+        error
+    }
+    /// Reader of a file the database was opened from, without the changes made by SQLite. Encrypted databases are decrypted.
+    fn evidence(&self, suffix: &str) -> ForensicResult<EvidenceReader> {
+        match self.files.as_ref().and_then(|v| v.plaintext(suffix)) {
+            Some(v) => Ok(v),
+            None => Err(ForensicError::Other(format!("The database was not opened from a VirtualFile with a{} file", if suffix.is_empty() { " main" } else { suffix }))),
+        }
+    }
+    /// Opens the files registered in the forensic VFS. Writable connections only write into the in-memory overlay and are set as query only.
+    fn open_vfs(files: VfsDatabase, read_only: bool) -> ForensicResult<SqliteDB> {
+        let uri = files.uri();
+        Self::open_vfs_uri(files, &uri, read_only)
+    }
+    fn open_vfs_uri(files: VfsDatabase, uri: &str, read_only: bool) -> ForensicResult<SqliteDB> {
+        let mut custody = Vec::new();
+        for suffix in std::iter::once("").chain(COMPANION_SUFFIXES) {
+            if let Some(mut reader) = files.evidence(suffix) {
+                let hasher = hash_reader(&mut reader)?;
+                custody.push(EvidenceRecord::new(suffix, hasher, Some(EvidenceLocation::Vfs(suffix.to_string()))));
+            }
+        }
+        let flags = if read_only { OpenFlags::new().set_read_only() } else { OpenFlags::new().set_read_write() };
+        let connection = sqlite::Connection::open_with_flags(uri, flags.set_full_mutex().set_uri()).map_err(SqliteError::from)?;
+        if !read_only {
+            connection
+                .execute("PRAGMA query_only=1;")
+                .map_err(|e| SqliteError::new(e, Some("PRAGMA query_only=1;")))?;
+        }
+        let mut db = SqliteDB::try_new(connection)?;
+        db.files = Some(files);
+        db.custody = custody;
+        Ok(db)
+    }
+    /// Create a SQLite DB from a virtual file in ReadOnly and Serialized mode. The implementation copies the entire SQLite into a temp workspace and opens it.
+    /// Useful when the virtual file is slow to seek, like files inside compressed containers. The copy is deleted when the SqliteDB is dropped.
+    pub fn virtual_file_copy(file: Box<dyn VirtualFile>) -> ForensicResult<SqliteDB> {
+        Self::virtual_file_copy_in(file, TempWorkspace::new()?)
+    }
+    /// Same as `virtual_file_copy` but copying into the given workspace
+    pub fn virtual_file_copy_in(mut file: Box<dyn VirtualFile>, workspace: TempWorkspace) -> ForensicResult<SqliteDB> {
+        let mut reader = HashingReader::new(file.as_mut());
+        let temp_path = workspace.copy_file(COPY_NAME, &mut reader)?;
+        let record = EvidenceRecord::new("", reader.into_hasher(), Some(EvidenceLocation::Copy(temp_path.clone())));
+        Self::open_copy(&temp_path, workspace, true, vec![record])
+    }
+    /// Copies a database of a virtual filesystem together with its -wal and -journal into the workspace and opens it.
+    /// SQLite replays the companions over the copy, the connection is query only. The copies change with the replay,
+    /// so the files of the virtual filesystem are kept open and it is them that are hashed again at close.
+    pub fn virtual_fs_copy(fs: &mut dyn VirtualFileSystem, path: &Path, workspace: TempWorkspace) -> ForensicResult<SqliteDB> {
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", fs.open(path)?)?;
+        for suffix in COMPANION_SUFFIXES {
+            if let Ok(file) = fs.open(&companion_path(path, suffix)) {
+                files.register(suffix, file)?;
+            }
+        }
+        let mut custody = Vec::new();
+        let mut temp_path = None;
+        for suffix in std::iter::once("").chain(COMPANION_SUFFIXES) {
+            if let Some(mut evidence) = files.evidence(suffix) {
+                let mut reader = HashingReader::new(&mut evidence);
+                let copy = workspace.copy_file(&format!("{}{}", COPY_NAME, suffix), &mut reader)?;
+                custody.push(EvidenceRecord::new(suffix, reader.into_hasher(), Some(EvidenceLocation::Vfs(suffix.to_string()))));
+                temp_path.get_or_insert(copy);
+            }
+        }
+        let temp_path = temp_path.ok_or(ForensicError::Missing)?;
+        let mut db = Self::open_copy(&temp_path, workspace, false, custody)?;
+        db.files = Some(files);
+        Ok(db)
+    }
+    fn open_copy(path: &Path, workspace: TempWorkspace, read_only: bool, custody: Vec<EvidenceRecord>) -> ForensicResult<SqliteDB> {
+        let flags = if read_only { OpenFlags::new().set_read_only() } else { OpenFlags::new().set_read_write() };
+        let connection = sqlite::Connection::open_with_flags(&path.to_string_lossy()[..], flags.set_full_mutex()).map_err(SqliteError::from)?;
+        if !read_only {
+            connection
+                .execute("PRAGMA query_only=1;")
+                .map_err(|e| SqliteError::new(e, Some("PRAGMA query_only=1;")))?;
+        }
+        let mut db = SqliteDB::try_new(connection)?;
+        db.workspace = Some(workspace);
+        db.custody = custody;
+        Ok(db)
+    }
+    /// Collations of the database that were replaced by a fallback, either found in the schema when opening or requested later by a query.
+    /// Results that sort or compare on them may differ from what the application saw.
+    pub fn collation_substitutions(&self) -> Vec<CollationSubstitution> {
+        self.collations.lock().map(|v| v.clone()).unwrap_or_else(|e| e.into_inner().clone())
+    }
+    /// Registers the fallbacks of the collations named in the schema, so they are reported before any query needs them
+    fn substitute_schema_collations(&mut self) {
+        let mut names = Vec::new();
+        // An unreadable schema is reported by the queries themselves
+        if let Ok(mut sts) = self.prepare("SELECT sql FROM sqlite_schema WHERE sql IS NOT NULL;") {
+            while let Ok(true) = sts.next() {
+                let sql: String = match sts.read(0).and_then(|v| v.try_into()) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                names.extend(collations::schema_collations(&sql));
+            }
+        }
+        let db = self.conn.as_raw();
+        for name in names {
+            collations::substitute(db, &self.collations, &name);
+        }
+    }
+    /// Hashes, size and open time of every file the database was opened from
+    pub fn evidence_records(&self) -> &[EvidenceRecord] {
+        &self.custody
+    }
+    /// Closes the connection and hashes the evidence again, through the VFS or from the temp copy, to verify it was not modified while open.
+    /// Returns the chain of custody with the close times and the result of the verification.
+    pub fn close(self) -> Vec<EvidenceRecord> {
+        let SqliteDB { conn, collations, files, workspace, mut custody } = self;
+        drop(conn);
+        drop(collations);
+        for record in custody.iter_mut() {
+            let hasher = match record.location() {
+                Some(EvidenceLocation::Vfs(suffix)) => files
+                    .as_ref()
+                    .and_then(|v| v.evidence(suffix))
+                    .and_then(|mut v| hash_reader(&mut v).ok()),
+                Some(EvidenceLocation::Copy(path)) => std::fs::File::open(path)
+                    .ok()
+                    .and_then(|mut v| hash_reader(&mut v).ok()),
+                None => None,
+            };
+            record.close(hasher);
+        }
+        drop(files);
+        drop(workspace);
+        custody
+    }
+}
+
+/// Name of the database copy inside a workspace. SQLite creates its sidecars next to it.
+const COPY_NAME: &str = "evidence.db";
+
+/// Files SQLite keeps next to a database that change its content
+const COMPANION_SUFFIXES: [&str; 2] = ["-wal", "-journal"];
+
+fn companion_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether the main file is missing pages that only its -wal or -journal can explain
+fn needs_companion(reader: &mut EvidenceReader) -> ForensicResult<bool> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    if size == 0 {
+        return Ok(false);
+    }
+    let header = match SqliteHeader::read(reader) {
+        Ok(v) if v.magic_valid && v.page_size_valid() => v,
+        // SQLite reports what is wrong with the file
+        _ => return Ok(false),
+    };
+    let page_size = header.page_size as u64;
+    Ok(size % page_size != 0 || (header.page_count_valid() && size < header.page_count as u64 * page_size))
+}
+
+/// Converts a forensic value into a value SQLite can bind
+pub(crate) fn column_to_value(value: &ColumnValue) -> sqlite::Value {
+    match value {
+        ColumnValue::Null => sqlite::Value::Null,
+        ColumnValue::Integer(v) => sqlite::Value::Integer(*v),
+        ColumnValue::Float(v) => sqlite::Value::Float(*v),
+        ColumnValue::String(v) => sqlite::Value::String(v.clone()),
+        ColumnValue::Binary(v) => sqlite::Value::Binary(v.clone()),
+    }
+}
+
+/// Value that can be bound to a parameter of a SqliteStatement
+pub trait BindValue {
+    fn to_sqlite_value(&self) -> sqlite::Value;
+}
+
+impl BindValue for ColumnValue {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        column_to_value(self)
+    }
+}
+impl BindValue for i64 {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Integer(*self)
+    }
+}
+impl BindValue for i32 {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Integer(*self as i64)
+    }
+}
+impl BindValue for u32 {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Integer(*self as i64)
+    }
+}
+impl BindValue for f64 {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Float(*self)
+    }
+}
+impl BindValue for bool {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Integer(*self as i64)
+    }
+}
+impl BindValue for str {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::String(self.to_string())
+    }
+}
+impl BindValue for String {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::String(self.clone())
+    }
+}
+impl BindValue for [u8] {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Binary(self.to_vec())
+    }
+}
+impl BindValue for Vec<u8> {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        sqlite::Value::Binary(self.clone())
+    }
+}
+impl<T: BindValue> BindValue for Option<T> {
+    fn to_sqlite_value(&self) -> sqlite::Value {
+        match self {
+            Some(v) => v.to_sqlite_value(),
+            None => sqlite::Value::Null,
+        }
     }
 }
 
 impl SqlDb for SqliteDB {
     fn prepare<'a>(&'a self, statement: &'a str) -> ForensicResult<Box<dyn SqlStatement + 'a>> {
-        Ok(Box::new(SqliteStatement::new(&self.conn, statement)?))
+        Ok(Box::new(self.statement(statement)?))
     }
 
     fn from_file(&self, file: Box<dyn VirtualFile>) -> ForensicResult<Box<dyn SqlDb>> {
@@ -77,15 +496,64 @@ impl SqlDb for SqliteDB {
 
 pub struct SqliteStatement<'conn> {
     stmt: Statement<'conn>,
+    sql: String,
 }
 impl<'conn> SqliteStatement<'conn> {
     pub fn new(conn: &'conn Connection, statement: &str) -> ForensicResult<SqliteStatement<'conn>> {
-        Ok(Self {
-            stmt: match conn.prepare(statement) {
-                Ok(st) => st,
-                Err(e) => return Err(ForensicError::Other(e.to_string())),
-            },
-        })
+        Ok(Self::try_new(conn, statement)?)
+    }
+    /// Same as `new` but returning the typed SQLite error
+    pub fn try_new(conn: &'conn Connection, statement: &str) -> Result<SqliteStatement<'conn>, SqliteError> {
+        match conn.prepare(statement) {
+            Ok(stmt) => Ok(Self { stmt, sql: statement.to_string() }),
+            Err(e) => Err(SqliteError::new(e, Some(statement))),
+        }
+    }
+    /// SQL the statement was prepared with
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+    /// Binds a value to a parameter. Parameters are numbered from 1.
+    pub fn bind<V: BindValue + ?Sized>(&mut self, index: usize, value: &V) -> ForensicResult<()> {
+        let result = self.stmt.bind((index, &value.to_sqlite_value()));
+        Ok(result.map_err(|e| self.error(e))?)
+    }
+    /// Binds a value to a named parameter. The name includes its prefix: `:name`, `@name` or `$name`.
+    pub fn bind_by_name<V: BindValue + ?Sized>(&mut self, name: &str, value: &V) -> ForensicResult<()> {
+        let result = self.stmt.bind((name, &value.to_sqlite_value()));
+        Ok(result.map_err(|e| self.error(e))?)
+    }
+    /// Index of a named parameter
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.stmt.parameter_index(name).ok().flatten()
+    }
+    /// Rewinds the statement so it can run again. Bound values are kept until bound again.
+    pub fn reset(&mut self) -> ForensicResult<()> {
+        let result = self.stmt.reset();
+        Ok(result.map_err(|e| self.error(e))?)
+    }
+    /// Same as `SqlStatement::next` but returning the typed SQLite error
+    pub fn try_next(&mut self) -> Result<bool, SqliteError> {
+        match self.stmt.next() {
+            Ok(sqlite::State::Row) => Ok(true),
+            Ok(sqlite::State::Done) => Ok(false),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+    /// Same as `SqlStatement::read` but returning the typed SQLite error
+    pub fn try_read(&self, i: usize) -> Result<ColumnValue, SqliteError> {
+        let column_type = self.stmt.column_type(i).map_err(|e| self.error(e))?;
+        let value = match column_type {
+            sqlite::Type::Binary => self.stmt.read(i).map(ColumnValue::Binary),
+            sqlite::Type::Float => self.stmt.read(i).map(ColumnValue::Float),
+            sqlite::Type::Integer => self.stmt.read(i).map(ColumnValue::Integer),
+            sqlite::Type::String => self.stmt.read(i).map(ColumnValue::String),
+            sqlite::Type::Null => Ok(ColumnValue::Null),
+        };
+        value.map_err(|e| self.error(e))
+    }
+    fn error(&self, error: sqlite::Error) -> SqliteError {
+        SqliteError::new(error, Some(&self.sql))
     }
 }
 
@@ -95,10 +563,7 @@ impl<'conn> SqlStatement for SqliteStatement<'conn> {
     }
 
     fn column_name(&self, i: usize) -> Option<&str> {
-        match self.stmt.column_name(i) {
-            Ok(v) => Some(v),
-            Err(_) => None,
-        }
+        self.stmt.column_name(i).ok()
     }
 
     fn column_names(&self) -> Vec<&str> {
@@ -120,39 +585,11 @@ impl<'conn> SqlStatement for SqliteStatement<'conn> {
     }
 
     fn next(&mut self) -> ForensicResult<bool> {
-        match self.stmt.next() {
-            Ok(v) => Ok(match v {
-                sqlite::State::Row => true,
-                sqlite::State::Done => false,
-            }),
-            Err(e) => Err(ForensicError::Other(e.to_string())),
-        }
+        Ok(self.try_next()?)
     }
 
     fn read(&self, i: usize) -> ForensicResult<ColumnValue> {
-        let column_type = match self.stmt.column_type(i) {
-            Ok(v) => v,
-            Err(e) => return Err(ForensicError::Other(e.to_string())),
-        };
-        match column_type {
-            sqlite::Type::Binary => match self.stmt.read(i) {
-                Ok(v) => Ok(ColumnValue::Binary(v)),
-                Err(e) => Err(ForensicError::Other(e.to_string())),
-            },
-            sqlite::Type::Float => match self.stmt.read(i) {
-                Ok(v) => Ok(ColumnValue::Float(v)),
-                Err(e) => Err(ForensicError::Other(e.to_string())),
-            },
-            sqlite::Type::Integer => match self.stmt.read(i) {
-                Ok(v) => Ok(ColumnValue::Integer(v)),
-                Err(e) => Err(ForensicError::Other(e.to_string())),
-            },
-            sqlite::Type::String => match self.stmt.read(i) {
-                Ok(v) => Ok(ColumnValue::String(v)),
-                Err(e) => Err(ForensicError::Other(e.to_string())),
-            },
-            sqlite::Type::Null => Ok(ColumnValue::Null),
-        }
+        Ok(self.try_read(i)?)
     }
 }
 
@@ -169,14 +606,8 @@ mod test_db_implementation {
         let connection = sqlite::open(":memory:").unwrap();
         prepare_db(connection)
     }
-    fn initialize_file_db() -> Connection {
-        let millis = match SystemTime::now()
-            .duration_since(UNIX_EPOCH) {
-                Ok(v) => v,
-                Err(_) => Duration::from_secs(1)
-            }.subsec_nanos();
-        let file_name =format!("forensic_sqlite.{}.db", millis);
-        let temp_path = std::env::temp_dir().join(file_name);
+    fn initialize_file_db(test_dir: &TempWorkspace) -> Connection {
+        let temp_path = test_dir.path().join("machine.db");
         let connection = sqlite::open(&temp_path).unwrap();
         prepare_db(connection)
     }
@@ -205,9 +636,35 @@ mod test_db_implementation {
         test_database_content(statement.as_mut()).expect("Should not return error");
     }
 
+    #[test]
+    fn sqlite_bind_parameters() {
+        let conn = initialize_mem_db();
+        let w_conn = prepare_wrapper(conn);
+        let mut statement = w_conn.statement("SELECT age FROM users WHERE name = :name;").unwrap();
+        for (name, age) in [("Alice", Some(42)), ("Bob", Some(69)), ("O'Brien", None)] {
+            statement.bind_by_name(":name", name).unwrap();
+            assert_eq!(age.is_some(), statement.next().unwrap());
+            if let Some(age) = age {
+                let value: i64 = statement.read(0).unwrap().try_into().unwrap();
+                assert_eq!(age, value);
+            }
+            statement.reset().unwrap();
+        }
+        assert_eq!(Some(1), statement.parameter_index(":name"));
+        assert!(statement.bind_by_name(":missing", &1i64).is_err());
+
+        let mut statement = w_conn.statement("SELECT count(*) FROM users WHERE age > ? AND name != ?;").unwrap();
+        statement.bind(1, &ColumnValue::Integer(50)).unwrap();
+        statement.bind(2, &Some("Alice".to_string())).unwrap();
+        assert!(statement.next().unwrap());
+        let count: i64 = statement.read(0).unwrap().try_into().unwrap();
+        assert_eq!(1, count);
+    }
+
     #[test]
     fn sqlite_from_machine_file() {
-        let conn = initialize_file_db();
+        let test_dir = TempWorkspace::new().unwrap();
+        let conn = initialize_file_db(&test_dir);
         let w_conn = prepare_wrapper(conn);
         let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
         test_database_content(statement.as_mut()).expect("Should not return error");
@@ -215,13 +672,8 @@ mod test_db_implementation {
 
     #[test]
     fn sqlite_from_virtual_file() {
-        let millis = match SystemTime::now()
-            .duration_since(UNIX_EPOCH) {
-                Ok(v) => v,
-                Err(_) => Duration::from_secs(1)
-            }.as_millis();
-        let file_name =format!("forensic_sqlite.{}.db", millis);
-        let temp_path = std::env::temp_dir().join(file_name);
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("virtual.db");
         let connection = sqlite::open(&temp_path).unwrap();
         prepare_db(connection);
 
@@ -232,7 +684,544 @@ mod test_db_implementation {
         test_database_content(statement.as_mut()).expect("Should not return error");
     }
 
-    fn test_database_content<'a>(statement: &mut dyn SqlStatement) -> ForensicResult<()> {
+    #[test]
+    fn sqlite_from_virtual_file_copy() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("copy.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        prepare_db(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let file = fs.open(&temp_path).unwrap();
+        let mut workspace = TempWorkspace::new().unwrap();
+        workspace.set_overwrite_on_drop(true);
+        let workspace_path = workspace.path().to_path_buf();
+        let w_conn = SqliteDB::virtual_file_copy_in(file, workspace).unwrap();
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+        drop(statement);
+        assert!(workspace_path.join(COPY_NAME).exists());
+        let custody = w_conn.close();
+        assert_eq!(Some(true), custody[0].verified);
+        assert!(!workspace_path.exists());
+    }
+
+    #[test]
+    fn sqlite_verify_virtual_fs_copy() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("fs_copy.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        prepare_db(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_fs_copy(&mut fs, &temp_path, TempWorkspace::new().unwrap()).unwrap();
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+        drop(statement);
+        let custody = w_conn.close();
+        assert_eq!(1, custody.len());
+        assert_eq!(Some(true), custody[0].verified);
+    }
+
+    #[test]
+    fn sqlite_evidence_hashes() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("hashes.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        prepare_db(connection);
+        let expected = integrity::hash_reader(&mut std::fs::File::open(&temp_path).unwrap()).unwrap().finish();
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        assert_eq!(1, w_conn.evidence_records().len());
+        assert_eq!(expected, w_conn.evidence_records()[0].hashes);
+        assert_eq!(std::fs::metadata(&temp_path).unwrap().len(), w_conn.evidence_records()[0].size);
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+        drop(statement);
+        let custody = w_conn.close();
+        assert_eq!(Some(true), custody[0].verified);
+        assert!(custody[0].closed.unwrap() >= custody[0].opened);
+    }
+
+    #[test]
+    fn sqlite_wal_mode_from_virtual_file() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("wal_mode.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection.execute("PRAGMA journal_mode=WAL;").unwrap();
+        // Closing the connection checkpoints and removes the -wal, the header keeps the WAL mode
+        drop(prepare_db(connection));
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let file = fs.open(&temp_path).unwrap();
+        let w_conn = SqliteDB::virtual_file(file).unwrap();
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+    }
+
+    #[test]
+    fn sqlite_immutable_from_virtual_file() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("immutable.db");
+        let copy_path = test_dir.path().join("immutable.copy.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection.execute("PRAGMA journal_mode=WAL;").unwrap();
+        drop(prepare_db(connection));
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::immutable_file(fs.open(&temp_path).unwrap()).unwrap();
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+
+        // A header that counts more pages than the file holds
+        let mut data = std::fs::read(&temp_path).unwrap();
+        let page_count = u32::from_be_bytes([data[28], data[29], data[30], data[31]]) + 1;
+        data[28..32].copy_from_slice(&page_count.to_be_bytes());
+        std::fs::write(&copy_path, &data).unwrap();
+        match SqliteDB::immutable_file(fs.open(&copy_path).unwrap()) {
+            Err(ForensicError::Missing) => {}
+            _ => panic!("Should require the -wal"),
+        }
+    }
+
+    #[test]
+    fn sqlite_journal_states() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("journal.db");
+        let copy_path = test_dir.path().join("journal.copy.db");
+        let connection = prepare_db(sqlite::open(&temp_path).unwrap());
+        // Without syncs the journal header is complete as soon as the first page is journaled
+        connection.execute("PRAGMA synchronous=OFF; BEGIN; UPDATE users SET age = 1;").unwrap();
+        std::fs::copy(companion_path(&temp_path, "-journal"), companion_path(&copy_path, "-journal")).unwrap();
+        connection.execute("COMMIT;").unwrap();
+        drop(connection);
+        std::fs::copy(&temp_path, &copy_path).unwrap();
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut journal = fs.open(&companion_path(&copy_path, "-journal")).unwrap();
+        let parsed = JournalFile::parse(journal.as_mut(), 4096).unwrap();
+        assert!(parsed.records().any(|v| v.page_number == 2 && v.checksum_valid));
+
+        let (on_disk, rolled_back) = SqliteDB::journal_states(&mut fs, &copy_path).unwrap();
+        let mut statement = on_disk.prepare("SELECT count(*) FROM users WHERE age = 1;").unwrap();
+        assert!(statement.next().unwrap());
+        let updated: i64 = statement.read(0).unwrap().try_into().unwrap();
+        assert_eq!(2, updated);
+        let mut statement = rolled_back.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+    }
+
+    #[test]
+    fn sqlite_with_companions_from_virtual_fs() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("companions.db");
+        let copy_path = test_dir.path().join("companions.copy.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection.execute("PRAGMA journal_mode=WAL; PRAGMA wal_autocheckpoint=0;").unwrap();
+        let connection = prepare_db(connection);
+        // Acquire the files while the data only lives in the -wal
+        std::fs::copy(&temp_path, &copy_path).unwrap();
+        std::fs::copy(companion_path(&temp_path, "-wal"), companion_path(&copy_path, "-wal")).unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let only_main = SqliteDB::virtual_file(fs.open(&copy_path).unwrap()).unwrap();
+        assert!(only_main.prepare("SELECT name, age FROM users;").is_err());
+
+        let w_conn = SqliteDB::virtual_fs(&mut fs, &copy_path).unwrap();
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+        drop(statement);
+        drop(w_conn);
+        // The evidence is left as it was
+        assert!(companion_path(&copy_path, "-wal").exists());
+    }
+
+    #[test]
+    fn sqlite_wal_snapshots() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("snapshots.db");
+        let copy_path = test_dir.path().join("snapshots.copy.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection.execute("PRAGMA journal_mode=WAL; PRAGMA wal_autocheckpoint=0;").unwrap();
+        let connection = prepare_db(connection);
+        std::fs::copy(&temp_path, &copy_path).unwrap();
+        std::fs::copy(companion_path(&temp_path, "-wal"), companion_path(&copy_path, "-wal")).unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut wal = fs.open(&companion_path(&copy_path, "-wal")).unwrap();
+        let commits: Vec<usize> = WalFile::parse(wal.as_mut()).unwrap().commits().map(|v| v.index).collect();
+        // CREATE TABLE and one transaction per INSERT
+        assert_eq!(3, commits.len());
+
+        let only_alice = SqliteDB::wal_snapshot(fs.open(&copy_path).unwrap(), fs.open(&companion_path(&copy_path, "-wal")).unwrap(), commits[1]).unwrap();
+        let mut statement = only_alice.prepare("SELECT name FROM users;").unwrap();
+        assert!(statement.next().unwrap());
+        let name: String = statement.read(0).unwrap().try_into().unwrap();
+        assert_eq!("Alice", name);
+        assert!(!statement.next().unwrap());
+
+        let last = SqliteDB::wal_snapshot(fs.open(&copy_path).unwrap(), wal, commits[2]).unwrap();
+        let mut statement = last.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+    }
+
+    #[test]
+    fn sqlite_carve_deleted_rows() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("carving.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        let connection = prepare_db(connection);
+        connection.execute("DELETE FROM users WHERE name = 'Bob';").unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let carved = w_conn.carve_deleted().unwrap();
+        let mut statement = carving::CarvedStatement::new(carved);
+        let mut found = false;
+        while statement.next().unwrap() {
+            let source: String = statement.read(2).unwrap().try_into().unwrap();
+            if let ColumnValue::String(name) = statement.read(5).unwrap() {
+                if name == "Bob" {
+                    // The last inserted cell is at the start of the content area, so it is freed as unallocated space
+                    assert_eq!("unallocated", source);
+                    let age: usize = statement.read(6).unwrap().try_into().unwrap();
+                    assert_eq!(69, age);
+                    found = true;
+                }
+            }
+        }
+        assert!(found);
+    }
+
+    #[test]
+    fn sqlite_carve_freeblocks_of_freed_pages() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("carving_freelist.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        // Rowids of 3 bytes: the freeblock header only overwrites the payload size and the rowid of the deleted cell.
+        // The filler leaves a freelist trunk, so the pages of notes become freelist leaves.
+        connection
+            .execute(
+                "
+            CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+            CREATE TABLE filler (a);
+            WITH RECURSIVE n(i) AS (SELECT 100000 UNION ALL SELECT i + 1 FROM n WHERE i < 100200)
+            INSERT INTO notes SELECT i, 'note ' || i, 'body of the note ' || i FROM n;
+            DELETE FROM notes WHERE id = 100000;
+            DROP TABLE filler;
+            DROP TABLE notes;
+            ",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let carved = w_conn.carve_deleted().unwrap();
+        let deleted = carved
+            .iter()
+            .find(|v| v.values.iter().any(|v| matches!(v, ColumnValue::String(body) if body == "body of the note 100000")))
+            .unwrap();
+        assert_eq!(carving::CarvedSource::Freelist, deleted.source);
+    }
+
+    #[test]
+    fn sqlite_match_carved_rows() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("matching.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        let connection = prepare_db(connection);
+        connection.execute("CREATE TABLE urls (id INTEGER PRIMARY KEY, url TEXT, visits INTEGER, last_visit REAL); DELETE FROM users WHERE name = 'Alice';").unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let matched = w_conn.carve_deleted_tables().unwrap();
+        // Alice was freed as a freeblock, the start of her header is lost
+        let alice = matched.iter().find(|v| matches!(v.record.values.first(), Some(ColumnValue::String(name)) if name == "Alice")).unwrap();
+        assert!(alice.record.partial_header);
+        assert_eq!("users", alice.table.best.as_ref().unwrap().table);
+    }
+
+    #[test]
+    fn sqlite_query_recovered_tables() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("recovered.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        let connection = prepare_db(connection);
+        connection.execute("DELETE FROM users WHERE name = 'Bob';").unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let recovered = w_conn.recovered_tables().unwrap();
+        let mut statement = recovered.prepare("SELECT name, age, _source FROM deleted_users WHERE name LIKE 'B%';").unwrap();
+        assert!(statement.next().unwrap());
+        let age: usize = statement.read(1).unwrap().try_into().unwrap();
+        let source: String = statement.read(2).unwrap().try_into().unwrap();
+        assert_eq!(69, age);
+        assert_eq!("unallocated", source);
+        assert!(!statement.next().unwrap());
+    }
+
+    #[test]
+    fn sqlite_recovered_tables_avoid_name_clashes() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("recovered_names.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE unknown (id INTEGER PRIMARY KEY, _page TEXT, note TEXT);
+                INSERT INTO unknown VALUES (1, 'first page', 'kept');
+                INSERT INTO unknown VALUES (2, 'second page', 'deleted');
+                DELETE FROM unknown WHERE id = 2;",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let recovered = w_conn.recovered_tables().unwrap();
+        let mut statement = recovered.prepare("SELECT id, _page, _page_0, _rowid FROM deleted_unknown WHERE note = 'deleted';").unwrap();
+        assert!(statement.next().unwrap());
+        let id: i64 = statement.read(0).unwrap().try_into().unwrap();
+        let page: String = statement.read(1).unwrap().try_into().unwrap();
+        let rowid: i64 = statement.read(3).unwrap().try_into().unwrap();
+        assert_eq!(2, id);
+        assert_eq!(2, rowid);
+        assert_eq!("second page", page);
+        assert!(matches!(statement.read(2).unwrap(), ColumnValue::Integer(_)));
+        drop(statement);
+        assert!(recovered.prepare("SELECT _page FROM deleted_unknown_0;").is_ok());
+        drop(w_conn);
+    }
+
+    #[test]
+    fn sqlite_substitutes_unknown_collations() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("collations.db");
+        // The fallbacks also let us create a database like the ones of Android
+        let creator = SqliteDB::new(sqlite::open(&temp_path).unwrap());
+        creator.conn.execute("CREATE TABLE contacts (name TEXT COLLATE LOCALIZED); CREATE INDEX contacts_sort ON contacts (name COLLATE custom_sort);").unwrap();
+        creator.conn.execute("INSERT INTO contacts VALUES ('bob'), ('Alice'), ('Carol');").unwrap();
+        drop(creator);
+        assert!(sqlite::open(&temp_path).unwrap().prepare("SELECT name FROM contacts ORDER BY name;").is_err());
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let substitutions = w_conn.collation_substitutions();
+        assert_eq!(2, substitutions.len());
+        assert_eq!(CollationFallback::UnicodeNoCase, substitutions.iter().find(|v| v.name == "LOCALIZED").unwrap().fallback);
+        assert_eq!(CollationFallback::Binary, substitutions.iter().find(|v| v.name == "custom_sort").unwrap().fallback);
+        let mut statement = w_conn.prepare("SELECT name FROM contacts ORDER BY name;").unwrap();
+        let mut names = Vec::new();
+        while statement.next().unwrap() {
+            let name: String = statement.read(0).unwrap().try_into().unwrap();
+            names.push(name);
+        }
+        assert_eq!(vec!["Alice", "bob", "Carol"], names);
+        drop(statement);
+        drop(w_conn);
+    }
+
+    #[test]
+    fn sqlite_reads_unavailable_virtual_tables() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("vtab.db");
+        let connection = prepare_db(sqlite::open(&temp_path).unwrap());
+        // An FTS table of a module only the app registers, with its shadow tables. USING starts a new line.
+        connection.execute("CREATE TABLE notes_content (id INTEGER PRIMARY KEY, c0, c1); CREATE TABLE notes_data (id INTEGER PRIMARY KEY, block BLOB);").unwrap();
+        connection.execute("INSERT INTO notes_content VALUES (7, 'Groceries', 'Milk and eggs');").unwrap();
+        connection.execute("PRAGMA writable_schema=ON; INSERT INTO sqlite_master (type, name, tbl_name, rootpage, sql) VALUES ('table', 'notes', 'notes', 0, 'CREATE VIRTUAL TABLE notes' || char(10) || 'USING app_fts(title, body, tokenize=''app_tokenizer'')'); PRAGMA writable_schema=OFF;").unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        assert!(w_conn.list_tables().unwrap().contains(&"notes".to_string()));
+        assert!(w_conn.schema().unwrap().virtual_tables.iter().any(|v| v.name == "notes"));
+        let mut statement = w_conn.prepare("SELECT name, age FROM users ORDER BY name;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+        drop(statement);
+
+        let unavailable = w_conn.unavailable_virtual_tables().unwrap();
+        assert_eq!(1, unavailable.len());
+        assert_eq!("app_fts", unavailable[0].table.module);
+        assert_eq!(Some("app_tokenizer".to_string()), unavailable[0].table.tokenizer());
+        assert!(unavailable[0].error.contains("app_fts"));
+        assert_eq!(vec!["notes_content", "notes_data"], unavailable[0].shadow_tables);
+
+        let mut content = w_conn.virtual_table_content("notes").unwrap();
+        assert_eq!("body", content.column_name(2).unwrap());
+        assert!(content.next().unwrap());
+        let rowid: i64 = content.read(0).unwrap().try_into().unwrap();
+        let title: String = content.read(1).unwrap().try_into().unwrap();
+        assert_eq!(7, rowid);
+        assert_eq!("Groceries", title);
+        drop(content);
+        drop(w_conn);
+    }
+
+    #[test]
+    fn sqlite_typed_errors() {
+        let w_conn = prepare_wrapper(initialize_mem_db());
+        let error = w_conn.try_statement("SELECT * FROM missing;").err().unwrap();
+        assert_eq!(error::SqliteErrorKind::NoSuchTable, error.kind);
+        assert_eq!(Some(1), error.code());
+        assert_eq!(Some("SELECT * FROM missing;"), error.sql.as_deref());
+        assert!(matches!(
+            w_conn.prepare("SELECT * FROM missing;").err(),
+            Some(ForensicError::Other(v)) if v.contains("no such table: missing") && v.contains("SELECT * FROM missing;")
+        ));
+
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("notadb.db");
+        std::fs::write(&temp_path, vec![0x41u8; 4096]).unwrap();
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let error = w_conn.try_statement("SELECT name FROM sqlite_schema;").err().unwrap();
+        assert_eq!(error::SqliteErrorKind::NotADatabase, error.kind);
+        assert!(matches!(w_conn.prepare("SELECT name FROM sqlite_schema;").err(), Some(ForensicError::Other(v)) if v.contains("not a database")));
+        drop(w_conn);
+
+        // Random looking pages with no known header: a SQLCipher database without its key
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let encrypted: Vec<u8> = (0..16384)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect();
+        std::fs::write(&temp_path, encrypted).unwrap();
+        let w_conn = SqliteDB::virtual_file(fs.open(&temp_path).unwrap()).unwrap();
+        let error = w_conn.try_statement("SELECT name FROM sqlite_schema;").err().unwrap();
+        assert_eq!(error::SqliteErrorKind::Encrypted, error.kind);
+        assert!(matches!(w_conn.prepare("SELECT name FROM sqlite_schema;").err(), Some(ForensicError::Other(v)) if v.contains("encrypted")));
+        drop(w_conn);
+    }
+
+    #[test]
+    fn sqlite_sqlcipher_file() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let plain_path = test_dir.path().join("plain.db");
+        let encrypted_path = test_dir.path().join("sqlcipher.db");
+        let connection = sqlite::open(&plain_path).unwrap();
+        // SQLITE_FCNTL_RESERVE_BYTES: room for the IV and the HMAC of SQLCipher 4
+        let mut reserve: std::os::raw::c_int = 80;
+        unsafe {
+            sqlite3_sys::sqlite3_file_control(connection.as_raw(), c"main".as_ptr(), 38, &mut reserve as *mut std::os::raw::c_int as *mut std::os::raw::c_void);
+        }
+        connection.execute("PRAGMA page_size=4096;").unwrap();
+        drop(prepare_db(connection));
+        let mut data = std::fs::read(&plain_path).unwrap();
+        assert_eq!(80, data[20]);
+
+        let params = SqlCipherParams { kdf_iterations: 1000, ..SqlCipherParams::v4() };
+        let key = SqlCipherKey::parse("correct horse").unwrap();
+        let salt = [7u8; 16];
+        let cipher = PageCipher::new(&key, &params, &salt).unwrap();
+        for (i, page) in data.chunks_exact_mut(4096).enumerate() {
+            cipher.encrypt_page(i as u32 + 1, page, [i as u8; 16], &salt);
+        }
+        std::fs::write(&encrypted_path, &data).unwrap();
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let classification = encryption::classify(fs.open(&encrypted_path).unwrap().as_mut()).unwrap();
+        assert_eq!(encryption::DatabaseClass::SqlCipherLikely, classification.class);
+        let w_conn = SqliteDB::sqlcipher_file(fs.open(&encrypted_path).unwrap(), &key, &params).unwrap();
+        let mut statement = w_conn.prepare("SELECT name, age FROM users;").unwrap();
+        test_database_content(statement.as_mut()).expect("Should not return error");
+        drop(statement);
+        assert_eq!(4096, w_conn.header().unwrap().page_size);
+        drop(w_conn);
+        let wrong = SqlCipherKey::parse("wrong horse").unwrap();
+        assert!(SqliteDB::sqlcipher_file(fs.open(&encrypted_path).unwrap(), &wrong, &params).is_err());
+    }
+
+    #[test]
+    fn sqlite_recover_truncated_file() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("recover.db");
+        let damaged_path = test_dir.path().join("recover.damaged.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection
+            .execute(
+                "
+            PRAGMA page_size=1024;
+            CREATE TABLE events (id INTEGER PRIMARY KEY AUTOINCREMENT, kind TEXT);
+            INSERT INTO events (kind) VALUES ('login'), ('logout'), ('login');
+            DELETE FROM events WHERE id = 3;
+            CREATE TABLE notes (rowid TEXT, body TEXT);
+            INSERT INTO notes (_rowid_, rowid, body) VALUES (7, 'first', 'kept');
+            CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL, picture BLOB);
+            CREATE INDEX users_name ON users (name);
+            INSERT INTO users VALUES (1, 'Alice', zeroblob(3000));
+            WITH RECURSIVE n(i) AS (SELECT 2 UNION ALL SELECT i + 1 FROM n WHERE i < 400)
+            INSERT INTO users SELECT i, 'user ' || i, NULL FROM n;
+            PRAGMA writable_schema=ON;
+            INSERT INTO sqlite_master (type, name, tbl_name, rootpage, sql) VALUES ('table', 'docs', 'docs', 0, 'CREATE VIRTUAL TABLE docs' || char(10) || 'USING app_fts(body)');
+            PRAGMA writable_schema=OFF;
+            ",
+            )
+            .unwrap();
+        drop(connection);
+
+        // A partial acquisition that also lost the page size of the header
+        let mut data = std::fs::read(&temp_path).unwrap();
+        data.truncate(data.len() * 2 / 3 + 100);
+        data[16..18].copy_from_slice(&[0, 0]);
+        std::fs::write(&damaged_path, &data).unwrap();
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let (w_conn, report) = SqliteDB::recover_file(fs.open(&damaged_path).unwrap()).unwrap();
+        assert!(report.page_size_guessed);
+        assert_eq!(1024, report.page_size);
+        assert!(report.truncated);
+        assert!(report.lost_pages.iter().any(|v| v.object == "users"));
+        let users = report.tables.iter().find(|v| v.name == "users").unwrap();
+        assert!(users.rows > 0 && users.rows < 400);
+        // Virtual tables are not created, whatever whitespace comes before USING
+        assert!(!report.tables.iter().any(|v| v.name == "docs"));
+        assert!(!report.schema_errors.iter().any(|v| v.starts_with("docs")));
+
+        let mut statement = w_conn.statement("SELECT name, length(picture) FROM users WHERE id = 1;").unwrap();
+        assert!(statement.next().unwrap());
+        let name: String = statement.read(0).unwrap().try_into().unwrap();
+        let length: i64 = statement.read(1).unwrap().try_into().unwrap();
+        assert_eq!("Alice", name);
+        assert_eq!(3000, length);
+        drop(statement);
+        let mut statement = w_conn.statement("SELECT count(*) FROM users;").unwrap();
+        assert!(statement.next().unwrap());
+        let count: i64 = statement.read(0).unwrap().try_into().unwrap();
+        assert_eq!(users.rows as i64, count);
+        drop(statement);
+        assert!(w_conn.schema().unwrap().indexes.iter().any(|v| v.name == "users_name"));
+        // The recovered sequence, not the one SQLite computed while inserting the rows
+        let mut statement = w_conn.statement("SELECT seq FROM sqlite_sequence WHERE name = 'events';").unwrap();
+        assert!(statement.next().unwrap());
+        let seq: i64 = statement.read(0).unwrap().try_into().unwrap();
+        assert_eq!(3, seq);
+        assert!(!statement.next().unwrap());
+        drop(statement);
+        // A column named rowid shadows the rowid
+        let mut statement = w_conn.statement("SELECT _rowid_, rowid FROM notes;").unwrap();
+        assert!(statement.next().unwrap());
+        let rowid: i64 = statement.read(0).unwrap().try_into().unwrap();
+        let column: String = statement.read(1).unwrap().try_into().unwrap();
+        assert_eq!(7, rowid);
+        assert_eq!("first", column);
+        drop(statement);
+        let custody = w_conn.close();
+        assert_eq!(Some(true), custody[0].verified);
+    }
+
+    fn test_database_content(statement: &mut dyn SqlStatement) -> ForensicResult<()> {
         assert!(statement.next()?);
         let name: String = statement.read(0)?.try_into()?;
         let age: usize = statement.read(1)?.try_into()?;
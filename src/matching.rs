@@ -0,0 +1,264 @@
+//! Matching of carved records against the tables of the schema by their serial type signature.
+use forensic_rs::traits::sql::ColumnValue;
+
+use crate::{
+    carving::CarvedRecord,
+    record::{decode_value, read_varint, serial_type_size},
+    schema::{Affinity, SchemaColumn, SchemaTable},
+};
+
+/// Candidate table for a record
+#[derive(Debug, Clone)]
+pub struct TableMatch {
+    pub table: String,
+    /// Between 0 and 1
+    pub score: f32,
+}
+
+/// Tables a record may come from
+#[derive(Debug, Clone, Default)]
+pub struct RecordMatch {
+    pub best: Option<TableMatch>,
+    /// Other candidates, best first
+    pub alternatives: Vec<TableMatch>,
+}
+
+/// A carved record with the tables it may belong to
+#[derive(Debug, Clone)]
+pub struct MatchedRecord {
+    pub record: CarvedRecord,
+    pub table: RecordMatch,
+}
+
+/// Minimum score for a table to be a candidate
+pub(crate) const MIN_SCORE: f32 = 0.5;
+/// Records written before an `ALTER TABLE ADD COLUMN` have fewer columns than the table
+const FEWER_COLUMNS_PENALTY: f32 = 0.8;
+
+/// How well a serial type fits a column
+fn column_score(serial: u64, column: &SchemaColumn) -> f32 {
+    if column.rowid_alias {
+        return if serial == 0 { 1.0 } else { 0.0 };
+    }
+    let text = serial >= 13 && serial % 2 == 1;
+    let blob = serial >= 12 && serial.is_multiple_of(2);
+    match (column.affinity, serial) {
+        (_, 0) => 0.8,
+        (Affinity::Integer, 1..=6 | 8 | 9) => 1.0,
+        (Affinity::Integer, 7) => 0.5,
+        (Affinity::Real, 7) => 1.0,
+        // Integral values are stored as integers in REAL columns
+        (Affinity::Real, 1..=6 | 8 | 9) => 0.9,
+        (Affinity::Numeric, 1..=9) => 1.0,
+        (Affinity::Numeric, _) if text => 0.4,
+        (Affinity::Text, _) if text => 1.0,
+        (Affinity::Text, _) if blob => 0.4,
+        (Affinity::Blob, _) => 0.9,
+        (_, _) if blob => 0.3,
+        _ => 0.1,
+    }
+}
+
+/// Score of a serial type signature against a table. None when the record cannot belong to the table.
+pub fn table_score(serial_types: &[u64], table: &SchemaTable) -> Option<f32> {
+    if serial_types.is_empty() || serial_types.len() > table.columns.len() {
+        return None;
+    }
+    let mut total = 0.0;
+    for (serial, column) in serial_types.iter().zip(table.columns.iter()) {
+        let score = column_score(*serial, column);
+        if score == 0.0 {
+            return None;
+        }
+        total += score;
+    }
+    let mut score = total / serial_types.len() as f32;
+    if serial_types.len() < table.columns.len() {
+        score *= FEWER_COLUMNS_PENALTY;
+    }
+    Some(score)
+}
+
+/// Candidate tables for a serial type signature
+pub fn match_record(serial_types: &[u64], tables: &[SchemaTable]) -> RecordMatch {
+    let mut candidates: Vec<TableMatch> = tables
+        .iter()
+        .filter_map(|table| {
+            table_score(serial_types, table).map(|score| TableMatch {
+                table: table.name.clone(),
+                score,
+            })
+        })
+        .filter(|v| v.score >= MIN_SCORE)
+        .collect();
+    candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    let mut candidates = candidates.into_iter();
+    RecordMatch {
+        best: candidates.next(),
+        alternatives: candidates.collect(),
+    }
+}
+
+/// A record rebuilt with the help of a table after the start of its header was overwritten
+#[derive(Debug, Clone)]
+pub struct PartialRecord {
+    pub table: String,
+    pub serial_types: Vec<u64>,
+    pub values: Vec<ColumnValue>,
+    /// Leading serial types that were guessed
+    pub guessed: usize,
+    pub score: f32,
+    /// Bytes of `data` used by the record
+    pub size: usize,
+}
+
+/// Rebuilds a record whose first bytes were overwritten, like the cells turned into freeblocks.
+/// `data` is expected to start inside the record header and to end where the record ended.
+/// The leading serial types that are missing are guessed: NULL for all but the last one, whose size is what is left in `data`.
+pub fn recover_partial_record(data: &[u8], table: &SchemaTable) -> Option<PartialRecord> {
+    let columns = table.columns.len();
+    let mut best: Option<PartialRecord> = None;
+    for guessed in 0..=columns.min(3) {
+        let candidate = match try_partial_record(data, table, guessed) {
+            Some(v) => v,
+            None => continue,
+        };
+        if best.as_ref().map(|v| candidate.score > v.score).unwrap_or(true) {
+            best = Some(candidate);
+        }
+    }
+    best
+}
+
+fn try_partial_record(data: &[u8], table: &SchemaTable, guessed: usize) -> Option<PartialRecord> {
+    let mut serial_types = Vec::with_capacity(table.columns.len());
+    let mut pos = 0;
+    for _ in guessed..table.columns.len() {
+        let (serial, used) = read_varint(data.get(pos..)?)?;
+        serial_type_size(serial)?;
+        serial_types.push(serial);
+        pos += used;
+    }
+    let header_end = pos;
+    let known_size: usize = serial_types.iter().filter_map(|v| serial_type_size(*v)).sum();
+    let left = data.len().checked_sub(header_end + known_size)?;
+    if guessed > 0 {
+        let last = guess_serial(left, &table.columns[guessed - 1])?;
+        let mut full = vec![0u64; guessed - 1];
+        full.push(last);
+        full.extend(serial_types);
+        serial_types = full;
+    }
+    let score = table_score(&serial_types, table)?;
+    if score < MIN_SCORE {
+        return None;
+    }
+    let mut values = Vec::with_capacity(serial_types.len());
+    let mut body = header_end;
+    for serial in &serial_types {
+        let size = serial_type_size(*serial)?;
+        values.push(decode_value(*serial, data.get(body..body + size)?));
+        body += size;
+    }
+    Some(PartialRecord {
+        table: table.name.clone(),
+        serial_types,
+        values,
+        guessed,
+        // Guesses weaken the result
+        score: score * (1.0 - 0.1 * guessed as f32),
+        size: body,
+    })
+}
+
+/// Serial type of a value of the given size in a column
+fn guess_serial(size: usize, column: &SchemaColumn) -> Option<u64> {
+    if size == 0 {
+        return Some(0);
+    }
+    if column.rowid_alias {
+        return None;
+    }
+    let integer = match size {
+        1 => Some(1),
+        2 => Some(2),
+        3 => Some(3),
+        4 => Some(4),
+        6 => Some(5),
+        8 => Some(6),
+        _ => None,
+    };
+    match column.affinity {
+        Affinity::Integer => integer,
+        Affinity::Real if size == 8 => Some(7),
+        Affinity::Real | Affinity::Numeric => integer,
+        Affinity::Text => Some(13 + 2 * size as u64),
+        Affinity::Blob => Some(12 + 2 * size as u64),
+    }
+}
+
+#[cfg(test)]
+mod test_matching {
+    use super::*;
+
+    fn column(name: &str, declared_type: &str, rowid_alias: bool) -> SchemaColumn {
+        SchemaColumn {
+            name: name.into(),
+            declared_type: declared_type.into(),
+            affinity: Affinity::from_declared_type(declared_type),
+            primary_key: if rowid_alias { 1 } else { 0 },
+            not_null: false,
+            default_value: None,
+            rowid_alias,
+        }
+    }
+
+    fn tables() -> Vec<SchemaTable> {
+        vec![
+            SchemaTable {
+                name: "users".into(),
+                columns: vec![column("name", "TEXT", false), column("age", "INTEGER", false)],
+                root_page: 2,
+                without_rowid: false,
+                sql: "CREATE TABLE users (name TEXT, age INTEGER)".into(),
+            },
+            SchemaTable {
+                name: "urls".into(),
+                columns: vec![
+                    column("id", "INTEGER", true),
+                    column("url", "LONGVARCHAR", false),
+                    column("visit_count", "INTEGER", false),
+                ],
+                root_page: 3,
+                without_rowid: false,
+                sql: "CREATE TABLE urls (id INTEGER PRIMARY KEY, url LONGVARCHAR, visit_count INTEGER)".into(),
+            },
+        ]
+    }
+
+    #[test]
+    fn should_match_by_signature() {
+        let tables = tables();
+        let found = match_record(&[0x17, 0x01], &tables);
+        assert_eq!("users", found.best.unwrap().table);
+        let found = match_record(&[0x00, 0x33, 0x01], &tables);
+        assert_eq!("urls", found.best.unwrap().table);
+        assert!(found.alternatives.is_empty());
+        // The rowid alias must be NULL
+        assert!(match_record(&[0x01, 0x33, 0x01], &tables).best.is_none());
+    }
+
+    #[test]
+    fn should_recover_overwritten_header() {
+        let tables = tables();
+        // Serial type of "name" lost: only the age serial type survives, then "Alice" and 42
+        let data = [0x01, b'A', b'l', b'i', b'c', b'e', 42];
+        let record = recover_partial_record(&data, &tables[0]).unwrap();
+        assert_eq!(1, record.guessed);
+        assert_eq!(vec![0x17, 0x01], record.serial_types);
+        match &record.values[0] {
+            ColumnValue::String(v) => assert_eq!("Alice", v),
+            _ => panic!("Should be a string"),
+        }
+    }
+}
@@ -0,0 +1,822 @@
+//! Raw access to the pages of a database file: b-tree pages, their cells, the traversal of a b-tree and overflow chains. https://www.sqlite.org/fileformat.html#b_tree_pages
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Seek, SeekFrom},
+};
+
+use forensic_rs::{
+    prelude::{ForensicError, ForensicResult},
+    traits::sql::ColumnValue,
+};
+
+use crate::{
+    carving::freelist_pages,
+    header::{SqliteHeader, HEADER_SIZE},
+    record::{decode_record, read_varint, Record, RecordError},
+    SqliteDB,
+};
+
+pub(crate) const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+pub(crate) const DB_HEADER_SIZE: usize = HEADER_SIZE;
+
+pub(crate) const INTERIOR_INDEX: u8 = 2;
+pub(crate) const INTERIOR_TABLE: u8 = 5;
+pub(crate) const LEAF_INDEX: u8 = 10;
+pub(crate) const LEAF_TABLE: u8 = 13;
+
+/// Database file read page by page
+pub(crate) struct DbFile<'a, R: Read + Seek + ?Sized> {
+    reader: &'a mut R,
+    pub page_size: usize,
+    /// Page size minus the reserved bytes at the end of every page
+    pub usable_size: usize,
+    pub page_count: u32,
+    pub freelist_trunk: u32,
+}
+
+impl<'a, R: Read + Seek + ?Sized> DbFile<'a, R> {
+    pub(crate) fn open(reader: &'a mut R) -> ForensicResult<Self> {
+        let header = match SqliteHeader::read(reader) {
+            Ok(v) if v.magic_valid => v,
+            _ => return Err(ForensicError::Other("Not a SQLite database".into())),
+        };
+        if !header.page_size_valid() {
+            return Err(ForensicError::Other(format!("Invalid page size {}", header.page_size)));
+        }
+        let page_size = header.page_size as usize;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            reader,
+            page_size,
+            usable_size: header.usable_size() as usize,
+            page_count: (file_size / page_size as u64) as u32,
+            freelist_trunk: header.freelist_trunk,
+        })
+    }
+
+    /// Opens a damaged or truncated file. The magic is not required, a page size that is not valid is guessed from the b-tree pages
+    /// and a partial last page is counted. Returns whether the page size was guessed.
+    pub(crate) fn open_damaged(reader: &'a mut R) -> ForensicResult<(Self, bool)> {
+        let header = SqliteHeader::read(reader)?;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        let (page_size, guessed) = if header.page_size_valid() {
+            (header.page_size as usize, false)
+        } else {
+            (guess_page_size(reader, file_size)?, true)
+        };
+        // The rest of a header without magic is not trusted either
+        let (reserved, freelist_trunk) = if header.magic_valid {
+            (header.reserved_bytes as usize, header.freelist_trunk)
+        } else {
+            (0, 0)
+        };
+        let db = Self {
+            reader,
+            page_size,
+            usable_size: page_size - reserved,
+            page_count: file_size.div_ceil(page_size as u64) as u32,
+            freelist_trunk,
+        };
+        Ok((db, guessed))
+    }
+
+    /// Reads a page. Pages are numbered from 1. A partial last page is padded with zeros.
+    pub(crate) fn read_page(&mut self, number: u32) -> ForensicResult<Vec<u8>> {
+        if number == 0 || number > self.page_count {
+            return Err(ForensicError::Other(format!("Page {} out of range", number)));
+        }
+        let mut page = vec![0u8; self.page_size];
+        self.reader.seek(SeekFrom::Start(self.page_offset(number)))?;
+        let mut readed = 0;
+        while readed < page.len() {
+            match self.reader.read(&mut page[readed..])? {
+                0 => break,
+                n => readed += n,
+            }
+        }
+        Ok(page)
+    }
+
+    /// Offset of a page in the file
+    pub(crate) fn page_offset(&self, number: u32) -> u64 {
+        (number as u64 - 1) * self.page_size as u64
+    }
+}
+
+/// Page size whose page starts hold the most b-tree headers, for files whose header was overwritten.
+/// Smaller sizes land in the middle of pages and larger ones skip pages, both score lower. Defaults to 4096.
+fn guess_page_size<R: Read + Seek + ?Sized>(reader: &mut R, file_size: u64) -> ForensicResult<usize> {
+    let mut best = (4096, 0i64);
+    for page_size in (9..=16).map(|v| 1usize << v) {
+        let pages = (file_size / page_size as u64).min(256) as u32;
+        let mut score = 0i64;
+        let mut data = [0u8; 12];
+        for number in 2..=pages {
+            reader.seek(SeekFrom::Start((number as u64 - 1) * page_size as u64))?;
+            reader.read_exact(&mut data)?;
+            let valid = match BtreeHeader::parse(&data, 0) {
+                Some(header) => header.cell_content_start <= page_size && header.cell_pointers_end() <= header.cell_content_start,
+                None => false,
+            };
+            score += if valid { 1 } else { -1 };
+        }
+        if score > best.1 {
+            best = (page_size, score);
+        }
+    }
+    Ok(best.0)
+}
+
+/// Header of a b-tree page
+pub(crate) struct BtreeHeader {
+    pub page_type: u8,
+    pub first_freeblock: u16,
+    pub cell_count: u16,
+    pub cell_content_start: usize,
+    pub fragmented_bytes: u8,
+    pub right_most: Option<u32>,
+    /// Offset of the header in the page: 100 for page 1
+    pub offset: usize,
+}
+
+impl BtreeHeader {
+    pub(crate) fn parse(page: &[u8], offset: usize) -> Option<BtreeHeader> {
+        let data = page.get(offset..offset + 12)?;
+        let page_type = data[0];
+        let interior = match page_type {
+            INTERIOR_INDEX | INTERIOR_TABLE => true,
+            LEAF_INDEX | LEAF_TABLE => false,
+            _ => return None,
+        };
+        Some(BtreeHeader {
+            page_type,
+            first_freeblock: be_u16(&data[1..3]),
+            cell_count: be_u16(&data[3..5]),
+            cell_content_start: match be_u16(&data[5..7]) {
+                0 => 65536,
+                v => v as usize,
+            },
+            fragmented_bytes: data[7],
+            right_most: if interior { Some(be_u32(&data[8..12])) } else { None },
+            offset,
+        })
+    }
+
+    pub(crate) fn header_size(&self) -> usize {
+        if self.right_most.is_some() {
+            12
+        } else {
+            8
+        }
+    }
+
+    /// Offset of the first byte after the cell pointer array
+    pub(crate) fn cell_pointers_end(&self) -> usize {
+        self.offset + self.header_size() + 2 * self.cell_count as usize
+    }
+
+    /// Offsets of the cells stored in the cell pointer array
+    pub(crate) fn cell_pointers(&self, page: &[u8]) -> Vec<usize> {
+        let start = self.offset + self.header_size();
+        (0..self.cell_count as usize)
+            .filter_map(|i| page.get(start + 2 * i..start + 2 * i + 2))
+            .map(|v| be_u16(v) as usize)
+            .collect()
+    }
+}
+
+/// Type of a b-tree page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageType {
+    InteriorIndex,
+    InteriorTable,
+    LeafIndex,
+    LeafTable,
+}
+
+impl PageType {
+    /// Type from the first byte of the b-tree header
+    pub fn from_byte(value: u8) -> Option<PageType> {
+        match value {
+            INTERIOR_INDEX => Some(PageType::InteriorIndex),
+            INTERIOR_TABLE => Some(PageType::InteriorTable),
+            LEAF_INDEX => Some(PageType::LeafIndex),
+            LEAF_TABLE => Some(PageType::LeafTable),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            PageType::InteriorIndex => INTERIOR_INDEX,
+            PageType::InteriorTable => INTERIOR_TABLE,
+            PageType::LeafIndex => LEAF_INDEX,
+            PageType::LeafTable => LEAF_TABLE,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, PageType::LeafIndex | PageType::LeafTable)
+    }
+
+    /// Table b-trees are keyed by rowid, index b-trees by the record. WITHOUT ROWID tables are index b-trees.
+    pub fn is_table(&self) -> bool {
+        matches!(self, PageType::InteriorTable | PageType::LeafTable)
+    }
+}
+
+/// A block of the freeblock chain of a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Freeblock {
+    /// Offset in the page
+    pub offset: usize,
+    /// Size in bytes, the 4 bytes of the freeblock header included
+    pub size: usize,
+}
+
+/// A cell of a b-tree page
+#[derive(Debug, Clone)]
+pub struct Cell {
+    /// Offset of the cell in the page
+    pub offset: usize,
+    /// Bytes of the cell stored in the page, overflow pointer included
+    pub size: usize,
+    /// Child page of the cells of interior pages, holding the keys up to this cell
+    pub left_child: Option<u32>,
+    /// Rowid of the cells of table pages. In interior table pages it is the largest rowid of the left child.
+    pub rowid: Option<i64>,
+    /// Size of the whole payload. Zero for interior table cells, which have none.
+    pub payload_size: u64,
+    /// Part of the payload stored in the page. The record of the row or the key of the index.
+    pub payload: Vec<u8>,
+    /// First page of the overflow chain with the rest of the payload
+    pub overflow_page: Option<u32>,
+}
+
+/// A decoded b-tree page
+#[derive(Debug, Clone)]
+pub struct BtreePage {
+    pub number: u32,
+    pub page_type: PageType,
+    /// Offset of the b-tree header in the page: 100 for page 1, after the database header
+    pub header_offset: usize,
+    pub first_freeblock: u16,
+    pub cell_count: u16,
+    pub cell_content_start: usize,
+    pub fragmented_bytes: u8,
+    /// Child page with the keys after the last cell, only in interior pages
+    pub right_most: Option<u32>,
+    /// Cell pointer array: offsets of the cells in key order
+    pub cell_pointers: Vec<usize>,
+    /// Freeblock chain. A chain that loops or leaves the page is cut at that point.
+    pub freeblocks: Vec<Freeblock>,
+    /// Cells that could be decoded, in key order
+    pub cells: Vec<Cell>,
+    /// Cell pointers whose cell is outside the cell content area or cannot be decoded
+    pub invalid_cells: Vec<usize>,
+}
+
+impl BtreePage {
+    /// Decodes a b-tree page. `usable_size` is the page size minus the reserved bytes of the database header.
+    pub fn parse(number: u32, page: &[u8], usable_size: usize) -> ForensicResult<BtreePage> {
+        parse_btree_page(number, page, usable_size).map_err(ForensicError::Other)
+    }
+
+    /// Child pages from left to right, the right-most pointer included
+    pub fn children(&self) -> Vec<u32> {
+        self.cells.iter().filter_map(|v| v.left_child).chain(self.right_most).collect()
+    }
+
+    /// Offset of the first byte after the cell pointer array, where the unallocated space starts
+    pub fn cell_pointers_end(&self) -> usize {
+        let header_size = if self.right_most.is_some() { 12 } else { 8 };
+        self.header_offset + header_size + 2 * self.cell_count as usize
+    }
+}
+
+/// Same as `BtreePage::parse` with the reason as text
+pub(crate) fn parse_btree_page(number: u32, page: &[u8], usable_size: usize) -> Result<BtreePage, String> {
+    let header_offset = if number == 1 { DB_HEADER_SIZE } else { 0 };
+    let usable_size = usable_size.min(page.len());
+    let header = match BtreeHeader::parse(page, header_offset) {
+        Some(v) => v,
+        None => return Err(format!("Page {} is not a b-tree page, page type {}", number, page.get(header_offset).copied().unwrap_or(0))),
+    };
+    if header.cell_pointers_end() > usable_size {
+        return Err(format!("The {} cells of page {} do not fit in the page", header.cell_count, number));
+    }
+    let page_type = PageType::from_byte(header.page_type).unwrap_or(PageType::LeafTable);
+    let cell_pointers = header.cell_pointers(page);
+    let mut cells = Vec::with_capacity(cell_pointers.len());
+    let mut invalid_cells = Vec::new();
+    for pointer in &cell_pointers {
+        match parse_cell(page, page_type, *pointer, header.cell_pointers_end(), usable_size) {
+            Some(cell) => cells.push(cell),
+            None => invalid_cells.push(*pointer),
+        }
+    }
+    let mut freeblocks = Vec::new();
+    let mut offset = header.first_freeblock as usize;
+    // Freeblocks are in increasing order, anything else is a loop or garbage
+    while offset != 0 && offset >= header.cell_pointers_end() && offset + 4 <= usable_size {
+        let size = be_u16(&page[offset + 2..offset + 4]) as usize;
+        if size < 4 || offset + size > usable_size {
+            break;
+        }
+        freeblocks.push(Freeblock { offset, size });
+        let next = be_u16(&page[offset..offset + 2]) as usize;
+        if next != 0 && next < offset + size {
+            break;
+        }
+        offset = next;
+    }
+    Ok(BtreePage {
+        number,
+        page_type,
+        header_offset,
+        first_freeblock: header.first_freeblock,
+        cell_count: header.cell_count,
+        cell_content_start: header.cell_content_start,
+        fragmented_bytes: header.fragmented_bytes,
+        right_most: header.right_most,
+        cell_pointers,
+        freeblocks,
+        cells,
+        invalid_cells,
+    })
+}
+
+/// Decodes the cell at a pointer. None when it is outside the cell content area or its fields do not fit in the page.
+fn parse_cell(page: &[u8], page_type: PageType, offset: usize, pointers_end: usize, usable_size: usize) -> Option<Cell> {
+    if offset < pointers_end || offset >= usable_size {
+        return None;
+    }
+    let data = &page[offset..usable_size];
+    let mut pos = 0;
+    let left_child = match page_type {
+        PageType::InteriorIndex | PageType::InteriorTable => {
+            pos += 4;
+            Some(be_u32(data.get(0..4)?))
+        }
+        _ => None,
+    };
+    if page_type == PageType::InteriorTable {
+        let (rowid, used) = read_varint(data.get(pos..)?)?;
+        return Some(Cell {
+            offset,
+            size: pos + used,
+            left_child,
+            rowid: Some(rowid as i64),
+            payload_size: 0,
+            payload: Vec::new(),
+            overflow_page: None,
+        });
+    }
+    let (payload_size, used) = read_varint(data.get(pos..)?)?;
+    pos += used;
+    let rowid = match page_type {
+        PageType::LeafTable => {
+            let (rowid, used) = read_varint(data.get(pos..)?)?;
+            pos += used;
+            Some(rowid as i64)
+        }
+        _ => None,
+    };
+    // Larger payloads are garbage read as a size
+    if payload_size > 1 << 30 {
+        return None;
+    }
+    let local = local_payload_size(page_type.as_byte(), payload_size as usize, usable_size);
+    let payload = data.get(pos..pos + local)?.to_vec();
+    pos += local;
+    let overflow_page = if local < payload_size as usize {
+        let next = be_u32(data.get(pos..pos + 4)?);
+        pos += 4;
+        Some(next)
+    } else {
+        None
+    };
+    Some(Cell {
+        offset,
+        size: pos,
+        left_child,
+        rowid,
+        payload_size,
+        payload,
+        overflow_page,
+    })
+}
+
+/// A page reached by a traversal
+#[derive(Debug, Clone)]
+pub struct WalkedPage {
+    /// 0 for the root page
+    pub depth: u32,
+    /// Interior page that points to this one, None for the root
+    pub parent: Option<u32>,
+    pub page: BtreePage,
+}
+
+/// A page referenced by the b-tree that could not be read as part of it
+#[derive(Debug, Clone)]
+pub struct PageError {
+    pub page: u32,
+    pub parent: Option<u32>,
+    pub reason: String,
+}
+
+/// Pages of a b-tree
+#[derive(Debug, Clone, Default)]
+pub struct BtreeWalk {
+    /// Pages depth first from left to right: leaf pages come in key order
+    pub pages: Vec<WalkedPage>,
+    /// Pages that are beyond the end of the file, are not b-tree pages of the same kind as the root or are referenced twice
+    pub errors: Vec<PageError>,
+}
+
+impl BtreeWalk {
+    /// Cells of the leaf pages in key order: the rows of a table b-tree
+    pub fn leaf_cells(&self) -> impl Iterator<Item = &Cell> {
+        self.pages.iter().filter(|v| v.page.page_type.is_leaf()).flat_map(|v| v.page.cells.iter())
+    }
+}
+
+/// Reads a page of a database file. Pages are numbered from 1.
+pub fn read_page<R: Read + Seek + ?Sized>(reader: &mut R, number: u32) -> ForensicResult<Vec<u8>> {
+    DbFile::open(reader)?.read_page(number)
+}
+
+/// Reads and decodes a b-tree page of a database file
+pub fn read_btree_page<R: Read + Seek + ?Sized>(reader: &mut R, number: u32) -> ForensicResult<BtreePage> {
+    let mut db = DbFile::open(reader)?;
+    let page = db.read_page(number)?;
+    BtreePage::parse(number, &page, db.usable_size)
+}
+
+/// Walks the b-tree with the given root page, like the `rootpage` of a table in `sqlite_schema`.
+/// Pages that cannot be read are reported in `errors` and their subtrees skipped, the rest of the tree is still walked.
+pub fn walk_btree<R: Read + Seek + ?Sized>(reader: &mut R, root: u32) -> ForensicResult<BtreeWalk> {
+    walk(&mut DbFile::open(reader)?, root, &mut BTreeSet::new())
+}
+
+/// Walks a b-tree skipping the pages already in `visited`, and adds its pages to it
+pub(crate) fn walk<R: Read + Seek + ?Sized>(db: &mut DbFile<R>, root: u32, visited: &mut BTreeSet<u32>) -> ForensicResult<BtreeWalk> {
+    let mut walk = BtreeWalk::default();
+    let mut root_type: Option<bool> = None;
+    let mut pending = vec![(root, 0u32, None)];
+    while let Some((number, depth, parent)) = pending.pop() {
+        let mut error = |reason: String| walk.errors.push(PageError { page: number, parent, reason });
+        if number == 0 || number > db.page_count {
+            error(format!("Page {} is beyond the end of the file", number));
+            continue;
+        }
+        if !visited.insert(number) {
+            error(format!("Page {} is referenced twice", number));
+            continue;
+        }
+        let page = match db.read_page(number).and_then(|v| BtreePage::parse(number, &v, db.usable_size)) {
+            Ok(v) => v,
+            Err(ForensicError::Other(reason)) => {
+                error(reason);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // Table and index pages never mix in a b-tree
+        if *root_type.get_or_insert(page.page_type.is_table()) != page.page_type.is_table() {
+            error(format!("Page {} is a {:?} page in a b-tree of other kind", number, page.page_type));
+            continue;
+        }
+        pending.extend(page.children().into_iter().rev().map(|child| (child, depth + 1, Some(number))));
+        walk.pages.push(WalkedPage { depth, parent, page });
+    }
+    Ok(walk)
+}
+
+/// Where an overflow chain stopped before the end of the payload
+#[derive(Debug, Clone)]
+pub struct ChainBreak {
+    /// Page the chain pointed to. 0 when the chain ended too early.
+    pub page: u32,
+    pub reason: String,
+}
+
+/// Payload rebuilt from an overflow chain
+#[derive(Debug, Clone)]
+pub struct PayloadFragment {
+    /// Page and offset of the cell that owns the payload. None for orphaned chains, whose cell is gone.
+    pub cell: Option<(u32, usize)>,
+    pub rowid: Option<i64>,
+    /// Size declared by the cell, unknown for orphaned chains
+    pub payload_size: Option<u64>,
+    /// Overflow pages in chain order
+    pub chain: Vec<u32>,
+    /// Local part of the cell followed by the content of the overflow pages.
+    /// Orphaned chains miss the local part, with the header of the record, and keep the whole last page, whose end may be garbage.
+    pub payload: Vec<u8>,
+    /// For live cells the payload has its declared size. For orphaned chains the chain reaches its last page, the start of the payload is still missing.
+    pub complete: bool,
+    pub broken: Option<ChainBreak>,
+}
+
+impl PayloadFragment {
+    /// Decodes the payload as a record. Orphaned chains start in the middle of the record and rarely decode.
+    pub fn record(&self) -> Result<Record, RecordError> {
+        decode_record(&self.payload)
+    }
+}
+
+/// Content of the overflow pages of a payload from its first page. Reading stops after `size` bytes, pages in `visited` are not read again and the pages read are added to it.
+pub(crate) fn follow_overflow<R: Read + Seek + ?Sized>(
+    db: &mut DbFile<R>,
+    first: u32,
+    size: usize,
+    visited: &mut BTreeSet<u32>,
+) -> (Vec<u8>, Vec<u32>, Option<ChainBreak>) {
+    let mut data = Vec::with_capacity(size.min(1 << 20));
+    let mut chain = Vec::new();
+    let mut next = first;
+    while data.len() < size {
+        let reason = if next == 0 {
+            "The chain ends before the end of the payload".to_string()
+        } else if next > db.page_count {
+            format!("Overflow page {} is beyond the end of the file", next)
+        } else if !visited.insert(next) {
+            format!("Overflow page {} is already part of a b-tree or another chain", next)
+        } else {
+            match db.read_page(next) {
+                Ok(page) => {
+                    chain.push(next);
+                    let take = (size - data.len()).min(db.usable_size - 4);
+                    data.extend_from_slice(&page[4..4 + take]);
+                    next = be_u32(&page[0..4]);
+                    continue;
+                }
+                Err(_) => format!("Overflow page {} cannot be read", next),
+            }
+        };
+        return (data, chain, Some(ChainBreak { page: next, reason }));
+    }
+    (data, chain, None)
+}
+
+/// Payload of a live cell with its overflow chain
+pub(crate) fn cell_fragment<R: Read + Seek + ?Sized>(db: &mut DbFile<R>, page: u32, cell: &Cell, visited: &mut BTreeSet<u32>) -> PayloadFragment {
+    let mut payload = cell.payload.clone();
+    let (chain, broken) = match cell.overflow_page {
+        Some(first) => {
+            let remaining = (cell.payload_size as usize).saturating_sub(payload.len());
+            let (data, chain, broken) = follow_overflow(db, first, remaining, visited);
+            payload.extend_from_slice(&data);
+            (chain, broken)
+        }
+        None => (Vec::new(), None),
+    };
+    PayloadFragment {
+        cell: Some((page, cell.offset)),
+        rowid: cell.rowid,
+        payload_size: Some(cell.payload_size),
+        chain,
+        complete: broken.is_none(),
+        payload,
+        broken,
+    }
+}
+
+/// Reads the whole payload of a cell of the given page, following its overflow chain
+pub fn read_payload<R: Read + Seek + ?Sized>(reader: &mut R, page: u32, cell: &Cell) -> ForensicResult<PayloadFragment> {
+    Ok(cell_fragment(&mut DbFile::open(reader)?, page, cell, &mut BTreeSet::new()))
+}
+
+/// Payloads of every live cell with overflow pages and the orphaned overflow chains of the database.
+/// The b-trees are found from the `rootpage` of `sqlite_schema`. Orphaned chains are built from the pages no b-tree or live chain uses that look like overflow pages,
+/// freelist leaves included: when a row is deleted its overflow pages are freed but keep their content.
+pub fn overflow_fragments<R: Read + Seek + ?Sized>(reader: &mut R) -> ForensicResult<Vec<PayloadFragment>> {
+    let mut db = DbFile::open(reader)?;
+    let mut used = BTreeSet::new();
+    let mut fragments = Vec::new();
+    let mut roots = vec![1u32];
+    let mut next_root = 0;
+    while next_root < roots.len() {
+        let root = roots[next_root];
+        next_root += 1;
+        for walked in walk(&mut db, root, &mut used)?.pages {
+            let schema_leaf = root == 1 && walked.page.page_type == PageType::LeafTable;
+            for cell in &walked.page.cells {
+                let fragment = cell.overflow_page.map(|_| cell_fragment(&mut db, walked.page.number, cell, &mut used));
+                if schema_leaf {
+                    let payload = fragment.as_ref().map(|v| &v.payload[..]).unwrap_or(&cell.payload[..]);
+                    if let Ok(record) = decode_record(payload) {
+                        if let Some(ColumnValue::Integer(table_root)) = record.values.get(3) {
+                            let table_root = *table_root as u32;
+                            if table_root > 1 && !roots.contains(&table_root) {
+                                roots.push(table_root);
+                            }
+                        }
+                    }
+                }
+                fragments.extend(fragment);
+            }
+        }
+    }
+    fragments.extend(orphan_chains(&mut db, &used)?);
+    Ok(fragments)
+}
+
+/// Chains of the pages that look like overflow pages and are not used by the live database
+fn orphan_chains<R: Read + Seek + ?Sized>(db: &mut DbFile<R>, used: &BTreeSet<u32>) -> ForensicResult<Vec<PayloadFragment>> {
+    let trunks = freelist_pages(db).map(|(trunks, _)| trunks).unwrap_or_default();
+    let mut nexts = BTreeMap::new();
+    for number in 2..=db.page_count {
+        if used.contains(&number) || trunks.contains(&number) {
+            continue;
+        }
+        let page = db.read_page(number)?;
+        let next = be_u32(&page[0..4]);
+        // A zeroed page holds nothing, a b-tree page would need more than 2^24 pages to be read as an overflow pointer
+        if next > db.page_count || next == number || BtreeHeader::parse(&page, 0).is_some() || page[4..db.usable_size].iter().all(|v| *v == 0) {
+            continue;
+        }
+        nexts.insert(number, next);
+    }
+    let pointed: BTreeSet<u32> = nexts.values().copied().collect();
+    // Heads first, then whatever is left in loops
+    let heads: Vec<u32> = nexts.keys().copied().filter(|v| !pointed.contains(v)).chain(nexts.keys().copied()).collect();
+    let mut taken = BTreeSet::new();
+    let mut fragments = Vec::new();
+    for head in heads {
+        if !taken.insert(head) {
+            continue;
+        }
+        let mut chain = vec![head];
+        let mut broken = None;
+        let mut next = nexts[&head];
+        while next != 0 {
+            if !nexts.contains_key(&next) {
+                broken = Some(ChainBreak { page: next, reason: format!("Page {} is not a free overflow page", next) });
+                break;
+            }
+            if !taken.insert(next) {
+                broken = Some(ChainBreak { page: next, reason: format!("Page {} is already part of another chain", next) });
+                break;
+            }
+            chain.push(next);
+            next = nexts[&next];
+        }
+        let mut payload = Vec::with_capacity(chain.len() * (db.usable_size - 4));
+        for number in &chain {
+            payload.extend_from_slice(&db.read_page(*number)?[4..db.usable_size]);
+        }
+        fragments.push(PayloadFragment {
+            cell: None,
+            rowid: None,
+            payload_size: None,
+            chain,
+            payload,
+            complete: broken.is_none(),
+            broken,
+        });
+    }
+    Ok(fragments)
+}
+
+impl SqliteDB {
+    /// Raw page of the database file. Pages are numbered from 1.
+    pub fn raw_page(&self, number: u32) -> ForensicResult<Vec<u8>> {
+        read_page(&mut self.evidence("")?, number)
+    }
+    /// Decoded b-tree page of the database file
+    pub fn btree_page(&self, number: u32) -> ForensicResult<BtreePage> {
+        read_btree_page(&mut self.evidence("")?, number)
+    }
+    /// Walks the b-tree with the given root page of the database file
+    pub fn walk_btree(&self, root: u32) -> ForensicResult<BtreeWalk> {
+        walk_btree(&mut self.evidence("")?, root)
+    }
+    /// Walks the b-tree of a table or index from its `rootpage` in `sqlite_schema`
+    pub fn object_btree(&self, name: &str) -> ForensicResult<BtreeWalk> {
+        let schema = self.schema()?;
+        let root = schema
+            .table(name)
+            .map(|v| v.root_page)
+            .or_else(|| schema.indexes.iter().find(|v| v.name.eq_ignore_ascii_case(name)).map(|v| v.root_page));
+        match root {
+            Some(root) if root != 0 => self.walk_btree(root),
+            _ => Err(ForensicError::Missing),
+        }
+    }
+    /// Whole payload of a cell of the given page of the database file, following its overflow chain
+    pub fn cell_payload(&self, page: u32, cell: &Cell) -> ForensicResult<PayloadFragment> {
+        read_payload(&mut self.evidence("")?, page, cell)
+    }
+    /// Payloads of the live cells with overflow pages and the orphaned overflow chains of the database file
+    pub fn overflow_fragments(&self) -> ForensicResult<Vec<PayloadFragment>> {
+        overflow_fragments(&mut self.evidence("")?)
+    }
+}
+
+/// Number of payload bytes stored in the page for a payload of the given size. The rest spills into overflow pages.
+pub(crate) fn local_payload_size(page_type: u8, payload: usize, usable_size: usize) -> usize {
+    let max_local = if page_type == LEAF_TABLE {
+        usable_size - 35
+    } else {
+        ((usable_size - 12) * 64 / 255) - 23
+    };
+    if payload <= max_local {
+        return payload;
+    }
+    let min_local = ((usable_size - 12) * 32 / 255) - 23;
+    let local = min_local + ((payload - min_local) % (usable_size - 4));
+    if local <= max_local {
+        local
+    } else {
+        min_local
+    }
+}
+
+pub(crate) fn be_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+pub(crate) fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[cfg(test)]
+mod test_page {
+    use super::*;
+
+    use forensic_rs::traits::vfs::VirtualFileSystem;
+
+    use crate::workspace::TempWorkspace;
+
+    #[test]
+    fn should_walk_table_btree() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("page.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection
+            .execute(
+                "PRAGMA page_size=1024; CREATE TABLE t (a TEXT);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300) INSERT INTO t SELECT 'row ' || i FROM n;
+                DELETE FROM t WHERE rowid = 2;",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut file = fs.open(&temp_path).unwrap();
+        let schema = read_btree_page(file.as_mut(), 1).unwrap();
+        assert_eq!(PageType::LeafTable, schema.page_type);
+        assert_eq!(DB_HEADER_SIZE, schema.header_offset);
+        assert_eq!(1, schema.cells.len());
+        assert_eq!(Some(1), schema.cells[0].rowid);
+
+        let walk = walk_btree(file.as_mut(), 2).unwrap();
+        assert!(walk.errors.is_empty());
+        let root = &walk.pages[0];
+        assert_eq!(PageType::InteriorTable, root.page.page_type);
+        assert!(root.page.right_most.is_some());
+        assert!(walk.pages[1..].iter().all(|v| v.depth == 1 && v.parent == Some(2)));
+        let rowids: Vec<i64> = walk.leaf_cells().filter_map(|v| v.rowid).collect();
+        assert_eq!(299, rowids.len());
+        assert!(rowids.windows(2).all(|v| v[0] < v[1]));
+        // The deleted row left a freeblock in the first leaf
+        assert!(!walk.pages[1].page.freeblocks.is_empty());
+        assert_eq!(1, walk_btree(file.as_mut(), 10_000).unwrap().errors.len());
+    }
+
+    #[test]
+    fn should_rebuild_overflow_chains() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("overflow.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        // The dropped table leaves a freelist trunk, the overflow pages of the deleted row become its leaves
+        connection
+            .execute(
+                "PRAGMA page_size=1024; PRAGMA secure_delete=OFF;
+                CREATE TABLE filler (a); CREATE TABLE t (a BLOB);
+                INSERT INTO t VALUES (randomblob(3000)); INSERT INTO t VALUES (randomblob(3000));
+                DROP TABLE filler; DELETE FROM t WHERE rowid = 2;",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut file = fs.open(&temp_path).unwrap();
+        let fragments = overflow_fragments(file.as_mut()).unwrap();
+        let live = fragments.iter().find(|v| v.cell.is_some()).unwrap();
+        assert!(live.complete);
+        assert_eq!(Some(1), live.rowid);
+        assert_eq!(2, live.chain.len());
+        assert_eq!(live.payload_size, Some(live.payload.len() as u64));
+        match &live.record().unwrap().values[0] {
+            ColumnValue::Binary(v) => assert_eq!(3000, v.len()),
+            _ => panic!("Invalid value"),
+        }
+        let orphan = fragments.iter().find(|v| v.cell.is_none()).unwrap();
+        assert!(orphan.complete);
+        assert_eq!(2, orphan.chain.len());
+        assert!(orphan.chain.iter().all(|v| !live.chain.contains(v)));
+    }
+}
@@ -0,0 +1,233 @@
+//! SQLite record format and varints. https://www.sqlite.org/fileformat.html#record_format
+//! Used to decode the payload of cells, carved candidates and page images of the WAL and the journal.
+use std::fmt;
+
+use forensic_rs::{prelude::ForensicError, traits::sql::ColumnValue};
+
+/// Reads a SQLite varint. Returns the value and the number of bytes used, None when the data ends before the last byte.
+/// Varints use 1 to 9 bytes: the ninth byte contributes all of its 8 bits. Non-minimal encodings are accepted as SQLite does.
+pub fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(9) {
+        if i == 8 {
+            // The ninth byte contributes all of its 8 bits
+            return Some(((value << 8) | *byte as u64, 9));
+        }
+        value = (value << 7) | (*byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Size in bytes of a value with the given serial type. None for the reserved types 10 and 11.
+pub fn serial_type_size(serial: u64) -> Option<usize> {
+    match serial {
+        0 | 8 | 9 => Some(0),
+        1 => Some(1),
+        2 => Some(2),
+        3 => Some(3),
+        4 => Some(4),
+        5 => Some(6),
+        6 | 7 => Some(8),
+        10 | 11 => None,
+        n => Some(((n - 12) / 2) as usize),
+    }
+}
+
+/// Decodes a value. `data` should have the size of the serial type: shorter texts and blobs are returned cut, shorter numbers as Null.
+pub fn decode_value(serial: u64, data: &[u8]) -> ColumnValue {
+    match serial {
+        0 => ColumnValue::Null,
+        1..=6 if serial_type_size(serial).map(|v| data.len() < v).unwrap_or(true) => ColumnValue::Null,
+        1..=6 => {
+            // Big-endian two's complement of variable size
+            let mut value: i64 = if data.first().map(|v| v & 0x80 != 0).unwrap_or(false) { -1 } else { 0 };
+            for byte in data {
+                value = (value << 8) | *byte as i64;
+            }
+            ColumnValue::Integer(value)
+        }
+        7 => match data.get(0..8) {
+            Some(v) => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(v);
+                ColumnValue::Float(f64::from_be_bytes(bytes))
+            }
+            None => ColumnValue::Null,
+        },
+        8 => ColumnValue::Integer(0),
+        9 => ColumnValue::Integer(1),
+        n if n >= 12 && n % 2 == 0 => ColumnValue::Binary(data.to_vec()),
+        n if n >= 13 => ColumnValue::String(String::from_utf8_lossy(data).into_owned()),
+        _ => ColumnValue::Null,
+    }
+}
+
+/// A decoded record
+pub struct Record {
+    pub header_size: usize,
+    pub serial_types: Vec<u64>,
+    pub values: Vec<ColumnValue>,
+    /// Bytes used by the header and the values that were decoded
+    pub size: usize,
+    /// The payload ended before the last values. Texts and blobs are cut, other values are Null.
+    pub truncated: bool,
+    /// First value the payload did not hold completely
+    pub truncated_column: Option<usize>,
+}
+
+/// Why a record could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordErrorKind {
+    /// A varint runs past the end of the data or, for serial types, past the end of the header
+    TruncatedVarint,
+    /// The header size is smaller than its own varint
+    InvalidHeaderSize(u64),
+    /// The header size is larger than the data: the payload ends inside the header
+    TruncatedHeader(u64),
+    /// Serial types 10 and 11 are reserved and never written by SQLite
+    ReservedSerialType(u64),
+}
+
+/// Error of the record decoder with the position where decoding failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordError {
+    pub kind: RecordErrorKind,
+    /// Offset in the data of the varint that failed
+    pub offset: usize,
+    /// Column whose serial type failed, None for the header size
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            RecordErrorKind::TruncatedVarint => write!(f, "Truncated varint")?,
+            RecordErrorKind::InvalidHeaderSize(v) => write!(f, "Invalid record header size {}", v)?,
+            RecordErrorKind::TruncatedHeader(v) => write!(f, "Record header of {} bytes is truncated", v)?,
+            RecordErrorKind::ReservedSerialType(v) => write!(f, "Reserved serial type {}", v)?,
+        }
+        write!(f, " at offset {}", self.offset)?;
+        if let Some(column) = self.column {
+            write!(f, " in column {}", column)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl From<RecordError> for ForensicError {
+    fn from(error: RecordError) -> ForensicError {
+        ForensicError::Other(error.to_string())
+    }
+}
+
+/// Decodes a record at the start of `data`. Bytes after the record are ignored, `size` tells where it ended.
+/// A payload that ends among the values is decoded as far as it goes and marked as `truncated`. A payload that ends inside the header is an error.
+pub fn decode_record(data: &[u8]) -> Result<Record, RecordError> {
+    let error = |kind, offset, column| RecordError { kind, offset, column };
+    let (header_size, mut pos) = read_varint(data).ok_or_else(|| error(RecordErrorKind::TruncatedVarint, 0, None))?;
+    if header_size < pos as u64 {
+        return Err(error(RecordErrorKind::InvalidHeaderSize(header_size), 0, None));
+    }
+    if header_size > data.len() as u64 {
+        return Err(error(RecordErrorKind::TruncatedHeader(header_size), 0, None));
+    }
+    let header_size = header_size as usize;
+    let mut serial_types = Vec::new();
+    while pos < header_size {
+        let column = Some(serial_types.len());
+        let (serial, used) = read_varint(&data[pos..header_size]).ok_or_else(|| error(RecordErrorKind::TruncatedVarint, pos, column))?;
+        if serial_type_size(serial).is_none() {
+            return Err(error(RecordErrorKind::ReservedSerialType(serial), pos, column));
+        }
+        serial_types.push(serial);
+        pos += used;
+    }
+    let mut values = Vec::with_capacity(serial_types.len());
+    let mut body = header_size;
+    let mut truncated_column = None;
+    for (column, serial) in serial_types.iter().enumerate() {
+        let size = serial_type_size(*serial).unwrap_or(0);
+        if body + size > data.len() {
+            truncated_column.get_or_insert(column);
+            values.push(if *serial >= 12 {
+                decode_value(*serial, &data[body..])
+            } else {
+                ColumnValue::Null
+            });
+            body = data.len();
+            continue;
+        }
+        values.push(decode_value(*serial, &data[body..body + size]));
+        body += size;
+    }
+    Ok(Record {
+        header_size,
+        serial_types,
+        values,
+        size: body,
+        truncated: truncated_column.is_some(),
+        truncated_column,
+    })
+}
+
+#[cfg(test)]
+mod test_record {
+    use super::*;
+
+    #[test]
+    fn should_decode_varints() {
+        assert_eq!(Some((0x7f, 1)), read_varint(&[0x7f]));
+        assert_eq!(Some((0x80, 2)), read_varint(&[0x81, 0x00]));
+        assert_eq!(Some((u64::MAX, 9)), read_varint(&[0xff; 9]));
+        assert_eq!(None, read_varint(&[0x81]));
+    }
+
+    #[test]
+    fn should_decode_record() {
+        // Header of 3 bytes: text of 5 bytes and 8 bit integer
+        let data = [0x03, 0x17, 0x01, b'A', b'l', b'i', b'c', b'e', 42];
+        let record = decode_record(&data).unwrap();
+        assert_eq!(vec![0x17, 0x01], record.serial_types);
+        assert!(!record.truncated);
+        assert_eq!(9, record.size);
+        match (&record.values[0], &record.values[1]) {
+            (ColumnValue::String(name), ColumnValue::Integer(age)) => {
+                assert_eq!("Alice", name);
+                assert_eq!(42, *age);
+            }
+            _ => panic!("Invalid values"),
+        }
+        let record = decode_record(&data[0..6]).unwrap();
+        assert!(record.truncated);
+        assert_eq!(Some(0), record.truncated_column);
+    }
+
+    #[test]
+    fn should_report_decoding_errors() {
+        // Integer of 8 bytes, reserved type 10 and text
+        let error = decode_record(&[0x04, 0x06, 0x0a, 0x13]).err().unwrap();
+        assert_eq!(RecordErrorKind::ReservedSerialType(10), error.kind);
+        assert_eq!(2, error.offset);
+        assert_eq!(Some(1), error.column);
+        // The varint of the second serial type continues past the header
+        let error = decode_record(&[0x03, 0x01, 0x81, 0x01]).err().unwrap();
+        assert_eq!(RecordErrorKind::TruncatedVarint, error.kind);
+        assert_eq!((2, Some(1)), (error.offset, error.column));
+        assert_eq!(RecordErrorKind::TruncatedHeader(5), decode_record(&[0x05, 0x01]).err().unwrap().kind);
+        assert_eq!(RecordErrorKind::InvalidHeaderSize(0), decode_record(&[0x00]).err().unwrap().kind);
+        assert_eq!(RecordErrorKind::TruncatedVarint, decode_record(&[]).err().unwrap().kind);
+        assert_eq!("Reserved serial type 11 at offset 1 in column 0", decode_record(&[0x02, 0x0b]).err().unwrap().to_string());
+        assert!(matches!(
+            ForensicError::from(decode_record(&[0x02, 0x0b]).err().unwrap()),
+            ForensicError::Other(v) if v == "Reserved serial type 11 at offset 1 in column 0"
+        ));
+        // Integers shorter than their serial type are not invented
+        assert!(matches!(decode_value(6, &[0x01, 0x02]), ColumnValue::Null));
+        assert!(matches!(decode_value(1, &[0xff]), ColumnValue::Integer(-1)));
+    }
+}
@@ -0,0 +1,142 @@
+//! Rows recovered from free space, the WAL and the journal exposed as regular tables
+use std::collections::BTreeMap;
+
+use forensic_rs::{prelude::ForensicResult, traits::sql::ColumnValue};
+use sqlite::Connection;
+
+use crate::{
+    carving::{self, CarvedRecord, CarvedSource},
+    column_to_value,
+    error::SqliteError,
+    journal::JournalFile,
+    matching::match_record,
+    page::DbFile,
+    schema::SchemaTable,
+    wal::WalFile,
+    SqliteDB,
+};
+
+/// Prefix of the tables with recovered rows: `deleted_urls` holds the rows of `urls`
+pub const RECOVERED_PREFIX: &str = "deleted_";
+/// Table with the rows that match no table of the schema. `deleted_unknown_0`, `deleted_unknown_1`... when the schema has a table named `unknown`.
+pub const UNKNOWN_TABLE: &str = "deleted_unknown";
+/// Columns at the start of every recovered table. A column that clashes with a column of the table gets a `_0`, `_1`... suffix.
+pub const PROVENANCE_COLUMNS: [&str; 5] = ["_page", "_offset", "_source", "_confidence", "_rowid"];
+
+impl SqliteDB {
+    /// In-memory database with a `deleted_<table>` table for each table of the schema, holding the rows recovered from free space, the -wal and the -journal.
+    /// Rows start with the provenance columns `_page`, `_offset`, `_source`, `_confidence` and `_rowid`, the INTEGER PRIMARY KEY column holds the rowid too.
+    /// Rows that match no table go to `deleted_unknown`, with columns `c0`, `c1`... See `PROVENANCE_COLUMNS` and `UNKNOWN_TABLE` for the names used when they clash with the schema.
+    /// WAL frames and journal records are page images: every row version they hold is returned, live rows included.
+    pub fn recovered_tables(&self) -> ForensicResult<SqliteDB> {
+        let tables = self.schema_tables()?;
+        let mut records = self.carve_deleted()?;
+        records.extend(self.carve_companions(&tables)?);
+        build_recovered_db(&tables, records)
+    }
+
+    /// Carves the page images stored in the -wal and -journal the database was opened with
+    pub(crate) fn carve_companions(&self, tables: &[SchemaTable]) -> ForensicResult<Vec<CarvedRecord>> {
+        let mut main = self.evidence("")?;
+        let (page_size, usable_size) = {
+            let db = DbFile::open(&mut main)?;
+            (db.page_size, db.usable_size)
+        };
+        let mut records = Vec::new();
+        if let Ok(mut wal) = self.evidence("-wal") {
+            // An empty or zeroed WAL holds nothing to carve
+            if let Ok(parsed) = WalFile::parse(&mut wal) {
+                for frame in &parsed.frames {
+                    let image = parsed.read_page(&mut wal, frame)?;
+                    records.extend(carving::carve_page_image(&image, frame.page_number, frame.page_offset(), usable_size, CarvedSource::Wal, tables));
+                }
+            }
+        }
+        if let Ok(mut journal) = self.evidence("-journal") {
+            if let Ok(parsed) = JournalFile::parse(&mut journal, page_size as u32) {
+                for record in parsed.records() {
+                    let image = parsed.read_page(&mut journal, record)?;
+                    records.extend(carving::carve_page_image(&image, record.page_number, record.offset, usable_size, CarvedSource::Journal, tables));
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn build_recovered_db(tables: &[SchemaTable], records: Vec<CarvedRecord>) -> ForensicResult<SqliteDB> {
+    let connection = sqlite::open(":memory:").map_err(SqliteError::from)?;
+    let mut matched: BTreeMap<String, Vec<CarvedRecord>> = BTreeMap::new();
+    let mut unknown = Vec::new();
+    for record in records {
+        match match_record(&record.serial_types, tables).best {
+            Some(best) => matched.entry(best.table).or_default().push(record),
+            None => unknown.push(record),
+        }
+    }
+    let names: Vec<String> = tables.iter().map(|v| format!("{}{}", RECOVERED_PREFIX, v.name)).collect();
+    for (table, name) in tables.iter().zip(names.iter()) {
+        let columns: Vec<String> = table.columns.iter().map(|v| v.name.clone()).collect();
+        let rowid_alias = table.columns.iter().position(|v| v.rowid_alias);
+        insert_records(&connection, name, &columns, rowid_alias, matched.remove(&table.name).unwrap_or_default())?;
+    }
+    let width = unknown.iter().map(|v| v.values.len()).max().unwrap_or(0);
+    let columns: Vec<String> = (0..width).map(|i| format!("c{}", i)).collect();
+    let unknown_table = unique_name(UNKNOWN_TABLE, |name| names.iter().any(|v| v.eq_ignore_ascii_case(name)));
+    insert_records(&connection, &unknown_table, &columns, None, unknown)?;
+    SqliteDB::try_new(connection)
+}
+
+/// Creates a recovered table and inserts the records. `rowid_alias` is the INTEGER PRIMARY KEY column, filled with the rowid of the cell.
+fn insert_records(connection: &Connection, table: &str, columns: &[String], rowid_alias: Option<usize>, records: Vec<CarvedRecord>) -> ForensicResult<()> {
+    let mut definition: Vec<String> = PROVENANCE_COLUMNS
+        .iter()
+        .map(|v| unique_name(v, |name| columns.iter().any(|column| column.eq_ignore_ascii_case(name))))
+        .map(|v| quote_identifier(&v))
+        .collect();
+    definition.extend(columns.iter().map(|v| quote_identifier(v)));
+    let create = format!("CREATE TABLE {} ({});", quote_identifier(table), definition.join(", "));
+    connection.execute(&create).map_err(|e| SqliteError::new(e, Some(&create)))?;
+    let placeholders = vec!["?"; definition.len()].join(", ");
+    let insert = format!("INSERT INTO {} VALUES ({});", quote_identifier(table), placeholders);
+    let mut statement = connection.prepare(&insert).map_err(|e| SqliteError::new(e, Some(&insert)))?;
+    for record in records {
+        let mut values = vec![
+            sqlite::Value::Integer(record.page as i64),
+            sqlite::Value::Integer(record.offset as i64),
+            sqlite::Value::String(record.source.as_str().to_string()),
+            sqlite::Value::Float(record.confidence as f64),
+            match record.rowid {
+                Some(v) => sqlite::Value::Integer(v),
+                None => sqlite::Value::Null,
+            },
+        ];
+        values.extend((0..columns.len()).map(|i| match (record.values.get(i), record.rowid) {
+            // The record stores NULL for the INTEGER PRIMARY KEY, its value is the rowid
+            (Some(ColumnValue::Null) | None, Some(rowid)) if rowid_alias == Some(i) => sqlite::Value::Integer(rowid),
+            (Some(v), _) => column_to_value(v),
+            (None, _) => sqlite::Value::Null,
+        }));
+        for (i, value) in values.iter().enumerate() {
+            statement.bind((i + 1, value)).map_err(|e| SqliteError::new(e, Some(&insert)))?;
+        }
+        statement.next().map_err(|e| SqliteError::new(e, Some(&insert)))?;
+        statement.reset().map_err(|e| SqliteError::new(e, Some(&insert)))?;
+    }
+    Ok(())
+}
+
+/// `base`, or `base_0`, `base_1`... when it is already taken
+fn unique_name(base: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut name = base.to_string();
+    let mut suffix = 0;
+    while taken(&name) {
+        name = format!("{}_{}", base, suffix);
+        suffix += 1;
+    }
+    name
+}
+
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
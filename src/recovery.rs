@@ -0,0 +1,467 @@
+//! Recovery of corrupted and truncated databases without SQLite, like the `.recover` command of the SQLite shell.
+//! The b-trees of the tables in `sqlite_schema` are walked page by page from their root. Pages that cannot be read or parsed are skipped and reported,
+//! the rows of the rest are rebuilt into an in-memory database with the original schema.
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    io::{Read, Seek, SeekFrom},
+};
+
+use forensic_rs::{
+    prelude::{ForensicError, ForensicResult},
+    traits::{sql::ColumnValue, vfs::VirtualFile},
+};
+
+use crate::{
+    carving::freelist_pages,
+    error::SqliteError,
+    integrity::{hash_reader, EvidenceLocation, EvidenceRecord},
+    page::{follow_overflow, parse_btree_page, BtreePage, Cell, DbFile, PageType},
+    record::decode_record,
+    recovered::quote_identifier,
+    schema::{is_without_rowid, parse_virtual_table, SchemaTable},
+    vfs::VfsDatabase,
+    SqliteDB, SqliteStatement,
+};
+
+/// Table with the rows of leaf pages no b-tree reaches and the rows a rebuilt table rejected. A suffix is added if the schema already has a table with the name.
+pub const LOST_AND_FOUND: &str = "lost_and_found";
+/// Columns at the start of the lost and found table, followed by `c0`, `c1`...
+pub const LOST_AND_FOUND_COLUMNS: [&str; 3] = ["_page", "_table", "_rowid"];
+
+/// Name given to the b-tree of the schema in the report
+const SCHEMA_OBJECT: &str = "sqlite_schema";
+
+/// A page of a b-tree that was skipped
+#[derive(Debug, Clone)]
+pub struct LostPage {
+    pub page: u32,
+    /// Table whose b-tree or overflow chain references the page, `sqlite_schema` for the schema
+    pub object: String,
+    pub reason: String,
+}
+
+/// Rows rebuilt for a table of the schema
+#[derive(Debug, Clone)]
+pub struct RecoveredTable {
+    pub name: String,
+    pub root_page: u32,
+    /// Rows inserted in the rebuilt table
+    pub rows: usize,
+    /// Rows read but refused by the rebuilt table, like duplicated keys or more values than columns. They are in the lost and found table.
+    pub rejected_rows: usize,
+    /// Rows whose payload was cut by a lost overflow page or the end of the file
+    pub truncated_rows: usize,
+    /// Cells of the salvaged pages that could not be decoded
+    pub lost_cells: usize,
+    /// Pages of the b-tree that were skipped. The rows they held, and those of the pages below them, are missing or in the lost and found table.
+    pub lost_pages: usize,
+}
+
+/// What was salvaged and what was lost while recovering a database
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+    pub page_size: u32,
+    /// Pages of the file, a partial last page included
+    pub page_count: u32,
+    /// The page size of the header was not valid and was guessed from the b-tree pages
+    pub page_size_guessed: bool,
+    /// The file ends in a partial page, read padded with zeros
+    pub truncated: bool,
+    /// Pages read from the b-trees of the schema and the tables, overflow pages included
+    pub salvaged_pages: Vec<u32>,
+    pub lost_pages: Vec<LostPage>,
+    /// Table leaf pages outside every b-tree and the freelist. Their rows are in the lost and found table.
+    pub orphan_pages: Vec<u32>,
+    pub tables: Vec<RecoveredTable>,
+    /// Name of the lost and found table in the rebuilt database
+    pub lost_and_found: String,
+    /// Rows stored in the lost and found table
+    pub lost_and_found_rows: usize,
+    /// Objects of the schema that could not be created in the rebuilt database, with the error of SQLite.
+    /// Tables that fail are created with generic columns `c0`, `c1`...
+    pub schema_errors: Vec<String>,
+}
+
+impl SqliteDB {
+    /// Recovers a database that cannot be opened or fails mid-query, like a partial acquisition or a file from a damaged disk.
+    /// See `recovery::recover`. The evidence is hashed for the chain of custody, `header` and carving read the damaged file as it is.
+    pub fn recover_file(file: Box<dyn VirtualFile>) -> ForensicResult<(SqliteDB, RecoveryReport)> {
+        let mut files = VfsDatabase::new(false)?;
+        files.register("", file)?;
+        let mut reader = files.evidence("").ok_or(ForensicError::Missing)?;
+        let hasher = hash_reader(&mut reader)?;
+        let (mut db, report) = recover(&mut reader)?;
+        db.files = Some(files);
+        db.custody = vec![EvidenceRecord::new("", hasher, Some(EvidenceLocation::Vfs(String::new())))];
+        Ok((db, report))
+    }
+}
+
+/// Rebuilds the tables of a damaged database into an in-memory database without using SQLite to read it.
+/// Tables, indexes, views and triggers are created from the recovered schema and the rows of every reachable table leaf page are inserted.
+/// Virtual tables are not created, their shadow tables are recovered as regular tables. Freelist pages are left to carving.
+pub fn recover<R: Read + Seek + ?Sized>(reader: &mut R) -> ForensicResult<(SqliteDB, RecoveryReport)> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let (db, page_size_guessed) = DbFile::open_damaged(reader)?;
+    let mut walker = Walker {
+        report: RecoveryReport {
+            page_size: db.page_size as u32,
+            page_count: db.page_count,
+            page_size_guessed,
+            truncated: file_size % db.page_size as u64 != 0,
+            ..Default::default()
+        },
+        db,
+        claimed: BTreeSet::new(),
+    };
+    let entries: Vec<SchemaRow> = walker
+        .walk(1, SCHEMA_OBJECT, false)
+        .rows
+        .into_iter()
+        .filter_map(SchemaRow::from_row)
+        .collect();
+    let recovered = SqliteDB::try_new(sqlite::open(":memory:").map_err(SqliteError::from)?)?;
+
+    let mut contents = Vec::new();
+    for entry in entries.iter().filter(|v| v.kind == "table") {
+        let sql = entry.sql.as_deref().unwrap_or_default();
+        // Other internal tables are maintained by SQLite itself
+        if (entry.name.starts_with("sqlite_") && entry.name != "sqlite_sequence") || parse_virtual_table(sql).is_some() {
+            continue;
+        }
+        let content = walker.walk(entry.root_page, &entry.name, is_without_rowid(sql));
+        // sqlite_sequence is created along with the first AUTOINCREMENT table
+        if !entry.name.starts_with("sqlite_") {
+            if let Err(e) = recovered.conn.execute(sql) {
+                walker.report.schema_errors.push(format!("{}: {}", entry.name, SqliteError::new(e, None)));
+                let width = content.rows.iter().map(|v| v.values.len()).max().unwrap_or(0).max(1);
+                let columns: Vec<String> = (0..width).map(|i| format!("c{}", i)).collect();
+                let create = format!("CREATE TABLE {} ({});", quote_identifier(&entry.name), columns.join(", "));
+                recovered.conn.execute(&create).map_err(|e| SqliteError::new(e, Some(&create)))?;
+            }
+        }
+        contents.push((entry, content));
+    }
+
+    // Inserting into AUTOINCREMENT tables fills sqlite_sequence: its recovered rows go last and replace those
+    contents.sort_by_key(|(entry, _)| entry.name == "sqlite_sequence");
+    let schema = recovered.schema()?;
+    let mut lost_and_found = Vec::new();
+    for (entry, content) in contents {
+        let mut table = RecoveredTable {
+            name: entry.name.clone(),
+            root_page: entry.root_page,
+            rows: 0,
+            rejected_rows: 0,
+            truncated_rows: content.rows.iter().filter(|v| v.truncated).count(),
+            lost_cells: content.lost_cells,
+            lost_pages: content.lost_pages,
+        };
+        let rejected = match schema.table(&entry.name) {
+            Some(definition) => {
+                if entry.name == "sqlite_sequence" {
+                    clear_sequences(&recovered, &content.rows)?;
+                }
+                let (rows, rejected) = insert_rows(&recovered, definition, content.rows)?;
+                table.rows = rows;
+                rejected
+            }
+            None => content.rows,
+        };
+        table.rejected_rows = rejected.len();
+        lost_and_found.extend(rejected.into_iter().map(|row| (Some(entry.name.clone()), row)));
+        walker.report.tables.push(table);
+    }
+
+    // Created after the rows so triggers do not fire and indexes are built once
+    for entry in entries.iter().filter(|v| matches!(&v.kind[..], "index" | "view" | "trigger")) {
+        if let Some(sql) = &entry.sql {
+            if let Err(e) = recovered.conn.execute(sql) {
+                walker.report.schema_errors.push(format!("{}: {}", entry.name, SqliteError::new(e, None)));
+            }
+        }
+    }
+
+    let freelist: BTreeSet<u32> = match freelist_pages(&mut walker.db) {
+        Ok((trunks, leaves)) => trunks.union(&leaves).copied().collect(),
+        Err(_) => BTreeSet::new(),
+    };
+    lost_and_found.extend(walker.orphan_rows(&freelist).into_iter().map(|row| (None, row)));
+
+    let mut name = LOST_AND_FOUND.to_string();
+    let mut suffix = 0;
+    while entries.iter().any(|v| v.name.eq_ignore_ascii_case(&name)) {
+        name = format!("{}_{}", LOST_AND_FOUND, suffix);
+        suffix += 1;
+    }
+    walker.report.lost_and_found_rows = insert_lost_and_found(&recovered, &name, lost_and_found)?;
+    walker.report.lost_and_found = name;
+    let mut report = walker.report;
+    report.salvaged_pages.sort_unstable();
+    report.lost_pages.sort_by_key(|v| v.page);
+    Ok((recovered, report))
+}
+
+/// A row of a table read from a leaf page
+struct Row {
+    page: u32,
+    /// None for the rows of WITHOUT ROWID tables
+    rowid: Option<i64>,
+    values: Vec<ColumnValue>,
+    truncated: bool,
+}
+
+/// Rows and losses of a b-tree
+#[derive(Default)]
+struct BtreeContent {
+    rows: Vec<Row>,
+    lost_cells: usize,
+    lost_pages: usize,
+}
+
+/// A row of `sqlite_schema`
+struct SchemaRow {
+    kind: String,
+    name: String,
+    root_page: u32,
+    sql: Option<String>,
+}
+
+impl SchemaRow {
+    fn from_row(row: Row) -> Option<SchemaRow> {
+        let mut values = row.values.into_iter();
+        let kind = match values.next()? {
+            ColumnValue::String(v) => v,
+            _ => return None,
+        };
+        let name = match values.next()? {
+            ColumnValue::String(v) => v,
+            _ => return None,
+        };
+        let root_page = match values.nth(1) {
+            Some(ColumnValue::Integer(v)) => v as u32,
+            _ => 0,
+        };
+        let sql = match values.next() {
+            Some(ColumnValue::String(v)) if !v.is_empty() => Some(v),
+            _ => None,
+        };
+        Some(SchemaRow { kind, name, root_page, sql })
+    }
+}
+
+struct Walker<'a, R: Read + Seek + ?Sized> {
+    db: DbFile<'a, R>,
+    /// Pages already read as part of a b-tree or an overflow chain. Protects against loops and pages shared by two trees.
+    claimed: BTreeSet<u32>,
+    report: RecoveryReport,
+}
+
+impl<'a, R: Read + Seek + ?Sized> Walker<'a, R> {
+    /// Reads every row of the b-tree with the given root. WITHOUT ROWID tables are index b-trees.
+    fn walk(&mut self, root: u32, object: &str, index: bool) -> BtreeContent {
+        let mut content = BtreeContent::default();
+        let mut pending = vec![root];
+        while let Some(number) = pending.pop() {
+            let page = match self.read_btree_page(number, index) {
+                Ok(v) => v,
+                Err(reason) => {
+                    content.lost_pages += 1;
+                    self.lose(number, object, reason);
+                    continue;
+                }
+            };
+            self.report.salvaged_pages.push(number);
+            content.lost_cells += page.invalid_cells.len();
+            // Interior cells of an index b-tree hold keys too: rows of WITHOUT ROWID tables
+            if page.page_type != PageType::InteriorTable {
+                for cell in &page.cells {
+                    match self.cell_row(number, cell, object) {
+                        Some(row) => content.rows.push(row),
+                        None => content.lost_cells += 1,
+                    }
+                }
+            }
+            // Left to right
+            pending.extend(page.children().into_iter().rev());
+        }
+        content
+    }
+
+    fn read_btree_page(&mut self, number: u32, index: bool) -> Result<BtreePage, String> {
+        if number == 0 || number > self.db.page_count {
+            return Err(format!("Page {} is beyond the end of the file", number));
+        }
+        if !self.claimed.insert(number) {
+            return Err("Page already read in another b-tree or overflow chain".into());
+        }
+        let data = self.db.read_page(number).map_err(|_| "Page cannot be read".to_string())?;
+        let page = parse_btree_page(number, &data, self.db.usable_size)?;
+        if page.page_type.is_table() == index {
+            return Err(format!("{:?} page in {} b-tree", page.page_type, if index { "an index" } else { "a table" }));
+        }
+        Ok(page)
+    }
+
+    fn cell_row(&mut self, number: u32, cell: &Cell, object: &str) -> Option<Row> {
+        let (payload, cut) = self.payload(cell, object);
+        let record = decode_record(&payload).ok()?;
+        Some(Row {
+            page: number,
+            rowid: cell.rowid,
+            truncated: cut || record.truncated,
+            values: record.values,
+        })
+    }
+
+    /// Payload of a cell with its overflow chain. Returns whether the chain was cut before the end of the payload.
+    fn payload(&mut self, cell: &Cell, object: &str) -> (Vec<u8>, bool) {
+        let mut payload = cell.payload.clone();
+        let first = match cell.overflow_page {
+            Some(v) => v,
+            None => return (payload, false),
+        };
+        let remaining = (cell.payload_size as usize).saturating_sub(payload.len());
+        let (data, chain, broken) = follow_overflow(&mut self.db, first, remaining, &mut self.claimed);
+        payload.extend_from_slice(&data);
+        self.report.salvaged_pages.extend(chain);
+        match broken {
+            // A chain that ends early has no page to report
+            Some(broken) if broken.page != 0 => self.lose(broken.page, object, broken.reason),
+            Some(_) => {}
+            None => return (payload, false),
+        }
+        (payload, true)
+    }
+
+    /// Rows of the table leaf pages that were not read from any b-tree and are not free, like the leaves below a lost interior page
+    fn orphan_rows(&mut self, freelist: &BTreeSet<u32>) -> Vec<Row> {
+        let mut rows = Vec::new();
+        for number in 1..=self.db.page_count {
+            if self.claimed.contains(&number) || freelist.contains(&number) {
+                continue;
+            }
+            let page = match self.db.read_page(number).ok().and_then(|v| parse_btree_page(number, &v, self.db.usable_size).ok()) {
+                Some(v) if v.page_type == PageType::LeafTable && !v.cells.is_empty() => v,
+                _ => continue,
+            };
+            self.claimed.insert(number);
+            let found = rows.len();
+            for cell in &page.cells {
+                if let Some(row) = self.cell_row(number, cell, LOST_AND_FOUND) {
+                    rows.push(row);
+                }
+            }
+            if rows.len() > found {
+                self.report.orphan_pages.push(number);
+            }
+        }
+        rows
+    }
+
+    fn lose(&mut self, page: u32, object: &str, reason: String) {
+        self.report.lost_pages.push(LostPage {
+            page,
+            object: object.to_string(),
+            reason,
+        });
+    }
+}
+
+/// Inserts the rows into a rebuilt table. Returns the number of rows inserted and the rows the table refused.
+fn insert_rows(db: &SqliteDB, table: &SchemaTable, rows: Vec<Row>) -> ForensicResult<(usize, Vec<Row>)> {
+    let columns = &table.columns;
+    // Records of WITHOUT ROWID tables start with the primary key
+    let order: Vec<usize> = if table.without_rowid {
+        let mut keys: Vec<usize> = (0..columns.len()).filter(|i| columns[*i].primary_key > 0).collect();
+        keys.sort_by_key(|i| columns[*i].primary_key);
+        keys.into_iter().chain((0..columns.len()).filter(|i| columns[*i].primary_key == 0)).collect()
+    } else {
+        (0..columns.len()).collect()
+    };
+    // One statement per record width: columns added later with ALTER TABLE are missing from older records and take their default
+    let mut statements: BTreeMap<usize, SqliteStatement<'_>> = BTreeMap::new();
+    let mut inserted = 0;
+    let mut rejected = Vec::new();
+    for row in rows {
+        let width = row.values.len();
+        if width > order.len() {
+            rejected.push(row);
+            continue;
+        }
+        let statement = match statements.entry(width) {
+            Entry::Occupied(v) => v.into_mut(),
+            Entry::Vacant(v) => {
+                let mut names: Vec<String> = order[..width].iter().map(|i| quote_identifier(&columns[*i].name)).collect();
+                if let Some(rowid) = table.rowid_name() {
+                    names.insert(0, rowid.into());
+                }
+                let insert = format!(
+                    "INSERT INTO {} ({}) VALUES ({});",
+                    quote_identifier(&table.name),
+                    names.join(", "),
+                    vec!["?"; names.len()].join(", ")
+                );
+                v.insert(db.try_statement(&insert)?)
+            }
+        };
+        let mut index = 1;
+        if table.rowid_name().is_some() {
+            statement.bind(index, &row.rowid)?;
+            index += 1;
+        }
+        for (value, column) in row.values.iter().zip(order.iter()) {
+            match (value, row.rowid) {
+                // The record stores NULL for the INTEGER PRIMARY KEY, its value is the rowid
+                (ColumnValue::Null, Some(rowid)) if columns[*column].rowid_alias => statement.bind(index, &rowid)?,
+                _ => statement.bind(index, value)?,
+            }
+            index += 1;
+        }
+        match statement.try_next() {
+            Ok(_) => inserted += 1,
+            Err(_) => rejected.push(row),
+        }
+        // The error of a refused row is returned again by reset
+        let _ = statement.reset();
+    }
+    Ok((inserted, rejected))
+}
+
+/// Removes the rows SQLite wrote in sqlite_sequence for the tables with a recovered sequence
+fn clear_sequences(db: &SqliteDB, rows: &[Row]) -> ForensicResult<()> {
+    let mut statement = db.try_statement("DELETE FROM sqlite_sequence WHERE name = ?;")?;
+    for name in rows.iter().filter_map(|v| v.values.first()) {
+        statement.bind(1, name)?;
+        statement.try_next()?;
+        statement.reset()?;
+    }
+    Ok(())
+}
+
+/// Creates the lost and found table and inserts the rows. Returns the number of rows inserted.
+fn insert_lost_and_found(db: &SqliteDB, name: &str, rows: Vec<(Option<String>, Row)>) -> ForensicResult<usize> {
+    let width = rows.iter().map(|(_, row)| row.values.len()).max().unwrap_or(0);
+    let mut columns: Vec<String> = LOST_AND_FOUND_COLUMNS.iter().map(|v| quote_identifier(v)).collect();
+    columns.extend((0..width).map(|i| quote_identifier(&format!("c{}", i))));
+    let create = format!("CREATE TABLE {} ({});", quote_identifier(name), columns.join(", "));
+    db.conn.execute(&create).map_err(|e| SqliteError::new(e, Some(&create)))?;
+    let insert = format!("INSERT INTO {} VALUES ({});", quote_identifier(name), vec!["?"; columns.len()].join(", "));
+    let mut statement = db.try_statement(&insert)?;
+    let mut inserted = 0;
+    for (table, row) in rows {
+        statement.bind(1, &row.page)?;
+        statement.bind(2, &table)?;
+        statement.bind(3, &row.rowid)?;
+        for i in 0..width {
+            match row.values.get(i) {
+                Some(value) => statement.bind(4 + i, value)?,
+                None => statement.bind(4 + i, &ColumnValue::Null)?,
+            }
+        }
+        statement.try_next()?;
+        statement.reset()?;
+        inserted += 1;
+    }
+    Ok(inserted)
+}
@@ -0,0 +1,401 @@
+//! Schema of the database stored in `sqlite_schema`
+use forensic_rs::{prelude::ForensicResult, traits::sql::SqlDb};
+
+use crate::SqliteDB;
+
+/// Type affinity of a column. https://www.sqlite.org/datatype3.html#type_affinity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    /// Affinity of a declared type following the rules of SQLite
+    pub fn from_declared_type(declared: &str) -> Affinity {
+        let declared = declared.to_uppercase();
+        if declared.contains("INT") {
+            Affinity::Integer
+        } else if declared.contains("CHAR") || declared.contains("CLOB") || declared.contains("TEXT") {
+            Affinity::Text
+        } else if declared.contains("BLOB") || declared.trim().is_empty() {
+            Affinity::Blob
+        } else if declared.contains("REAL") || declared.contains("FLOA") || declared.contains("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+}
+
+/// A column of a table or view
+#[derive(Debug, Clone)]
+pub struct SchemaColumn {
+    pub name: String,
+    pub declared_type: String,
+    pub affinity: Affinity,
+    /// Position of the column in the primary key starting at 1, 0 when it is not part of it
+    pub primary_key: u32,
+    pub not_null: bool,
+    /// Default value as the SQL expression written in the schema
+    pub default_value: Option<String>,
+    /// INTEGER PRIMARY KEY of a rowid table: the value is the rowid and the record stores NULL
+    pub rowid_alias: bool,
+}
+
+/// A table and its columns
+#[derive(Debug, Clone)]
+pub struct SchemaTable {
+    pub name: String,
+    pub columns: Vec<SchemaColumn>,
+    /// Root page of the table b-tree
+    pub root_page: u32,
+    pub without_rowid: bool,
+    pub sql: String,
+}
+
+impl SchemaTable {
+    /// Name that refers to the rowid in SQL: the first of `rowid`, `_rowid_` and `oid` that is not a column of the table.
+    /// None for WITHOUT ROWID tables, or when the three are columns and the rowid cannot be read.
+    pub fn rowid_name(&self) -> Option<&'static str> {
+        if self.without_rowid {
+            return None;
+        }
+        ["rowid", "_rowid_", "oid"]
+            .into_iter()
+            .find(|name| !self.columns.iter().any(|v| v.name.eq_ignore_ascii_case(name)))
+    }
+}
+
+/// A virtual table. Its rows live in the shadow tables of the module, if any.
+#[derive(Debug, Clone)]
+pub struct SchemaVirtualTable {
+    pub name: String,
+    /// Module after `USING`, like fts5 or rtree
+    pub module: String,
+    /// Arguments of the module, as written between the parentheses
+    pub arguments: String,
+    /// Empty when the module is not available to describe the table
+    pub columns: Vec<SchemaColumn>,
+    pub sql: String,
+}
+
+/// An index of a table
+#[derive(Debug, Clone)]
+pub struct SchemaIndex {
+    pub name: String,
+    pub table: String,
+    /// Indexed columns. Expressions have no name and are listed as None.
+    pub columns: Vec<Option<String>>,
+    pub unique: bool,
+    pub root_page: u32,
+    /// None for the indexes created by UNIQUE and PRIMARY KEY constraints
+    pub sql: Option<String>,
+}
+
+/// A view and the columns it returns
+#[derive(Debug, Clone)]
+pub struct SchemaView {
+    pub name: String,
+    /// Empty when the view cannot be described, like views calling functions that are not registered
+    pub columns: Vec<SchemaColumn>,
+    pub sql: String,
+}
+
+/// A trigger of a table or view
+#[derive(Debug, Clone)]
+pub struct SchemaTrigger {
+    pub name: String,
+    pub table: String,
+    pub sql: String,
+}
+
+/// Every object of `sqlite_schema`, internal `sqlite_` ones included
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    pub tables: Vec<SchemaTable>,
+    pub virtual_tables: Vec<SchemaVirtualTable>,
+    pub indexes: Vec<SchemaIndex>,
+    pub views: Vec<SchemaView>,
+    pub triggers: Vec<SchemaTrigger>,
+}
+
+impl Schema {
+    pub fn table(&self, name: &str) -> Option<&SchemaTable> {
+        self.tables.iter().find(|v| v.name.eq_ignore_ascii_case(name))
+    }
+    /// Raw `sql` of any object of the schema
+    pub fn sql(&self, name: &str) -> Option<&str> {
+        let name_eq = |v: &str| v.eq_ignore_ascii_case(name);
+        self.tables.iter().find(|v| name_eq(&v.name)).map(|v| &v.sql[..])
+            .or_else(|| self.virtual_tables.iter().find(|v| name_eq(&v.name)).map(|v| &v.sql[..]))
+            .or_else(|| self.indexes.iter().find(|v| name_eq(&v.name)).and_then(|v| v.sql.as_deref()))
+            .or_else(|| self.views.iter().find(|v| name_eq(&v.name)).map(|v| &v.sql[..]))
+            .or_else(|| self.triggers.iter().find(|v| name_eq(&v.name)).map(|v| &v.sql[..]))
+    }
+}
+
+/// A row of `sqlite_schema`
+struct SchemaEntry {
+    kind: String,
+    name: String,
+    table: String,
+    root_page: u32,
+    sql: Option<String>,
+}
+
+impl SqliteDB {
+    /// Every table, virtual table, index, view and trigger of the database with its raw `sql`
+    pub fn schema(&self) -> ForensicResult<Schema> {
+        let mut entries = Vec::with_capacity(32);
+        let mut sts = self.prepare(r#"SELECT
+        type, name, tbl_name, rootpage, sql
+    FROM
+        sqlite_schema;"#)?;
+        while sts.next()? {
+            entries.push(SchemaEntry {
+                kind: sts.read(0)?.try_into()?,
+                name: sts.read(1)?.try_into()?,
+                table: sts.read(2)?.try_into().unwrap_or_default(),
+                root_page: sts.read(3)?.try_into().map(|v: i64| v as u32).unwrap_or(0),
+                sql: sts.read(4)?.try_into().ok().filter(|v: &String| !v.is_empty()),
+            });
+        }
+        drop(sts);
+        let mut schema = Schema::default();
+        for entry in entries {
+            let sql = entry.sql.clone().unwrap_or_default();
+            match &entry.kind[..] {
+                "table" => match parse_virtual_table(&sql) {
+                    Some((module, arguments)) => schema.virtual_tables.push(SchemaVirtualTable {
+                        // The module may not be available to describe the table
+                        columns: self.table_columns(&entry.name, false).unwrap_or_default(),
+                        name: entry.name,
+                        module,
+                        arguments,
+                        sql,
+                    }),
+                    None => {
+                        let without_rowid = is_without_rowid(&sql);
+                        schema.tables.push(SchemaTable {
+                            // A table SQLite cannot describe must not hide the rest of the schema
+                            columns: self.table_columns(&entry.name, without_rowid).unwrap_or_default(),
+                            name: entry.name,
+                            root_page: entry.root_page,
+                            without_rowid,
+                            sql,
+                        });
+                    }
+                },
+                "index" => schema.indexes.push(SchemaIndex {
+                    columns: self.index_columns(&entry.name)?,
+                    // Indexes without sql come from UNIQUE and PRIMARY KEY constraints
+                    unique: entry.sql.as_deref().map(is_unique_index).unwrap_or(true),
+                    name: entry.name,
+                    table: entry.table,
+                    root_page: entry.root_page,
+                    sql: entry.sql,
+                }),
+                "view" => schema.views.push(SchemaView {
+                    columns: self.table_columns(&entry.name, false).unwrap_or_default(),
+                    name: entry.name,
+                    sql,
+                }),
+                "trigger" => schema.triggers.push(SchemaTrigger {
+                    name: entry.name,
+                    table: entry.table,
+                    sql,
+                }),
+                _ => {}
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Tables of the database with their columns, internal `sqlite_` tables and virtual tables excluded
+    pub fn schema_tables(&self) -> ForensicResult<Vec<SchemaTable>> {
+        Ok(self
+            .schema()?
+            .tables
+            .into_iter()
+            .filter(|v| !v.name.starts_with("sqlite_"))
+            .collect())
+    }
+
+    fn table_columns(&self, table: &str, without_rowid: bool) -> ForensicResult<Vec<SchemaColumn>> {
+        let query = format!("PRAGMA table_info(\"{}\");", table.replace('"', "\"\""));
+        let mut sts = self.prepare(&query)?;
+        let mut columns = Vec::new();
+        while sts.next()? {
+            let name: String = sts.read(1)?.try_into()?;
+            let declared_type: String = sts.read(2)?.try_into().unwrap_or_default();
+            let not_null: i64 = sts.read(3)?.try_into().unwrap_or(0);
+            let pk: i64 = sts.read(5)?.try_into().unwrap_or(0);
+            columns.push(SchemaColumn {
+                affinity: Affinity::from_declared_type(&declared_type),
+                primary_key: pk as u32,
+                not_null: not_null != 0,
+                default_value: sts.read(4)?.try_into().ok().filter(|v: &String| !v.is_empty()),
+                rowid_alias: false,
+                name,
+                declared_type,
+            });
+        }
+        let primary_keys = columns.iter().filter(|v| v.primary_key > 0).count();
+        for column in columns.iter_mut() {
+            column.rowid_alias = column.primary_key > 0
+                && primary_keys == 1
+                && !without_rowid
+                && column.declared_type.eq_ignore_ascii_case("INTEGER");
+        }
+        Ok(columns)
+    }
+
+    fn index_columns(&self, index: &str) -> ForensicResult<Vec<Option<String>>> {
+        let query = format!("PRAGMA index_info(\"{}\");", index.replace('"', "\"\""));
+        let mut sts = self.prepare(&query)?;
+        let mut columns = Vec::new();
+        while sts.next()? {
+            columns.push(sts.read(2)?.try_into().ok().filter(|v: &String| !v.is_empty()));
+        }
+        Ok(columns)
+    }
+}
+
+fn sql_words(sql: &str) -> Vec<String> {
+    sql.to_uppercase().split_whitespace().map(|v| v.to_string()).collect()
+}
+
+pub(crate) fn is_without_rowid(sql: &str) -> bool {
+    sql_words(sql)
+        .windows(2)
+        .any(|v| v[0] == "WITHOUT" && v[1].starts_with("ROWID"))
+}
+
+fn is_unique_index(sql: &str) -> bool {
+    sql_words(sql).get(1).map(|v| v == "UNIQUE").unwrap_or(false)
+}
+
+/// Byte offset after the USING keyword: the first unquoted word USING, whatever whitespace or quote comes before it
+fn using_end(sql: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    let mut chars = sql.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            // A doubled quote inside a name is read as two quoted names, which skips the same text
+            '"' | '\'' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                for (_, v) in chars.by_ref() {
+                    if v == close {
+                        break;
+                    }
+                }
+            }
+            c if is_word(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, v)) = chars.next_if(|(_, v)| is_word(*v)) {
+                    end = i + v.len_utf8();
+                }
+                if sql[start..end].eq_ignore_ascii_case("USING") {
+                    return Some(end);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Module and arguments of a `CREATE VIRTUAL TABLE ... USING module(arguments)` statement
+pub(crate) fn parse_virtual_table(sql: &str) -> Option<(String, String)> {
+    let words = sql_words(sql);
+    if words.first()? != "CREATE" || words.get(1)? != "VIRTUAL" {
+        return None;
+    }
+    let rest = sql[using_end(sql)?..].trim_start();
+    let end = rest
+        .find(|c: char| c == '(' || c == ';' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    let module = rest[..end].to_string();
+    let arguments = match (rest.find('('), rest.rfind(')')) {
+        (Some(start), Some(end)) if start < end => rest[start + 1..end].trim().to_string(),
+        _ => String::new(),
+    };
+    Some((module, arguments))
+}
+
+#[cfg(test)]
+mod test_schema {
+    use super::*;
+
+    #[test]
+    fn should_compute_affinity() {
+        assert_eq!(Affinity::Integer, Affinity::from_declared_type("BIGINT"));
+        assert_eq!(Affinity::Text, Affinity::from_declared_type("VARCHAR(255)"));
+        assert_eq!(Affinity::Blob, Affinity::from_declared_type(""));
+        assert_eq!(Affinity::Real, Affinity::from_declared_type("DOUBLE PRECISION"));
+        assert_eq!(Affinity::Numeric, Affinity::from_declared_type("DATETIME"));
+        // "POINT" contains "INT"
+        assert_eq!(Affinity::Integer, Affinity::from_declared_type("FLOATING POINT"));
+    }
+
+    #[test]
+    fn should_parse_virtual_table_module() {
+        assert_eq!(
+            Some(("fts5".to_string(), "title, body, tokenize = 'porter'".to_string())),
+            parse_virtual_table("CREATE VIRTUAL TABLE docs USING fts5(title, body, tokenize = 'porter')")
+        );
+        assert_eq!(
+            Some(("dbstat".to_string(), String::new())),
+            parse_virtual_table("create virtual table if not exists stats using dbstat")
+        );
+        assert_eq!(
+            Some(("fts5".to_string(), "body".to_string())),
+            parse_virtual_table("CREATE VIRTUAL TABLE docs\nUSING fts5(body)")
+        );
+        assert_eq!(
+            Some(("rtree".to_string(), "id, x0, x1".to_string())),
+            parse_virtual_table("CREATE VIRTUAL TABLE \"using\"\tUSING rtree(id, x0, x1)")
+        );
+        assert_eq!(None, parse_virtual_table("CREATE TABLE using_table (a)"));
+    }
+
+    #[test]
+    fn should_describe_schema() {
+        let db = SqliteDB::new(sqlite::open(":memory:").unwrap());
+        db.conn
+            .execute(
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL DEFAULT 'nobody', email VARCHAR(255) UNIQUE);
+                CREATE INDEX users_name ON users (name, lower(email));
+                CREATE VIEW names AS SELECT name FROM users;
+                CREATE TRIGGER users_insert AFTER INSERT ON users BEGIN SELECT 1; END;
+                CREATE TABLE pairs (a, b, PRIMARY KEY (a, b)) WITHOUT ROWID;",
+            )
+            .unwrap();
+        let schema = db.schema().unwrap();
+        let users = schema.table("users").unwrap();
+        assert!(users.columns[0].rowid_alias);
+        assert_eq!(1, users.columns[0].primary_key);
+        assert!(users.columns[1].not_null);
+        assert_eq!(Some("'nobody'".to_string()), users.columns[1].default_value);
+        assert_eq!(None, users.columns[2].default_value);
+        assert!(users.sql.starts_with("CREATE TABLE users"));
+        assert!(schema.table("pairs").unwrap().without_rowid);
+
+        let index = schema.indexes.iter().find(|v| v.name == "users_name").unwrap();
+        assert_eq!("users", index.table);
+        assert_eq!(vec![Some("name".to_string()), None], index.columns);
+        assert!(!index.unique);
+        let auto = schema.indexes.iter().find(|v| v.name.starts_with("sqlite_autoindex_users")).unwrap();
+        assert!(auto.unique);
+        assert!(auto.sql.is_none());
+
+        assert_eq!("name", schema.views[0].columns[0].name);
+        assert_eq!("users", schema.triggers[0].table);
+        assert!(schema.sql("users_insert").unwrap().contains("AFTER INSERT"));
+        assert_eq!(2, db.schema_tables().unwrap().len());
+    }
+}
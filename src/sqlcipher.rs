@@ -0,0 +1,310 @@
+//! Decryption of SQLCipher databases. https://www.zetetic.net/sqlcipher/design/
+//!
+//! Every page is encrypted with AES-256-CBC. The end of each page is reserved for the IV and the HMAC of the encrypted data.
+//! The first 16 bytes of the file are the salt of the key derivation, unless the header is kept in plain text.
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt},
+    Aes256,
+};
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+use hmac::{digest::KeyInit, Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use crate::page::SQLITE_MAGIC;
+
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const IV_SIZE: usize = 16;
+const BLOCK_SIZE: usize = 16;
+/// Bytes of page 1 not encrypted: the salt, replaced by the SQLite magic once decrypted
+const FILE_HEADER_SIZE: usize = 16;
+/// Iterations of the derivation of the HMAC key from the encryption key
+const FAST_KDF_ITERATIONS: u32 = 2;
+/// XORed with the salt to get the salt of the HMAC key
+const HMAC_SALT_MASK: u8 = 0x3a;
+
+/// Hash used by the key derivation and the page HMAC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlCipherHash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl SqlCipherHash {
+    fn size(&self) -> usize {
+        match self {
+            SqlCipherHash::Sha1 => 20,
+            SqlCipherHash::Sha256 => 32,
+            SqlCipherHash::Sha512 => 64,
+        }
+    }
+}
+
+/// Key of a SQLCipher database
+#[derive(Clone)]
+pub enum SqlCipherKey {
+    /// Passphrase, derived with PBKDF2 and the salt of the file
+    Passphrase(Vec<u8>),
+    /// Raw 256-bit key, as used with `PRAGMA key = "x'...'"`. The salt is needed when the header is kept in plain text.
+    Raw { key: [u8; KEY_SIZE], salt: Option<[u8; SALT_SIZE]> },
+}
+
+impl SqlCipherKey {
+    /// Parses a key as written in `PRAGMA key`: a passphrase, or `x'...'` with 64 hex digits for a raw key or 96 for a raw key and its salt
+    pub fn parse(key: &str) -> ForensicResult<SqlCipherKey> {
+        let hex = match key.strip_prefix("x'").or_else(|| key.strip_prefix("X'")).and_then(|v| v.strip_suffix('\'')) {
+            Some(v) => v,
+            None => return Ok(SqlCipherKey::Passphrase(key.as_bytes().to_vec())),
+        };
+        let bytes = decode_hex(hex).ok_or_else(|| ForensicError::Other("The raw SQLCipher key is not hexadecimal".into()))?;
+        match bytes.len() {
+            KEY_SIZE => Ok(SqlCipherKey::Raw {
+                key: to_array(&bytes),
+                salt: None,
+            }),
+            48 => Ok(SqlCipherKey::Raw {
+                key: to_array(&bytes[..KEY_SIZE]),
+                salt: Some(to_array(&bytes[KEY_SIZE..])),
+            }),
+            n => Err(ForensicError::Other(format!("A raw SQLCipher key has 32 or 48 bytes, not {}", n))),
+        }
+    }
+}
+
+/// Cipher settings of a database, the `PRAGMA cipher_*` values used to create it
+#[derive(Debug, Clone)]
+pub struct SqlCipherParams {
+    pub page_size: u32,
+    pub kdf_iterations: u32,
+    pub kdf_algorithm: SqlCipherHash,
+    /// None when the pages have no HMAC, like SQLCipher 1.x
+    pub hmac_algorithm: Option<SqlCipherHash>,
+    /// Bytes at the start of the file kept in plain text, 0 when the salt is stored there
+    pub plaintext_header_size: u32,
+}
+
+impl SqlCipherParams {
+    /// Defaults of SQLCipher 1.x
+    pub fn v1() -> SqlCipherParams {
+        SqlCipherParams {
+            page_size: 1024,
+            kdf_iterations: 4000,
+            kdf_algorithm: SqlCipherHash::Sha1,
+            hmac_algorithm: None,
+            plaintext_header_size: 0,
+        }
+    }
+    /// Defaults of SQLCipher 2.x
+    pub fn v2() -> SqlCipherParams {
+        SqlCipherParams {
+            hmac_algorithm: Some(SqlCipherHash::Sha1),
+            ..Self::v1()
+        }
+    }
+    /// Defaults of SQLCipher 3.x
+    pub fn v3() -> SqlCipherParams {
+        SqlCipherParams {
+            kdf_iterations: 64000,
+            ..Self::v2()
+        }
+    }
+    /// Defaults of SQLCipher 4.x
+    pub fn v4() -> SqlCipherParams {
+        SqlCipherParams {
+            page_size: 4096,
+            kdf_iterations: 256000,
+            kdf_algorithm: SqlCipherHash::Sha512,
+            hmac_algorithm: Some(SqlCipherHash::Sha512),
+            plaintext_header_size: 0,
+        }
+    }
+
+    /// Bytes at the end of each page for the IV and the HMAC, rounded up to the AES block size
+    pub fn reserve_size(&self) -> usize {
+        let reserve = IV_SIZE + self.hmac_algorithm.map(|v| v.size()).unwrap_or(0);
+        reserve.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+    }
+}
+
+/// Decrypts the pages of a database with derived keys
+pub(crate) struct PageCipher {
+    params: SqlCipherParams,
+    cipher: Aes256,
+    hmac_key: [u8; KEY_SIZE],
+}
+
+impl PageCipher {
+    /// Derives the keys. `header` is the start of the file, where the salt is unless it is in plain text.
+    pub(crate) fn new(key: &SqlCipherKey, params: &SqlCipherParams, header: &[u8]) -> ForensicResult<PageCipher> {
+        let page_size = params.page_size as usize;
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() || params.reserve_size() >= page_size {
+            return Err(ForensicError::Other(format!("Invalid SQLCipher page size {}", page_size)));
+        }
+        let file_salt = match header.get(..SALT_SIZE) {
+            Some(v) if params.plaintext_header_size == 0 => Some(to_array(v)),
+            _ => None,
+        };
+        let (key, salt) = match key {
+            SqlCipherKey::Raw { key, salt } => (*key, salt.or(file_salt)),
+            SqlCipherKey::Passphrase(passphrase) => {
+                let salt = match file_salt {
+                    Some(v) => v,
+                    None => return Err(ForensicError::Other("A raw key with its salt is needed when the header is in plain text".into())),
+                };
+                (pbkdf2(params.kdf_algorithm, passphrase, &salt, params.kdf_iterations), Some(salt))
+            }
+        };
+        let hmac_key = match (params.hmac_algorithm, salt) {
+            (None, _) => [0u8; KEY_SIZE],
+            (Some(_), Some(salt)) => {
+                let hmac_salt: Vec<u8> = salt.iter().map(|v| v ^ HMAC_SALT_MASK).collect();
+                pbkdf2(params.kdf_algorithm, &key, &hmac_salt, FAST_KDF_ITERATIONS)
+            }
+            (Some(_), None) => return Err(ForensicError::Other("The salt of the database is needed to verify the pages".into())),
+        };
+        Ok(PageCipher {
+            params: params.clone(),
+            cipher: <Aes256 as KeyInit>::new(GenericArray::from_slice(&key)),
+            hmac_key,
+        })
+    }
+
+    pub(crate) fn page_size(&self) -> usize {
+        self.params.page_size as usize
+    }
+
+    /// Decrypts a page in place. Pages are numbered from 1. The reserved bytes are left as they are, SQLite ignores them.
+    pub(crate) fn decrypt_page(&self, page_number: u32, page: &mut [u8]) -> ForensicResult<()> {
+        let page_size = self.page_size();
+        if page.len() != page_size {
+            return Err(ForensicError::Other(format!("A SQLCipher page has {} bytes, not {}", page_size, page.len())));
+        }
+        // Pages never written are zero filled, SQLCipher returns them as they are
+        if page.iter().all(|v| *v == 0) {
+            return Ok(());
+        }
+        let offset = match (page_number, self.params.plaintext_header_size as usize) {
+            (1, 0) => FILE_HEADER_SIZE,
+            (1, v) => v,
+            _ => 0,
+        };
+        let end = page_size - self.params.reserve_size();
+        let iv: [u8; IV_SIZE] = to_array(&page[end..end + IV_SIZE]);
+        if let Some(hash) = self.params.hmac_algorithm {
+            let expected = page_hmac(hash, &self.hmac_key, &page[offset..end + IV_SIZE], page_number);
+            if page[end + IV_SIZE..end + IV_SIZE + hash.size()] != expected[..] {
+                return Err(ForensicError::Other(format!("HMAC check failed on page {}: wrong key, wrong cipher settings or tampered page", page_number)));
+            }
+        }
+        if !(end - offset).is_multiple_of(BLOCK_SIZE) {
+            return Err(ForensicError::Other(format!("Page {} cannot be split in AES blocks", page_number)));
+        }
+        let mut previous = iv;
+        for block in page[offset..end].chunks_exact_mut(BLOCK_SIZE) {
+            let encrypted: [u8; BLOCK_SIZE] = to_array(block);
+            self.cipher.decrypt_block(GenericArray::from_mut_slice(block));
+            for (byte, chain) in block.iter_mut().zip(previous) {
+                *byte ^= chain;
+            }
+            previous = encrypted;
+        }
+        if page_number == 1 && self.params.plaintext_header_size == 0 {
+            page[..FILE_HEADER_SIZE].copy_from_slice(SQLITE_MAGIC);
+        }
+        Ok(())
+    }
+}
+
+fn pbkdf2(hash: SqlCipherHash, password: &[u8], salt: &[u8], rounds: u32) -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    match hash {
+        SqlCipherHash::Sha1 => pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, rounds, &mut key),
+        SqlCipherHash::Sha256 => pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, rounds, &mut key),
+        SqlCipherHash::Sha512 => pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, rounds, &mut key),
+    }
+    key
+}
+
+/// HMAC of the encrypted data and the IV, followed by the page number in little endian
+fn page_hmac(hash: SqlCipherHash, key: &[u8], data: &[u8], page_number: u32) -> Vec<u8> {
+    let parts = [data, &page_number.to_le_bytes()[..]];
+    match hash {
+        SqlCipherHash::Sha1 => sign::<Hmac<Sha1>>(key, &parts),
+        SqlCipherHash::Sha256 => sign::<Hmac<Sha256>>(key, &parts),
+        SqlCipherHash::Sha512 => sign::<Hmac<Sha512>>(key, &parts),
+    }
+}
+
+fn sign<M: Mac + KeyInit>(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <M as KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn to_array<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut array = [0u8; N];
+    array.copy_from_slice(&data[..N]);
+    array
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+impl PageCipher {
+    /// Encrypts a page in place the way SQLCipher writes it, to build encrypted databases in tests
+    pub(crate) fn encrypt_page(&self, page_number: u32, page: &mut [u8], iv: [u8; IV_SIZE], salt: &[u8; SALT_SIZE]) {
+        use aes::cipher::BlockEncrypt;
+        let offset = if page_number == 1 { FILE_HEADER_SIZE } else { 0 };
+        let end = self.page_size() - self.params.reserve_size();
+        let mut previous = iv;
+        for block in page[offset..end].chunks_exact_mut(BLOCK_SIZE) {
+            for (byte, chain) in block.iter_mut().zip(previous) {
+                *byte ^= chain;
+            }
+            self.cipher.encrypt_block(GenericArray::from_mut_slice(block));
+            previous = to_array(block);
+        }
+        page[end..end + IV_SIZE].copy_from_slice(&iv);
+        if let Some(hash) = self.params.hmac_algorithm {
+            let mac = page_hmac(hash, &self.hmac_key, &page[offset..end + IV_SIZE], page_number);
+            page[end + IV_SIZE..end + IV_SIZE + mac.len()].copy_from_slice(&mac);
+        }
+        if page_number == 1 {
+            page[..SALT_SIZE].copy_from_slice(salt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sqlcipher {
+    use super::*;
+
+    #[test]
+    fn should_parse_keys() {
+        assert!(matches!(SqlCipherKey::parse("secret").unwrap(), SqlCipherKey::Passphrase(v) if v == b"secret"));
+        let raw = format!("x'{}'", "ab".repeat(32));
+        assert!(matches!(SqlCipherKey::parse(&raw).unwrap(), SqlCipherKey::Raw { key, salt: None } if key == [0xab; 32]));
+        let with_salt = format!("x'{}{}'", "ab".repeat(32), "01".repeat(16));
+        assert!(matches!(SqlCipherKey::parse(&with_salt).unwrap(), SqlCipherKey::Raw { salt: Some(salt), .. } if salt == [1; 16]));
+        assert!(SqlCipherKey::parse("x'abc'").is_err());
+    }
+
+    #[test]
+    fn should_compute_reserve_size() {
+        assert_eq!(16, SqlCipherParams::v1().reserve_size());
+        assert_eq!(48, SqlCipherParams::v3().reserve_size());
+        assert_eq!(80, SqlCipherParams::v4().reserve_size());
+    }
+}
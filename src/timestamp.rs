@@ -0,0 +1,286 @@
+//! Conversion of the timestamps stored by applications into UTC, whatever their epoch and unit
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use forensic_rs::{
+    prelude::{ForensicError, ForensicResult},
+    traits::sql::ColumnValue,
+};
+
+/// Seconds between 1601-01-01 and the Unix epoch
+const EPOCH_1601: i128 = 11_644_473_600;
+/// Seconds between the Unix epoch and 2001-01-01
+const EPOCH_2001: i128 = 978_307_200;
+/// Julian day of the Unix epoch
+const JULIAN_UNIX_EPOCH: f64 = 2_440_587.5;
+/// 2001-01-02, the oldest date the heuristic considers plausible. Small values are not dates of the formats with a 2001 epoch.
+const PLAUSIBLE_START: i128 = 978_393_600;
+const NANOS: i128 = 1_000_000_000;
+
+/// Epoch and unit of a stored timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampFormat {
+    UnixSeconds,
+    UnixMillis,
+    UnixMicros,
+    /// WebKit/Chrome: microseconds since 1601-01-01
+    WebKit,
+    /// Windows FILETIME: 100 nanosecond intervals since 1601-01-01
+    Filetime,
+    /// Julian day, usually a float
+    JulianDay,
+    /// Mac absolute time / Core Data: seconds since 2001-01-01
+    MacAbsolute,
+    /// Nanoseconds since 2001-01-01
+    MacAbsoluteNanos,
+}
+
+/// Formats in the order the heuristic prefers them when several fit
+const FORMATS: [TimestampFormat; 8] = [
+    TimestampFormat::UnixSeconds,
+    TimestampFormat::UnixMillis,
+    TimestampFormat::UnixMicros,
+    TimestampFormat::WebKit,
+    TimestampFormat::Filetime,
+    TimestampFormat::JulianDay,
+    TimestampFormat::MacAbsolute,
+    TimestampFormat::MacAbsoluteNanos,
+];
+
+impl TimestampFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimestampFormat::UnixSeconds => "unix_seconds",
+            TimestampFormat::UnixMillis => "unix_millis",
+            TimestampFormat::UnixMicros => "unix_micros",
+            TimestampFormat::WebKit => "webkit",
+            TimestampFormat::Filetime => "filetime",
+            TimestampFormat::JulianDay => "julian_day",
+            TimestampFormat::MacAbsolute => "mac_absolute",
+            TimestampFormat::MacAbsoluteNanos => "mac_absolute_nanos",
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch of a stored value
+    fn unix_nanos(&self, value: Number) -> Option<i128> {
+        let (scale, epoch) = match self {
+            TimestampFormat::UnixSeconds => (NANOS, 0),
+            TimestampFormat::UnixMillis => (1_000_000, 0),
+            TimestampFormat::UnixMicros => (1_000, 0),
+            TimestampFormat::WebKit => (1_000, -EPOCH_1601),
+            TimestampFormat::Filetime => (100, -EPOCH_1601),
+            TimestampFormat::MacAbsolute => (NANOS, EPOCH_2001),
+            TimestampFormat::MacAbsoluteNanos => (1, EPOCH_2001),
+            TimestampFormat::JulianDay => {
+                let days = match value {
+                    Number::Integer(v) => v as f64,
+                    Number::Float(v) => v,
+                };
+                return float_nanos((days - JULIAN_UNIX_EPOCH) * 86_400.0 * NANOS as f64);
+            }
+        };
+        let nanos = match value {
+            Number::Integer(v) => (v as i128).checked_mul(scale)?,
+            Number::Float(v) => float_nanos(v * scale as f64)?,
+        };
+        nanos.checked_add(epoch * NANOS)
+    }
+}
+
+/// A point in time in UTC and the format it was stored with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub format: TimestampFormat,
+    /// Nanoseconds since 1970-01-01 UTC, negative before it
+    pub unix_nanos: i128,
+}
+
+impl Timestamp {
+    /// Converts a value read from a statement. Integers, floats and numeric strings are accepted.
+    pub fn from_value(value: &ColumnValue, format: TimestampFormat) -> ForensicResult<Timestamp> {
+        let number = match Number::from_value(value) {
+            Some(v) => v,
+            None => return Err(ForensicError::Other("The value is not a numeric timestamp".into())),
+        };
+        match format.unix_nanos(number) {
+            Some(unix_nanos) => Ok(Timestamp { format, unix_nanos }),
+            None => Err(ForensicError::Other(format!("The value is out of range as {}", format.as_str()))),
+        }
+    }
+
+    /// Whole seconds since the Unix epoch, rounded down
+    pub fn unix_seconds(&self) -> i64 {
+        self.unix_nanos.div_euclid(NANOS) as i64
+    }
+
+    /// None for times before the Unix epoch on platforms that cannot represent them
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        if self.unix_nanos >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_nanos(u64::try_from(self.unix_nanos).ok()?))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_nanos(u64::try_from(-self.unix_nanos).ok()?))
+        }
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    /// RFC 3339 in UTC, like 2023-11-14T22:13:20.5Z
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.unix_nanos.div_euclid(NANOS);
+        let nanos = self.unix_nanos.rem_euclid(NANOS);
+        let days = seconds.div_euclid(86_400);
+        let time = seconds.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, time / 3600, (time / 60) % 60, time % 60)?;
+        if nanos != 0 {
+            let fraction = format!("{:09}", nanos);
+            write!(f, ".{}", fraction.trim_end_matches('0'))?;
+        }
+        write!(f, "Z")
+    }
+}
+
+/// A format suggested for a column and how well it fits
+#[derive(Debug, Clone, Copy)]
+pub struct EpochGuess {
+    pub format: TimestampFormat,
+    /// Share of the numeric values that become a plausible date, between 0 and 1
+    pub score: f32,
+}
+
+/// Formats that turn the values of a column into plausible dates, from 2001-01-02 to one year from now, best first.
+/// Zeros and non numeric values are ignored.
+pub fn rank_formats(values: &[ColumnValue]) -> Vec<EpochGuess> {
+    let numbers: Vec<Number> = values
+        .iter()
+        .filter_map(Number::from_value)
+        .filter(|v| !v.is_zero())
+        .collect();
+    if numbers.is_empty() {
+        return Vec::new();
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs() as i128)
+        .unwrap_or(0);
+    let plausible = PLAUSIBLE_START * NANOS..(now + 365 * 86_400) * NANOS;
+    let mut guesses: Vec<EpochGuess> = FORMATS
+        .iter()
+        .map(|format| {
+            let fits = numbers
+                .iter()
+                .filter(|v| format.unix_nanos(**v).map(|v| plausible.contains(&v)).unwrap_or(false))
+                .count();
+            EpochGuess {
+                format: *format,
+                score: fits as f32 / numbers.len() as f32,
+            }
+        })
+        .filter(|v| v.score > 0.0)
+        .collect();
+    // Stable: formats that fit equally keep the preferred order
+    guesses.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    guesses
+}
+
+/// Most likely format of the values of a column
+pub fn guess_format(values: &[ColumnValue]) -> Option<EpochGuess> {
+    rank_formats(values).into_iter().next()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn from_value(value: &ColumnValue) -> Option<Number> {
+        match value {
+            ColumnValue::Integer(v) => Some(Number::Integer(*v)),
+            ColumnValue::Float(v) => Some(Number::Float(*v)),
+            ColumnValue::String(v) => {
+                let v = v.trim();
+                v.parse::<i64>()
+                    .map(Number::Integer)
+                    .or_else(|_| v.parse::<f64>().map(Number::Float))
+                    .ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(v) => *v == 0,
+            Number::Float(v) => *v == 0.0,
+        }
+    }
+}
+
+fn float_nanos(value: f64) -> Option<i128> {
+    if value.is_finite() && value.abs() < 1e36 {
+        Some(value.round() as i128)
+    } else {
+        None
+    }
+}
+
+/// Year, month and day of a number of days since 1970-01-01. http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i128) -> (i128, i128, i128) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test_timestamp {
+    use super::*;
+
+    const EXPECTED: &str = "2023-11-14T22:13:20Z";
+
+    fn samples() -> Vec<(ColumnValue, TimestampFormat)> {
+        vec![
+            (ColumnValue::Integer(1_700_000_000), TimestampFormat::UnixSeconds),
+            (ColumnValue::Integer(1_700_000_000_000), TimestampFormat::UnixMillis),
+            (ColumnValue::Integer(1_700_000_000_000_000), TimestampFormat::UnixMicros),
+            (ColumnValue::Integer(13_344_473_600_000_000), TimestampFormat::WebKit),
+            (ColumnValue::Integer(133_444_736_000_000_000), TimestampFormat::Filetime),
+            (ColumnValue::Float(721_692_800.0), TimestampFormat::MacAbsolute),
+            (ColumnValue::Integer(721_692_800_000_000_000), TimestampFormat::MacAbsoluteNanos),
+        ]
+    }
+
+    #[test]
+    fn should_convert_every_format() {
+        for (value, format) in samples() {
+            let timestamp = Timestamp::from_value(&value, format).unwrap();
+            assert_eq!(EXPECTED, timestamp.to_string(), "{}", format.as_str());
+            assert_eq!(1_700_000_000, timestamp.unix_seconds());
+        }
+        let julian = Timestamp::from_value(&ColumnValue::Float(2_460_263.425_925_926), TimestampFormat::JulianDay).unwrap();
+        assert!((julian.unix_nanos - 1_700_000_000 * NANOS).abs() < NANOS / 1000);
+        let fraction = Timestamp::from_value(&ColumnValue::String("1700000000.5".into()), TimestampFormat::UnixSeconds).unwrap();
+        assert_eq!("2023-11-14T22:13:20.5Z", fraction.to_string());
+        assert_eq!("1969-12-31T23:59:59Z", Timestamp::from_value(&ColumnValue::Integer(-1), TimestampFormat::UnixSeconds).unwrap().to_string());
+        assert!(Timestamp::from_value(&ColumnValue::Null, TimestampFormat::WebKit).is_err());
+    }
+
+    #[test]
+    fn should_guess_the_epoch() {
+        for (value, format) in samples() {
+            let guess = guess_format(&[value, ColumnValue::Null, ColumnValue::Integer(0)]).unwrap();
+            assert_eq!(format, guess.format);
+            assert_eq!(1.0, guess.score);
+        }
+        let julian = guess_format(&[ColumnValue::Float(2_460_263.425_925_926)]).unwrap();
+        assert_eq!(TimestampFormat::JulianDay, julian.format);
+        assert!(guess_format(&[ColumnValue::Integer(42)]).is_none());
+    }
+}
@@ -0,0 +1,802 @@
+//! SQLite VFS that serves database files straight from forensic VirtualFiles.
+//!
+//! Files are registered under a synthetic path (`/forensic-rs/<id>/db`) and opened with a `vfs=forensic-rs` URI.
+//! Any path outside that prefix (temp files, regular databases) is forwarded to the default VFS of the process.
+//! VirtualFiles are not Send: each one stays in the thread that registered it and can only be read from that thread.
+//! `VfsDatabase` and `EvidenceReader` are not Send either, so the compiler keeps them, and the `SqliteDB` that holds them, in that thread.
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+    io::{Read, Seek, SeekFrom},
+    marker::PhantomData,
+    os::raw::{c_char, c_int, c_void},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, Once,
+    },
+    thread::ThreadId,
+};
+
+use forensic_rs::{
+    prelude::{ForensicError, ForensicResult},
+    traits::vfs::VirtualFile,
+};
+use sqlite3_sys as ffi;
+
+use crate::sqlcipher::PageCipher;
+
+/// Name of the VFS registered in SQLite
+pub(crate) const VFS_NAME: &str = "forensic-rs";
+const PATH_PREFIX: &str = "/forensic-rs/";
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ERROR: c_int = 1;
+const SQLITE_READONLY: c_int = 8;
+const SQLITE_IOERR: c_int = 10;
+const SQLITE_NOTFOUND: c_int = 12;
+const SQLITE_CANTOPEN: c_int = 14;
+const SQLITE_IOERR_READ: c_int = SQLITE_IOERR | (1 << 8);
+const SQLITE_IOERR_SHORT_READ: c_int = SQLITE_IOERR | (2 << 8);
+const SQLITE_IOERR_WRITE: c_int = SQLITE_IOERR | (3 << 8);
+const SQLITE_OPEN_READONLY: c_int = 0x0000_0001;
+const SQLITE_OPEN_READWRITE: c_int = 0x0000_0002;
+const SQLITE_OPEN_CREATE: c_int = 0x0000_0004;
+
+/// Size of the blocks kept in the copy-on-write overlay
+const CHUNK_SIZE: u64 = 4096;
+
+thread_local! {
+    /// VirtualFiles registered by this thread. They are not Send, so they never leave it.
+    static SOURCES: RefCell<BTreeMap<u64, Box<dyn VirtualFile>>> = const { RefCell::new(BTreeMap::new()) };
+}
+static NEXT_SOURCE: AtomicU64 = AtomicU64::new(0);
+
+/// The VirtualFile behind an entry, kept in the thread that registered it. The entry only holds its key, so it can be shared through the registry.
+/// Reading it from another thread, like a connection opened on the URI of the database elsewhere, fails with an I/O error.
+struct ConfinedSource {
+    id: u64,
+    owner: ThreadId,
+}
+
+impl ConfinedSource {
+    fn new(file: Box<dyn VirtualFile>) -> Self {
+        let id = NEXT_SOURCE.fetch_add(1, Ordering::SeqCst);
+        SOURCES.with(|sources| sources.borrow_mut().insert(id, file));
+        Self {
+            id,
+            owner: std::thread::current().id(),
+        }
+    }
+
+    /// Runs `f` with the VirtualFile. Fails outside of the thread that registered it.
+    fn with<T>(&self, f: impl FnOnce(&mut dyn VirtualFile) -> std::io::Result<T>) -> std::io::Result<T> {
+        if std::thread::current().id() != self.owner {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "The evidence can only be read from the thread that opened it",
+            ));
+        }
+        SOURCES.with(|sources| match sources.borrow_mut().get_mut(&self.id) {
+            Some(file) => f(file.as_mut()),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "The evidence is no longer registered")),
+        })
+    }
+}
+
+impl Drop for ConfinedSource {
+    fn drop(&mut self) {
+        // Dropped from another thread, the VirtualFile is released when the thread that owns it exits
+        if std::thread::current().id() == self.owner {
+            let _ = SOURCES.try_with(|sources| sources.borrow_mut().remove(&self.id));
+        }
+    }
+}
+
+/// A file served to SQLite by the forensic VFS.
+pub(crate) struct VfsEntry {
+    source: Option<ConfinedSource>,
+    evidence_size: u64,
+    /// Bytes of the source that are still part of the file
+    source_size: u64,
+    /// Blocks written by SQLite. Writes never reach the evidence.
+    overlay: BTreeMap<u64, Box<[u8]>>,
+    size: u64,
+    read_only: bool,
+    /// Decrypts the pages of the source, for encrypted databases
+    cipher: Option<Arc<PageCipher>>,
+    /// Last page decrypted, SQLite reads the same pages many times
+    plain_page: Option<(u64, Box<[u8]>)>,
+}
+
+impl VfsEntry {
+    fn new(mut source: Box<dyn VirtualFile>, read_only: bool) -> ForensicResult<Self> {
+        let size = source.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            source: Some(ConfinedSource::new(source)),
+            evidence_size: size,
+            source_size: size,
+            overlay: BTreeMap::new(),
+            size,
+            read_only,
+            cipher: None,
+            plain_page: None,
+        })
+    }
+    fn empty(read_only: bool) -> Self {
+        Self {
+            source: None,
+            evidence_size: 0,
+            source_size: 0,
+            overlay: BTreeMap::new(),
+            size: 0,
+            read_only,
+            cipher: None,
+            plain_page: None,
+        }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads the file as SQLite sees it, with the overlay applied. Returns the number of bytes read.
+    pub(crate) fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_chunk = (pos % CHUNK_SIZE) as usize;
+            let n = (CHUNK_SIZE as usize - in_chunk).min(len - done);
+            let out = &mut buf[done..done + n];
+            match self.overlay.get(&(pos / CHUNK_SIZE)) {
+                Some(block) => out.copy_from_slice(&block[in_chunk..in_chunk + n]),
+                None => {
+                    let readed = self.read_plain_at(pos, out, self.source_size)?;
+                    out[readed..].fill(0);
+                }
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Reads the evidence without the changes made by SQLite. Returns the number of bytes read.
+    pub(crate) fn read_evidence_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_source_at(offset, buf, self.evidence_size)
+    }
+
+    /// Reads the evidence decrypted, without the changes made by SQLite. Returns the number of bytes read.
+    pub(crate) fn read_plain_evidence_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_plain_at(offset, buf, self.evidence_size)
+    }
+
+    pub(crate) fn evidence_size(&self) -> u64 {
+        self.evidence_size
+    }
+
+    /// Reads the source decrypting whole pages when there is a cipher. A partial page at the end is returned as it is.
+    fn read_plain_at(&mut self, offset: u64, buf: &mut [u8], limit: u64) -> std::io::Result<usize> {
+        let cipher = match &self.cipher {
+            Some(v) => v.clone(),
+            None => return self.read_source_at(offset, buf, limit),
+        };
+        if offset >= limit {
+            return Ok(0);
+        }
+        let page_size = cipher.page_size() as u64;
+        let len = buf.len().min((limit - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let number = pos / page_size;
+            let in_page = (pos % page_size) as usize;
+            let n = (page_size as usize - in_page).min(len - done);
+            if self.plain_page.as_ref().map(|v| v.0 != number).unwrap_or(true) {
+                let mut page = vec![0u8; page_size as usize];
+                let readed = self.read_source_at(number * page_size, &mut page, limit)?;
+                if readed == page.len() && cipher.decrypt_page(number as u32 + 1, &mut page).is_err() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Page {} cannot be decrypted", number + 1),
+                    ));
+                }
+                self.plain_page = Some((number, page.into_boxed_slice()));
+            }
+            if let Some((_, page)) = &self.plain_page {
+                buf[done..done + n].copy_from_slice(&page[in_page..in_page + n]);
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    fn read_source_at(&mut self, offset: u64, buf: &mut [u8], limit: u64) -> std::io::Result<usize> {
+        let source = match &self.source {
+            Some(v) => v,
+            None => return Ok(0),
+        };
+        if offset >= limit {
+            return Ok(0);
+        }
+        let len = buf.len().min((limit - offset) as usize);
+        source.with(|source| {
+            source.seek(SeekFrom::Start(offset))?;
+            let mut total = 0;
+            while total < len {
+                match source.read(&mut buf[total..len]) {
+                    Ok(0) => break,
+                    Ok(n) => total += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(total)
+        })
+    }
+
+    /// Writes into the overlay
+    pub(crate) fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let chunk = pos / CHUNK_SIZE;
+            let in_chunk = (pos % CHUNK_SIZE) as usize;
+            let n = (CHUNK_SIZE as usize - in_chunk).min(data.len() - done);
+            if !self.overlay.contains_key(&chunk) {
+                let mut block = vec![0u8; CHUNK_SIZE as usize];
+                self.read_at(chunk * CHUNK_SIZE, &mut block)?;
+                self.overlay.insert(chunk, block.into_boxed_slice());
+            }
+            if let Some(block) = self.overlay.get_mut(&chunk) {
+                block[in_chunk..in_chunk + n].copy_from_slice(&data[done..done + n]);
+            }
+            done += n;
+        }
+        self.size = self.size.max(offset + data.len() as u64);
+        Ok(())
+    }
+
+    pub(crate) fn truncate(&mut self, size: u64) {
+        if size < self.size {
+            // Truncated bytes of the evidence must not come back if the file grows again
+            self.source_size = self.source_size.min(size);
+            self.plain_page = None;
+            self.overlay.split_off(&size.div_ceil(CHUNK_SIZE));
+            if let Some(block) = self.overlay.get_mut(&(size / CHUNK_SIZE)) {
+                block[(size % CHUNK_SIZE) as usize..].fill(0);
+            }
+        }
+        self.size = size;
+    }
+}
+
+pub(crate) type SharedEntry = Arc<Mutex<VfsEntry>>;
+
+/// Reads the evidence behind a registered file, without the changes made by SQLite.
+pub(crate) struct EvidenceReader {
+    entry: SharedEntry,
+    position: u64,
+    /// Decrypts the pages of encrypted databases
+    plaintext: bool,
+    /// Not Send: the VirtualFile can only be read from this thread
+    _thread: PhantomData<*const ()>,
+}
+
+impl Read for EvidenceReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut entry = lock_entry(&self.entry);
+        let readed = if self.plaintext {
+            entry.read_plain_evidence_at(self.position, buf)?
+        } else {
+            entry.read_evidence_at(self.position, buf)?
+        };
+        self.position += readed as u64;
+        Ok(readed)
+    }
+}
+
+impl Seek for EvidenceReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(v) => v as i128,
+            SeekFrom::End(v) => lock_entry(&self.entry).evidence_size() as i128 + v as i128,
+            SeekFrom::Current(v) => self.position as i128 + v as i128,
+        };
+        if position < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the file"));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+static REGISTRY: Mutex<BTreeMap<String, SharedEntry>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static REGISTER: Once = Once::new();
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+fn registry() -> MutexGuard<'static, BTreeMap<String, SharedEntry>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn lock_entry(entry: &SharedEntry) -> MutexGuard<'_, VfsEntry> {
+    entry.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn lookup(name: &CStr) -> Option<SharedEntry> {
+    let name = name.to_str().ok()?;
+    registry().get(name).cloned()
+}
+
+fn is_forensic_path(name: &CStr) -> bool {
+    name.to_bytes().starts_with(PATH_PREFIX.as_bytes())
+}
+
+/// Files of a single database registered in the forensic VFS. They are unregistered when dropped.
+pub(crate) struct VfsDatabase {
+    path: String,
+    copy_on_write: bool,
+    files: BTreeMap<String, SharedEntry>,
+    /// Not Send: the VirtualFiles can only be read from this thread
+    _thread: PhantomData<*const ()>,
+}
+
+impl VfsDatabase {
+    /// With `copy_on_write` SQLite can write to the files (hot journal rollback, WAL checkpoints), the changes are kept in memory.
+    pub(crate) fn new(copy_on_write: bool) -> ForensicResult<Self> {
+        ensure_registered()?;
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        Ok(Self {
+            path: format!("{}{}/db", PATH_PREFIX, id),
+            copy_on_write,
+            files: BTreeMap::new(),
+            _thread: PhantomData,
+        })
+    }
+
+    /// Registers a file next to the database. The main file uses an empty suffix.
+    pub(crate) fn register(&mut self, suffix: &str, file: Box<dyn VirtualFile>) -> ForensicResult<()> {
+        let name = format!("{}{}", self.path, suffix);
+        let entry = Arc::new(Mutex::new(VfsEntry::new(file, !self.copy_on_write)?));
+        registry().insert(name, entry.clone());
+        self.files.insert(suffix.to_string(), entry);
+        Ok(())
+    }
+
+    /// Writes into the overlay of a registered file before SQLite opens it, to build reconstructed states of the database.
+    pub(crate) fn patch(&self, suffix: &str, offset: u64, data: &[u8]) -> ForensicResult<()> {
+        let entry = self.registered(suffix)?;
+        lock_entry(entry).write_at(offset, data)?;
+        Ok(())
+    }
+
+    /// Truncates or extends the overlay of a registered file
+    pub(crate) fn truncate(&self, suffix: &str, size: u64) -> ForensicResult<()> {
+        let entry = self.registered(suffix)?;
+        lock_entry(entry).truncate(size);
+        Ok(())
+    }
+
+    /// Reader of the evidence registered with the given suffix
+    pub(crate) fn evidence(&self, suffix: &str) -> Option<EvidenceReader> {
+        self.files.get(suffix).map(|entry| EvidenceReader {
+            entry: entry.clone(),
+            position: 0,
+            plaintext: false,
+            _thread: PhantomData,
+        })
+    }
+
+    /// Reader of the evidence registered with the given suffix, decrypted if it has a cipher. The same as `evidence` for plain files.
+    pub(crate) fn plaintext(&self, suffix: &str) -> Option<EvidenceReader> {
+        self.files.get(suffix).map(|entry| EvidenceReader {
+            entry: entry.clone(),
+            position: 0,
+            plaintext: true,
+            _thread: PhantomData,
+        })
+    }
+
+    /// Decrypts the pages of a registered file as SQLite reads them
+    pub(crate) fn set_cipher(&self, suffix: &str, cipher: PageCipher) -> ForensicResult<()> {
+        let entry = self.registered(suffix)?;
+        let mut entry = lock_entry(entry);
+        entry.cipher = Some(Arc::new(cipher));
+        entry.plain_page = None;
+        Ok(())
+    }
+
+    fn registered(&self, suffix: &str) -> ForensicResult<&SharedEntry> {
+        match self.files.get(suffix) {
+            Some(v) => Ok(v),
+            None => Err(ForensicError::Other(format!("No file registered as {}{}", self.path, suffix))),
+        }
+    }
+
+    /// URI to be used with a connection opened with the URI flag
+    pub(crate) fn uri(&self) -> String {
+        format!("file:{}?vfs={}", self.path, VFS_NAME)
+    }
+
+    /// URI of the database with the immutable semantics: no locks, no -shm, no -wal or -journal lookups
+    pub(crate) fn immutable_uri(&self) -> String {
+        format!("{}&immutable=1", self.uri())
+    }
+}
+
+impl Drop for VfsDatabase {
+    fn drop(&mut self) {
+        // Also removes the files created by SQLite next to the database
+        registry().retain(|name, _| !name.starts_with(&self.path));
+    }
+}
+
+fn ensure_registered() -> ForensicResult<()> {
+    REGISTER.call_once(|| {
+        let registered = unsafe { register_vfs() };
+        REGISTERED.store(registered, Ordering::SeqCst);
+    });
+    if REGISTERED.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err(ForensicError::Other(format!(
+            "Cannot register the {} SQLite VFS",
+            VFS_NAME
+        )))
+    }
+}
+
+unsafe fn register_vfs() -> bool {
+    let default = ffi::sqlite3_vfs_find(std::ptr::null());
+    if default.is_null() {
+        return false;
+    }
+    let dflt = &*default;
+    let name = match CString::new(VFS_NAME) {
+        Ok(v) => v.into_raw(),
+        Err(_) => return false,
+    };
+    // The VFS lives as long as the process: SQLite keeps a pointer to it
+    let vfs = Box::new(ffi::sqlite3_vfs {
+        iVersion: 2,
+        szOsFile: dflt.szOsFile.max(std::mem::size_of::<ForensicFile>() as c_int),
+        mxPathname: dflt.mxPathname,
+        pNext: std::ptr::null_mut(),
+        zName: name,
+        pAppData: default as *mut c_void,
+        xOpen: Some(x_open),
+        xDelete: Some(x_delete),
+        xAccess: Some(x_access),
+        xFullPathname: Some(x_full_pathname),
+        // None of these use the VFS pointer, so the default implementations can be reused as they are
+        xDlOpen: dflt.xDlOpen,
+        xDlError: dflt.xDlError,
+        xDlSym: dflt.xDlSym,
+        xDlClose: dflt.xDlClose,
+        xRandomness: dflt.xRandomness,
+        xSleep: dflt.xSleep,
+        xCurrentTime: dflt.xCurrentTime,
+        xGetLastError: dflt.xGetLastError,
+        xCurrentTimeInt64: dflt.xCurrentTimeInt64,
+        xSetSystemCall: None,
+        xGetSystemCall: None,
+        xNextSystemCall: None,
+    });
+    ffi::sqlite3_vfs_register(Box::into_raw(vfs), 0) == SQLITE_OK
+}
+
+unsafe fn default_vfs(vfs: *mut ffi::sqlite3_vfs) -> *mut ffi::sqlite3_vfs {
+    (*vfs).pAppData as *mut ffi::sqlite3_vfs
+}
+
+#[repr(C)]
+struct ForensicFile {
+    base: ffi::sqlite3_file,
+    state: *mut FileState,
+}
+
+struct FileState {
+    entry: SharedEntry,
+    /// Wal-index kept in heap memory, the evidence folder is never touched.
+    shm: Vec<Box<[u8]>>,
+}
+
+unsafe fn file_state<'a>(file: *mut ffi::sqlite3_file) -> &'a mut FileState {
+    &mut *(*(file as *mut ForensicFile)).state
+}
+
+static IO_METHODS: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
+    iVersion: 2,
+    xClose: Some(x_close),
+    xRead: Some(x_read),
+    xWrite: Some(x_write),
+    xTruncate: Some(x_truncate),
+    xSync: Some(x_sync),
+    xFileSize: Some(x_file_size),
+    xLock: Some(x_lock),
+    xUnlock: Some(x_unlock),
+    xCheckReservedLock: Some(x_check_reserved_lock),
+    xFileControl: Some(x_file_control),
+    xSectorSize: Some(x_sector_size),
+    xDeviceCharacteristics: Some(x_device_characteristics),
+    xShmMap: Some(x_shm_map),
+    xShmLock: Some(x_shm_lock),
+    xShmBarrier: Some(x_shm_barrier),
+    xShmUnmap: Some(x_shm_unmap),
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe extern "C" fn x_open(
+    vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    p_out_flags: *mut c_int,
+) -> c_int {
+    let name = if z_name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(z_name))
+    };
+    if !name.map(is_forensic_path).unwrap_or(false) {
+        let default = default_vfs(vfs);
+        return match (*default).xOpen {
+            Some(open) => open(default, z_name, file, flags, p_out_flags),
+            None => SQLITE_CANTOPEN,
+        };
+    }
+    let entry = match name.and_then(lookup) {
+        Some(v) => v,
+        None if flags & SQLITE_OPEN_CREATE != 0 => {
+            // Only copy-on-write connections ask for new files: they live in memory until the database is dropped
+            let entry = Arc::new(Mutex::new(VfsEntry::empty(false)));
+            if let Some(name) = name.and_then(|v| v.to_str().ok()) {
+                registry().insert(name.to_string(), entry.clone());
+            }
+            entry
+        }
+        // Companions missing from the evidence, like the -wal of a WAL mode database, are served as empty files
+        None => Arc::new(Mutex::new(VfsEntry::empty(true))),
+    };
+    let read_only = lock_entry(&entry).read_only;
+    let state = Box::new(FileState {
+        entry,
+        shm: Vec::new(),
+    });
+    let file = file as *mut ForensicFile;
+    (*file).base.pMethods = &IO_METHODS;
+    (*file).state = Box::into_raw(state);
+    if !p_out_flags.is_null() {
+        *p_out_flags = if read_only {
+            (flags & !(SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE)) | SQLITE_OPEN_READONLY
+        } else {
+            flags
+        };
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_delete(vfs: *mut ffi::sqlite3_vfs, z_name: *const c_char, sync_dir: c_int) -> c_int {
+    let name = CStr::from_ptr(z_name);
+    if !is_forensic_path(name) {
+        let default = default_vfs(vfs);
+        return match (*default).xDelete {
+            Some(delete) => delete(default, z_name, sync_dir),
+            None => SQLITE_ERROR,
+        };
+    }
+    let entry = match lookup(name) {
+        Some(v) => v,
+        None => return SQLITE_OK,
+    };
+    if lock_entry(&entry).read_only {
+        return SQLITE_READONLY;
+    }
+    // The file disappears for SQLite, the evidence is still reachable from the VfsDatabase
+    if let Ok(name) = name.to_str() {
+        registry().remove(name);
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_access(
+    vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    flags: c_int,
+    p_res_out: *mut c_int,
+) -> c_int {
+    let name = CStr::from_ptr(z_name);
+    if !is_forensic_path(name) {
+        let default = default_vfs(vfs);
+        return match (*default).xAccess {
+            Some(access) => access(default, z_name, flags, p_res_out),
+            None => SQLITE_ERROR,
+        };
+    }
+    *p_res_out = lookup(name).is_some() as c_int;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_full_pathname(
+    vfs: *mut ffi::sqlite3_vfs,
+    z_name: *const c_char,
+    n_out: c_int,
+    z_out: *mut c_char,
+) -> c_int {
+    let name = CStr::from_ptr(z_name);
+    if !is_forensic_path(name) {
+        let default = default_vfs(vfs);
+        return match (*default).xFullPathname {
+            Some(full_pathname) => full_pathname(default, z_name, n_out, z_out),
+            None => SQLITE_ERROR,
+        };
+    }
+    let bytes = name.to_bytes_with_nul();
+    if bytes.len() > n_out as usize {
+        return SQLITE_CANTOPEN;
+    }
+    std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, z_out, bytes.len());
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_close(file: *mut ffi::sqlite3_file) -> c_int {
+    let file = file as *mut ForensicFile;
+    if !(*file).state.is_null() {
+        drop(Box::from_raw((*file).state));
+        (*file).state = std::ptr::null_mut();
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_read(file: *mut ffi::sqlite3_file, buf: *mut c_void, amt: c_int, offset: i64) -> c_int {
+    let out = std::slice::from_raw_parts_mut(buf as *mut u8, amt as usize);
+    let state = file_state(file);
+    let mut entry = lock_entry(&state.entry);
+    match entry.read_at(offset as u64, out) {
+        Ok(n) if n == out.len() => SQLITE_OK,
+        Ok(n) => {
+            // SQLite requires the unread part to be zero filled
+            out[n..].fill(0);
+            SQLITE_IOERR_SHORT_READ
+        }
+        Err(_) => SQLITE_IOERR_READ,
+    }
+}
+
+unsafe extern "C" fn x_write(file: *mut ffi::sqlite3_file, buf: *const c_void, amt: c_int, offset: i64) -> c_int {
+    let data = std::slice::from_raw_parts(buf as *const u8, amt as usize);
+    let state = file_state(file);
+    let mut entry = lock_entry(&state.entry);
+    if entry.read_only {
+        return SQLITE_READONLY;
+    }
+    match entry.write_at(offset as u64, data) {
+        Ok(_) => SQLITE_OK,
+        Err(_) => SQLITE_IOERR_WRITE,
+    }
+}
+
+unsafe extern "C" fn x_truncate(file: *mut ffi::sqlite3_file, size: i64) -> c_int {
+    let state = file_state(file);
+    let mut entry = lock_entry(&state.entry);
+    if entry.read_only {
+        return SQLITE_READONLY;
+    }
+    entry.truncate(size as u64);
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_sync(_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_size(file: *mut ffi::sqlite3_file, p_size: *mut i64) -> c_int {
+    let state = file_state(file);
+    *p_size = lock_entry(&state.entry).size() as i64;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_lock(_file: *mut ffi::sqlite3_file, _lock: c_int) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_unlock(_file: *mut ffi::sqlite3_file, _lock: c_int) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_check_reserved_lock(_file: *mut ffi::sqlite3_file, p_res_out: *mut c_int) -> c_int {
+    *p_res_out = 0;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_control(_file: *mut ffi::sqlite3_file, _op: c_int, _arg: *mut c_void) -> c_int {
+    SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn x_sector_size(_file: *mut ffi::sqlite3_file) -> c_int {
+    512
+}
+
+unsafe extern "C" fn x_device_characteristics(_file: *mut ffi::sqlite3_file) -> c_int {
+    0
+}
+
+unsafe extern "C" fn x_shm_map(
+    file: *mut ffi::sqlite3_file,
+    region: c_int,
+    size: c_int,
+    extend: c_int,
+    pp: *mut *mut c_void,
+) -> c_int {
+    let state = file_state(file);
+    let region = region as usize;
+    if state.shm.len() <= region {
+        if extend == 0 {
+            *pp = std::ptr::null_mut();
+            return SQLITE_OK;
+        }
+        while state.shm.len() <= region {
+            state.shm.push(vec![0u8; size as usize].into_boxed_slice());
+        }
+    }
+    *pp = state.shm[region].as_mut_ptr() as *mut c_void;
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_shm_lock(_file: *mut ffi::sqlite3_file, _offset: c_int, _n: c_int, _flags: c_int) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn x_shm_barrier(_file: *mut ffi::sqlite3_file) {
+    std::sync::atomic::fence(Ordering::SeqCst);
+}
+
+unsafe extern "C" fn x_shm_unmap(file: *mut ffi::sqlite3_file, _delete: c_int) -> c_int {
+    file_state(file).shm.clear();
+    SQLITE_OK
+}
+
+#[cfg(test)]
+mod test_vfs {
+    use super::*;
+
+    use forensic_rs::traits::vfs::VirtualFileSystem;
+
+    use crate::workspace::TempWorkspace;
+
+    #[test]
+    fn should_confine_evidence_to_its_thread() {
+        let test_dir = TempWorkspace::new().unwrap();
+        let temp_path = test_dir.path().join("vfs.db");
+        let connection = sqlite::open(&temp_path).unwrap();
+        connection.execute("CREATE TABLE t (a); INSERT INTO t VALUES (1);").unwrap();
+        drop(connection);
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut files = VfsDatabase::new(false).unwrap();
+        files.register("", fs.open(&temp_path).unwrap()).unwrap();
+        let mut reader = files.evidence("").unwrap();
+        let mut magic = [0u8; 16];
+        reader.read_exact(&mut magic).unwrap();
+        assert_eq!(b"SQLite format 3\0", &magic);
+        // The readers are not Send, but the registry can still be reached through the URI from another thread
+        let uri = files.uri();
+        let code = std::thread::spawn(move || {
+            let flags = sqlite::OpenFlags::new().set_read_only().set_uri();
+            // SQLite may already read the header while opening, so the error can come from either call
+            sqlite::Connection::open_with_flags(&uri, flags)
+                .and_then(|connection| connection.execute("SELECT * FROM t;"))
+                .err()
+                .and_then(|e| e.code)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(Some(SQLITE_IOERR), code.map(|v| v as c_int & 0xff));
+        drop(reader);
+        drop(files);
+    }
+}
@@ -0,0 +1,189 @@
+//! Virtual tables the connection cannot query, like FTS tables with the custom tokenizer of an app or modules only the app registers.
+//! Their data is still in the shadow tables of the module, which are regular tables that can be read directly.
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+
+use crate::{recovered::quote_identifier, schema::SchemaVirtualTable, SqliteDB, SqliteStatement};
+
+/// Shadow tables of the FTS3 and FTS4 modules
+const FTS4_SHADOWS: [&str; 5] = ["content", "segments", "segdir", "docsize", "stat"];
+/// Shadow tables of the FTS5 module
+const FTS5_SHADOWS: [&str; 5] = ["content", "data", "idx", "docsize", "config"];
+/// Shadow tables of the R*Tree module
+const RTREE_SHADOWS: [&str; 3] = ["node", "rowid", "parent"];
+
+/// A virtual table SQLite refuses to query and the shadow tables that keep its data
+#[derive(Debug, Clone)]
+pub struct UnavailableVirtualTable {
+    pub table: SchemaVirtualTable,
+    /// Error of SQLite, like "no such module: x" or "unknown tokenizer: y"
+    pub error: String,
+    /// Shadow tables present in the database
+    pub shadow_tables: Vec<String>,
+}
+
+impl SchemaVirtualTable {
+    /// Tokenizer of an FTS table, like `porter` or `icu zh_CN`
+    pub fn tokenizer(&self) -> Option<String> {
+        split_arguments(&self.arguments).into_iter().find_map(|argument| {
+            let rest = argument.get(..8).filter(|v| v.eq_ignore_ascii_case("tokenize")).map(|_| argument[8..].trim_start())?;
+            let rest = match rest.strip_prefix('=') {
+                Some(v) => v.trim_start(),
+                // FTS3 also accepts "tokenize porter"
+                None if argument[8..].starts_with(char::is_whitespace) => rest,
+                None => return None,
+            };
+            Some(unquote(rest).to_string())
+        })
+    }
+
+    /// Columns declared in the arguments of the module, without FTS options like `tokenize=` or `prefix=`.
+    /// Used when the module is not available to describe the table.
+    pub fn declared_columns(&self) -> Vec<String> {
+        split_arguments(&self.arguments)
+            .into_iter()
+            .filter(|v| !v.contains('=') && !v.to_ascii_lowercase().starts_with("tokenize "))
+            .filter_map(|v| first_identifier(&v))
+            .collect()
+    }
+
+    /// Suffixes of the shadow tables of the module, None when the module is unknown
+    pub fn shadow_suffixes(&self) -> Option<&'static [&'static str]> {
+        match &self.module.to_ascii_lowercase()[..] {
+            "fts3" | "fts4" => Some(&FTS4_SHADOWS[..]),
+            "fts5" => Some(&FTS5_SHADOWS[..]),
+            "rtree" | "rtree_i32" | "geopoly" => Some(&RTREE_SHADOWS[..]),
+            _ => None,
+        }
+    }
+}
+
+impl SqliteDB {
+    /// Virtual tables of the schema that cannot be queried because their module or tokenizer is not available to SQLite.
+    /// The rest of the schema is not affected, `list_tables` and queries on other tables keep working.
+    pub fn unavailable_virtual_tables(&self) -> ForensicResult<Vec<UnavailableVirtualTable>> {
+        let schema = self.schema()?;
+        let mut unavailable = Vec::new();
+        for table in schema.virtual_tables {
+            let query = format!("SELECT * FROM {} LIMIT 0;", quote_identifier(&table.name));
+            let error = match self.try_statement(&query) {
+                Ok(_) => continue,
+                Err(e) => e.message,
+            };
+            let prefix = format!("{}_", table.name.to_ascii_lowercase());
+            let shadow_tables = schema
+                .tables
+                .iter()
+                .map(|v| &v.name)
+                .filter(|name| match name.to_ascii_lowercase().strip_prefix(&prefix) {
+                    Some(suffix) => table.shadow_suffixes().map(|v| v.contains(&suffix)).unwrap_or(true),
+                    None => false,
+                })
+                .cloned()
+                .collect();
+            unavailable.push(UnavailableVirtualTable { table, error, shadow_tables });
+        }
+        Ok(unavailable)
+    }
+
+    /// Rows of a virtual table read from its `_content` shadow table, without going through the module.
+    /// The first column is the rowid, the rest are named as declared in the module arguments when they match the shadow table.
+    /// Contentless and external content FTS tables have no rows to read here.
+    pub fn virtual_table_content(&self, table: &str) -> ForensicResult<SqliteStatement<'_>> {
+        let schema = self.schema()?;
+        let vtable = match schema.virtual_tables.iter().find(|v| v.name.eq_ignore_ascii_case(table)) {
+            Some(v) => v,
+            None => return Err(ForensicError::Other(format!("{} is not a virtual table", table))),
+        };
+        let content = match schema.table(&format!("{}_content", vtable.name)) {
+            Some(v) => v,
+            None => return Err(ForensicError::Other(format!("The virtual table {} has no content shadow table", vtable.name))),
+        };
+        let declared = vtable.declared_columns();
+        let query = if content.columns.len() == declared.len() + 1 {
+            let columns: Vec<String> = std::iter::once("rowid".to_string())
+                .chain(declared)
+                .zip(content.columns.iter())
+                .map(|(name, column)| format!("{} AS {}", quote_identifier(&column.name), quote_identifier(&name)))
+                .collect();
+            format!("SELECT {} FROM {};", columns.join(", "), quote_identifier(&content.name))
+        } else {
+            format!("SELECT * FROM {};", quote_identifier(&content.name))
+        };
+        self.statement(&query)
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    match (value.chars().next(), value.chars().last()) {
+        (Some(start @ ('"' | '\'' | '`')), Some(end)) if start == end && value.len() > 1 => &value[1..value.len() - 1],
+        (Some('['), Some(']')) => &value[1..value.len() - 1],
+        _ => value,
+    }
+}
+
+fn first_identifier(argument: &str) -> Option<String> {
+    let argument = argument.trim();
+    let name = match argument.chars().next()? {
+        quote @ ('"' | '\'' | '`') => argument[1..].split(quote).next()?,
+        '[' => argument[1..].split(']').next()?,
+        _ => argument.split_whitespace().next()?,
+    };
+    Some(name.to_string())
+}
+
+/// Arguments of a module split on the commas outside quotes and parentheses
+fn split_arguments(arguments: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    for c in arguments.chars() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ',') if depth == 0 => {
+                split.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        split.push(current.trim().to_string());
+    }
+    split
+}
+
+#[cfg(test)]
+mod test_vtab {
+    use super::*;
+
+    fn virtual_table(module: &str, arguments: &str) -> SchemaVirtualTable {
+        SchemaVirtualTable {
+            name: "docs".into(),
+            module: module.into(),
+            arguments: arguments.into(),
+            columns: Vec::new(),
+            sql: String::new(),
+        }
+    }
+
+    #[test]
+    fn should_parse_module_arguments() {
+        let fts5 = virtual_table("fts5", "title, \"body text\" UNINDEXED, tokenize = 'icu zh_CN', prefix='2,3'");
+        assert_eq!(vec!["title", "body text"], fts5.declared_columns());
+        assert_eq!(Some("icu zh_CN".to_string()), fts5.tokenizer());
+        assert!(fts5.shadow_suffixes().unwrap().contains(&"data"));
+        let fts3 = virtual_table("FTS3", "subject, body, tokenize porter");
+        assert_eq!(vec!["subject", "body"], fts3.declared_columns());
+        assert_eq!(Some("porter".to_string()), fts3.tokenizer());
+        assert!(virtual_table("app_index", "a").shadow_suffixes().is_none());
+    }
+}
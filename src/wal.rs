@@ -0,0 +1,266 @@
+//! Parser of SQLite write-ahead log files. https://www.sqlite.org/fileformat.html#the_write_ahead_log
+use std::io::{Read, Seek, SeekFrom};
+
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+
+use crate::page::be_u32;
+
+pub const WAL_HEADER_SIZE: u64 = 32;
+pub const WAL_FRAME_HEADER_SIZE: u64 = 24;
+const WAL_MAGIC_LE: u32 = 0x377f0682;
+const WAL_MAGIC_BE: u32 = 0x377f0683;
+
+/// The 32-byte header at the start of a WAL file
+#[derive(Debug, Clone)]
+pub struct WalHeader {
+    pub magic: u32,
+    /// Checksums are computed over big-endian words
+    pub big_endian_checksum: bool,
+    pub format_version: u32,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    pub salt1: u32,
+    pub salt2: u32,
+    pub checksum1: u32,
+    pub checksum2: u32,
+    pub checksum_valid: bool,
+}
+
+/// A frame of the WAL: a page image preceded by a 24-byte header
+#[derive(Debug, Clone)]
+pub struct WalFrame {
+    /// Position of the frame in the WAL, starting at 0
+    pub index: usize,
+    /// Offset of the frame header in the WAL file
+    pub offset: u64,
+    pub page_number: u32,
+    /// Size of the database in pages after the commit. Zero for frames that are not the last of a transaction.
+    pub commit_size: u32,
+    pub salt1: u32,
+    pub salt2: u32,
+    pub checksum1: u32,
+    pub checksum2: u32,
+    /// Salts equal to the ones in the WAL header. Frames of older generations survive after a checkpoint restarts the WAL.
+    pub current_generation: bool,
+    /// Whether the cumulative checksum matches. `None` when it cannot be verified: the first surviving frame of an older generation.
+    pub checksum_valid: Option<bool>,
+}
+
+impl WalFrame {
+    pub fn is_commit(&self) -> bool {
+        self.commit_size != 0
+    }
+    pub fn same_generation(&self, other: &WalFrame) -> bool {
+        self.salt1 == other.salt1 && self.salt2 == other.salt2
+    }
+    /// Offset of the page image in the WAL file
+    pub fn page_offset(&self) -> u64 {
+        self.offset + WAL_FRAME_HEADER_SIZE
+    }
+}
+
+/// A parsed WAL file
+#[derive(Debug, Clone)]
+pub struct WalFile {
+    pub header: WalHeader,
+    pub frames: Vec<WalFrame>,
+}
+
+impl WalFile {
+    /// Parses the header and every frame physically present in the file, whatever its generation.
+    pub fn parse<R: Read + Seek + ?Sized>(file: &mut R) -> ForensicResult<WalFile> {
+        let mut buffer = [0u8; WAL_HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        if file.read_exact(&mut buffer).is_err() {
+            return Err(ForensicError::Other("WAL file is smaller than its header".into()));
+        }
+        let magic = be_u32(&buffer[0..4]);
+        let big_endian_checksum = match magic {
+            WAL_MAGIC_LE => false,
+            WAL_MAGIC_BE => true,
+            _ => return Err(ForensicError::Other(format!("Invalid WAL magic {:#x}", magic))),
+        };
+        let page_size = be_u32(&buffer[8..12]);
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            return Err(ForensicError::Other(format!("Invalid WAL page size {}", page_size)));
+        }
+        let checksum = wal_checksum(big_endian_checksum, (0, 0), &buffer[0..24]);
+        let header = WalHeader {
+            magic,
+            big_endian_checksum,
+            format_version: be_u32(&buffer[4..8]),
+            page_size,
+            checkpoint_sequence: be_u32(&buffer[12..16]),
+            salt1: be_u32(&buffer[16..20]),
+            salt2: be_u32(&buffer[20..24]),
+            checksum1: be_u32(&buffer[24..28]),
+            checksum2: be_u32(&buffer[28..32]),
+            checksum_valid: checksum == (be_u32(&buffer[24..28]), be_u32(&buffer[28..32])),
+        };
+
+        let frame_size = WAL_FRAME_HEADER_SIZE + page_size as u64;
+        let mut frame = vec![0u8; frame_size as usize];
+        let mut frames: Vec<WalFrame> = Vec::new();
+        let mut offset = WAL_HEADER_SIZE;
+        loop {
+            if file.read_exact(&mut frame).is_err() {
+                break;
+            }
+            let mut parsed = WalFrame {
+                index: frames.len(),
+                offset,
+                page_number: be_u32(&frame[0..4]),
+                commit_size: be_u32(&frame[4..8]),
+                salt1: be_u32(&frame[8..12]),
+                salt2: be_u32(&frame[12..16]),
+                checksum1: be_u32(&frame[16..20]),
+                checksum2: be_u32(&frame[20..24]),
+                current_generation: false,
+                checksum_valid: None,
+            };
+            parsed.current_generation = parsed.salt1 == header.salt1 && parsed.salt2 == header.salt2;
+            // The checksum chains from the previous frame of the same generation, or from the header for the first frame
+            let seed = match frames.last() {
+                Some(previous) if previous.same_generation(&parsed) => Some((previous.checksum1, previous.checksum2)),
+                None if parsed.current_generation => Some((header.checksum1, header.checksum2)),
+                _ => None,
+            };
+            parsed.checksum_valid = seed.map(|seed| {
+                let partial = wal_checksum(big_endian_checksum, seed, &frame[0..8]);
+                wal_checksum(big_endian_checksum, partial, &frame[WAL_FRAME_HEADER_SIZE as usize..])
+                    == (parsed.checksum1, parsed.checksum2)
+            });
+            frames.push(parsed);
+            offset += frame_size;
+        }
+        Ok(WalFile { header, frames })
+    }
+
+    /// Frames that end a transaction
+    pub fn commits(&self) -> impl Iterator<Item = &WalFrame> {
+        self.frames.iter().filter(|v| v.is_commit())
+    }
+
+    /// Frames SQLite would use: the valid prefix of the current generation up to its last commit
+    pub fn valid_frames(&self) -> &[WalFrame] {
+        let mut last_commit = 0;
+        for (pos, frame) in self.frames.iter().enumerate() {
+            if !frame.current_generation || frame.checksum_valid != Some(true) {
+                break;
+            }
+            if frame.is_commit() {
+                last_commit = pos + 1;
+            }
+        }
+        &self.frames[0..last_commit]
+    }
+
+    /// Frames that make up the database as of the given commit frame, in WAL order.
+    /// Only frames of the same generation that are still present are returned.
+    pub fn frames_until(&self, commit_frame: usize) -> ForensicResult<Vec<&WalFrame>> {
+        let commit = match self.frames.get(commit_frame) {
+            Some(v) => v,
+            None => return Err(ForensicError::Other(format!("WAL frame {} does not exist", commit_frame))),
+        };
+        if !commit.is_commit() {
+            return Err(ForensicError::Other(format!("WAL frame {} is not a commit frame", commit_frame)));
+        }
+        Ok(self.frames[0..=commit_frame]
+            .iter()
+            .filter(|v| v.same_generation(commit))
+            .collect())
+    }
+
+    /// Reads the page image stored in a frame
+    pub fn read_page<R: Read + Seek + ?Sized>(&self, file: &mut R, frame: &WalFrame) -> ForensicResult<Vec<u8>> {
+        let mut page = vec![0u8; self.header.page_size as usize];
+        file.seek(SeekFrom::Start(frame.page_offset()))?;
+        file.read_exact(&mut page)?;
+        Ok(page)
+    }
+}
+
+/// WAL checksum over 8-byte blocks, seeded with the previous checksum
+pub fn wal_checksum(big_endian: bool, seed: (u32, u32), data: &[u8]) -> (u32, u32) {
+    let (mut s0, mut s1) = seed;
+    for block in data.chunks_exact(8) {
+        let (x0, x1) = if big_endian {
+            (be_u32(&block[0..4]), be_u32(&block[4..8]))
+        } else {
+            (
+                u32::from_le_bytes([block[0], block[1], block[2], block[3]]),
+                u32::from_le_bytes([block[4], block[5], block[6], block[7]]),
+            )
+        };
+        s0 = s0.wrapping_add(x0).wrapping_add(s1);
+        s1 = s1.wrapping_add(x1).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+#[cfg(test)]
+mod test_wal {
+    use super::*;
+
+    use crate::workspace::TempWorkspace;
+
+    fn build_frame(wal: &mut Vec<u8>, seed: (u32, u32), page: u32, commit: u32, salts: (u32, u32), page_size: usize) -> (u32, u32) {
+        let mut header = Vec::new();
+        header.extend_from_slice(&page.to_be_bytes());
+        header.extend_from_slice(&commit.to_be_bytes());
+        header.extend_from_slice(&salts.0.to_be_bytes());
+        header.extend_from_slice(&salts.1.to_be_bytes());
+        let data = vec![page as u8; page_size];
+        let checksum = wal_checksum(false, wal_checksum(false, seed, &header[0..8]), &data);
+        header.extend_from_slice(&checksum.0.to_be_bytes());
+        header.extend_from_slice(&checksum.1.to_be_bytes());
+        wal.extend_from_slice(&header);
+        wal.extend_from_slice(&data);
+        checksum
+    }
+
+    fn build_wal(salts: (u32, u32)) -> Vec<u8> {
+        let mut wal = Vec::new();
+        wal.extend_from_slice(&WAL_MAGIC_LE.to_be_bytes());
+        wal.extend_from_slice(&3007000u32.to_be_bytes());
+        wal.extend_from_slice(&512u32.to_be_bytes());
+        wal.extend_from_slice(&0u32.to_be_bytes());
+        wal.extend_from_slice(&salts.0.to_be_bytes());
+        wal.extend_from_slice(&salts.1.to_be_bytes());
+        let checksum = wal_checksum(false, (0, 0), &wal[0..24]);
+        wal.extend_from_slice(&checksum.0.to_be_bytes());
+        wal.extend_from_slice(&checksum.1.to_be_bytes());
+        wal
+    }
+
+    #[test]
+    fn should_parse_frames_and_generations() {
+        let mut wal = build_wal((2, 2));
+        let checksum = (be_u32(&wal[24..28]), be_u32(&wal[28..32]));
+        build_frame(&mut wal, checksum, 1, 1, (2, 2), 512);
+        // Leftover of the previous generation
+        let old = build_frame(&mut wal, (7, 7), 2, 0, (1, 1), 512);
+        build_frame(&mut wal, old, 3, 3, (1, 1), 512);
+
+        let test_dir = TempWorkspace::new().unwrap();
+        let path = test_dir.path().join("wal.db-wal");
+        std::fs::write(&path, &wal).unwrap();
+        let mut fs = forensic_rs::core::fs::StdVirtualFS::new();
+        let mut file = forensic_rs::traits::vfs::VirtualFileSystem::open(&mut fs, &path).unwrap();
+        let parsed = WalFile::parse(file.as_mut()).unwrap();
+
+        assert!(parsed.header.checksum_valid);
+        assert_eq!(3, parsed.frames.len());
+        assert!(parsed.frames[0].current_generation);
+        assert_eq!(Some(true), parsed.frames[0].checksum_valid);
+        assert!(!parsed.frames[1].current_generation);
+        assert_eq!(None, parsed.frames[1].checksum_valid);
+        assert_eq!(Some(true), parsed.frames[2].checksum_valid);
+        assert_eq!(1, parsed.valid_frames().len());
+        assert_eq!(2, parsed.commits().count());
+        let old_generation = parsed.frames_until(2).unwrap();
+        assert_eq!(vec![1, 2], old_generation.iter().map(|v| v.index).collect::<Vec<usize>>());
+        assert!(parsed.frames_until(1).is_err());
+        assert_eq!(vec![3u8; 512], parsed.read_page(file.as_mut(), &parsed.frames[2]).unwrap());
+    }
+}
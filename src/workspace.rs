@@ -0,0 +1,153 @@
+//! Temporary workspace for the copies of the evidence that SQLite opens from disk
+use std::{
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use forensic_rs::prelude::{ForensicError, ForensicResult};
+
+static NEXT_WORKSPACE: AtomicU64 = AtomicU64::new(0);
+const MAX_ATTEMPTS: u32 = 64;
+
+/// A private directory holding the copies of a database. The main file and its sidecars (-wal, -shm, -journal) live together in the directory.
+/// Everything inside is deleted when the workspace is dropped, optionally overwriting the contents first.
+#[derive(Debug)]
+pub struct TempWorkspace {
+    path: PathBuf,
+    overwrite_on_drop: bool,
+}
+
+impl TempWorkspace {
+    /// Creates a workspace in the temp directory of the system
+    pub fn new() -> ForensicResult<TempWorkspace> {
+        Self::in_dir(&std::env::temp_dir())
+    }
+
+    /// Creates a workspace inside a directory
+    pub fn in_dir(dir: &Path) -> ForensicResult<TempWorkspace> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_nanos())
+            .unwrap_or(0);
+        for _ in 0..MAX_ATTEMPTS {
+            let name = format!(
+                "forensic_sqlite.{}.{}.{}",
+                std::process::id(),
+                nanos,
+                NEXT_WORKSPACE.fetch_add(1, Ordering::SeqCst)
+            );
+            let path = dir.join(name);
+            // Creating the directory fails if it already exists, so two workspaces never share it
+            match create_private_dir(&path) {
+                Ok(_) => {
+                    return Ok(TempWorkspace {
+                        path,
+                        overwrite_on_drop: false,
+                    })
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ForensicError::Other(format!("Cannot create a temp workspace in {}", dir.display())))
+    }
+
+    /// Overwrite the files with zeros before deleting them
+    pub fn set_overwrite_on_drop(&mut self, overwrite: bool) {
+        self.overwrite_on_drop = overwrite;
+    }
+
+    /// Directory of the workspace
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies a file into the workspace with the given name
+    pub fn copy_file<R: Read + ?Sized>(&self, name: &str, file: &mut R) -> ForensicResult<PathBuf> {
+        let path = self.path.join(name);
+        let mut buffer = vec![0; 4096];
+        let mut tmp_file = std::fs::File::create(&path)?;
+        loop {
+            let readed = file.read(&mut buffer)?;
+            if readed == 0 {
+                break;
+            }
+            tmp_file.write_all(&buffer[0..readed])?;
+        }
+        Ok(path)
+    }
+
+    fn overwrite(path: &Path) -> std::io::Result<()> {
+        let size = std::fs::metadata(path)?.len();
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        let zeros = vec![0u8; 4096];
+        let mut written = 0;
+        while written < size {
+            let chunk = (size - written).min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[0..chunk])?;
+            written += chunk as u64;
+        }
+        file.sync_all()
+    }
+}
+
+/// Creates a directory only the current user can read. The copies of the evidence must not be readable by other users of the temp directory.
+#[cfg(unix)]
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new().mode(0o700).create(path)
+}
+
+/// Creates a directory that inherits the permissions of its parent, like the per-user temp directory of Windows
+#[cfg(not(unix))]
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir(path)
+}
+
+impl Drop for TempWorkspace {
+    fn drop(&mut self) {
+        // Sidecars created by SQLite are removed too
+        if let Ok(entries) = std::fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if self.overwrite_on_drop {
+                    let _ = Self::overwrite(&path);
+                }
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test_workspace {
+    use super::*;
+
+    #[test]
+    fn should_create_unique_workspaces_and_clean_them() {
+        let first = TempWorkspace::new().unwrap();
+        let mut second = TempWorkspace::new().unwrap();
+        assert_ne!(first.path(), second.path());
+        second.set_overwrite_on_drop(true);
+        let first_path = first.path().to_path_buf();
+        let second_path = second.path().to_path_buf();
+        std::fs::write(first_path.join("evidence.db"), b"data").unwrap();
+        std::fs::write(second_path.join("evidence.db-wal"), b"data").unwrap();
+        drop(first);
+        drop(second);
+        assert!(!first_path.exists());
+        assert!(!second_path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_create_private_workspaces() {
+        use std::os::unix::fs::PermissionsExt;
+        let workspace = TempWorkspace::new().unwrap();
+        let mode = std::fs::metadata(workspace.path()).unwrap().permissions().mode();
+        assert_eq!(0o700, mode & 0o777);
+    }
+}